@@ -0,0 +1,212 @@
+use crate::history::{self, History};
+use crate::state_store::StateStore;
+use bitcoin::blockchain::Blockchain;
+use bitcoin::crypto::hash::H160;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crossbeam::channel::{self, TryRecvError};
+use log::{error, info};
+use std::thread;
+use std::time;
+use tiny_http::Header;
+use tiny_http::Response;
+use tiny_http::Server as HTTPServer;
+use url::Url;
+
+/// Interval at which the API server checks whether it should stop, when no request comes in
+const EXIT_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+pub struct Server {
+    handle: HTTPServer,
+    archive: Archive,
+}
+
+/// The archived chain and states answering the history queries
+#[derive(Clone)]
+struct Archive {
+    blockchain: Arc<Mutex<Blockchain>>,
+    block_state: Arc<Mutex<StateStore>>,
+    history: Arc<Mutex<History>>,
+}
+
+#[derive(Serialize)]
+struct ApiResponse {
+    success: bool,
+    message: String,
+}
+
+/// Parse an optional query parameter
+fn param<T: std::str::FromStr>(params: &HashMap<String, String>, name: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    match params.get(name) {
+        Some(v) => v.parse().map(Some).map_err(|e| format!("error parsing {}: {}", name, e)),
+        None => Ok(None),
+    }
+}
+
+impl Archive {
+    fn account(&self, params: &HashMap<String, String>) -> Result<history::AccountView, String> {
+        let address: H160 = param(params, "address")?.ok_or("missing address")?;
+        let chain = self.blockchain.lock().unwrap();
+        let states = self.block_state.lock().unwrap();
+        let block = match params.get("block") {
+            Some(block) => history::resolve(&chain, block)?,
+            None => chain.tip(),
+        };
+        self.history.lock().unwrap().account_at(&chain, &states, &address, &block)
+    }
+
+    fn proof(&self, params: &HashMap<String, String>) -> Result<history::AccountProofView, String> {
+        let address: H160 = param(params, "address")?.ok_or("missing address")?;
+        let chain = self.blockchain.lock().unwrap();
+        let states = self.block_state.lock().unwrap();
+        let block = match params.get("block") {
+            Some(block) => history::resolve(&chain, block)?,
+            None => chain.tip(),
+        };
+        self.history.lock().unwrap().account_proof(&chain, &states, &address, &block)
+    }
+
+    fn transactions(&self, params: &HashMap<String, String>) -> Result<Vec<history::TxView>, String> {
+        let address: H160 = param(params, "address")?.ok_or("missing address")?;
+        let chain = self.blockchain.lock().unwrap();
+        Ok(self.history.lock().unwrap().transactions(&chain, &address))
+    }
+
+    fn blocks(&self, params: &HashMap<String, String>) -> Result<Vec<history::BlockSummary>, String> {
+        let from: Option<u128> = param(params, "from")?;
+        let to = param(params, "to")?.unwrap_or(u128::MAX);
+        let chain = self.blockchain.lock().unwrap();
+        let history = self.history.lock().unwrap();
+        match from {
+            Some(from) => history.blocks_between(&chain, from, to),
+            None => Ok(history.latest_blocks(&chain, to)),
+        }
+    }
+
+    fn diff(&self, params: &HashMap<String, String>) -> Result<history::StateDiff, String> {
+        let chain = self.blockchain.lock().unwrap();
+        let from = history::resolve(&chain, params.get("from").ok_or("missing from")?)?;
+        let to = match params.get("to") {
+            Some(to) => history::resolve(&chain, to)?,
+            None => chain.tip(),
+        };
+        let states = self.block_state.lock().unwrap();
+        self.history.lock().unwrap().diff(&chain, &states, &from, &to)
+    }
+}
+
+macro_rules! respond_result {
+    ( $req:expr, $success:expr, $message:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let payload = ApiResponse {
+            success: $success,
+            message: $message.to_string(),
+        };
+        let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
+macro_rules! respond_query {
+    ( $req:expr, $result:expr ) => {{
+        match $result {
+            Ok(payload) => respond_json!($req, payload),
+            Err(e) => respond_result!($req, false, e),
+        }
+    }};
+}
+
+impl Server {
+    pub fn start(
+        addr: std::net::SocketAddr,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        block_state: &Arc<Mutex<StateStore>>,
+        history: &Arc<Mutex<History>>,
+    ) -> (Handle, Vec<thread::JoinHandle<()>>) {
+        let handle = HTTPServer::http(addr).unwrap();
+        let (exit_sender, exit_chan) = channel::unbounded::<()>();
+        let archive = Archive {
+            blockchain: blockchain.clone(),
+            block_state: block_state.clone(),
+            history: history.clone(),
+        };
+        let server = Self { handle, archive };
+        let server_thread = thread::Builder::new()
+            .name("api".to_string())
+            .spawn(move || while let Err(TryRecvError::Empty) = exit_chan.try_recv() {
+                let req = match server.handle.recv_timeout(EXIT_POLL_INTERVAL) {
+                    Ok(Some(req)) => req,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!("Error receiving API request: {}", e);
+                        return;
+                    }
+                };
+                let archive = server.archive.clone();
+                thread::spawn(move || {
+                    // a valid url requires a base
+                    let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
+                    let url = match base_url.join(req.url()) {
+                        Ok(u) => u,
+                        Err(e) => {
+                            respond_result!(req, false, format!("error parsing url: {}", e));
+                            return;
+                        }
+                    };
+                    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                    match url.path() {
+                        "/history/account" => respond_query!(req, archive.account(&params)),
+                        "/history/proof" => respond_query!(req, archive.proof(&params)),
+                        "/history/transactions" => respond_query!(req, archive.transactions(&params)),
+                        "/history/blocks" => respond_query!(req, archive.blocks(&params)),
+                        "/history/diff" => respond_query!(req, archive.diff(&params)),
+                        _ => {
+                            let content_type =
+                                "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = ApiResponse {
+                                success: false,
+                                message: "endpoint not found".to_string(),
+                            };
+                            let resp = Response::from_string(
+                                serde_json::to_string_pretty(&payload).unwrap(),
+                            )
+                            .with_header(content_type)
+                            .with_status_code(404);
+                            req.respond(resp).unwrap();
+                        }
+                    }
+                });
+            })
+            .unwrap();
+        info!("API server listening at {}", &addr);
+        (Handle { exit_chan: exit_sender }, vec![server_thread])
+    }
+}
+
+/// Stops the API server
+pub struct Handle {
+    exit_chan: channel::Sender<()>,
+}
+
+impl Handle {
+    /// Stop accepting requests. The server thread exits once the channel is dropped, the requests
+    /// being handled are still answered.
+    pub fn exit(self) {
+        std::mem::drop(self.exit_chan);
+    }
+}
@@ -0,0 +1,96 @@
+//! Blocks connected by the archival node, appended to a log in the state directory in the order
+//! they connected, so that parents come before their children. The log is replayed into the
+//! chain at start.
+
+use bitcoin::block::Block;
+use serde::de::DeserializeOwned;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Name of the log in the state directory
+const LOG_FILE: &str = "blocks.log";
+
+pub struct BlockLog {
+    log: File,
+}
+
+impl BlockLog {
+    /// Open the log persisted in `dir`, creating it if needed, with the blocks it holds
+    pub fn open(dir: &Path) -> io::Result<(Self, Vec<Block>)> {
+        fs::create_dir_all(dir)?;
+        let (log, blocks) = open_log(&dir.join(LOG_FILE))?;
+        Ok((BlockLog { log }, blocks))
+    }
+
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        self.log.write_all(&bincode::serialize(block).unwrap())
+    }
+
+    /// Flush the log to disk, before the node exits
+    pub fn close(&mut self) -> io::Result<()> {
+        self.log.sync_all()
+    }
+}
+
+/// Open a log of bincode records for appending, creating it if needed, and read its records. A
+/// record cut short by a crash at the end of the log is dropped.
+pub fn open_log<T: DeserializeOwned>(path: &Path) -> io::Result<(File, Vec<T>)> {
+    let mut log = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    let mut bytes = Vec::new();
+    log.read_to_end(&mut bytes)?;
+    let mut records = Vec::new();
+    let mut reader = &bytes[..];
+    while !reader.is_empty() {
+        let remaining = reader.len();
+        match bincode::deserialize_from(&mut reader) {
+            Ok(record) => records.push(record),
+            Err(_) => {
+                log.set_len((bytes.len() - remaining) as u64)?;
+                break;
+            }
+        }
+    }
+    Ok((log, records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::block::{Content, Header};
+    use bitcoin::crypto::hash::{H256, Hashable};
+
+    #[test]
+    fn blocks_replay_in_order_without_a_torn_record() {
+        let dir = std::env::temp_dir().join(format!("archival-blocks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (mut log, blocks) = BlockLog::open(&dir).unwrap();
+        assert!(blocks.is_empty());
+        let mut parent = H256::default();
+        let mut hashes = Vec::new();
+        for timestamp in 0..3 {
+            let block = Block {
+                head: Header {
+                    parent_hash: parent,
+                    nonce: 0,
+                    difficulty: H256::default(),
+                    timestamp,
+                    merkle_root: H256::default(),
+                    state_root: H256::default(),
+                },
+                content: Content { content: Vec::new() },
+            };
+            log.append(&block).unwrap();
+            parent = block.hash();
+            hashes.push(parent);
+        }
+        log.log.write_all(&[1, 2, 3]).unwrap();
+        drop(log);
+
+        let (_, blocks) = BlockLog::open(&dir).unwrap();
+        assert_eq!(blocks.iter().map(|block| block.hash()).collect::<Vec<_>>(), hashes);
+        let size = fs::metadata(dir.join(LOG_FILE)).unwrap().len();
+        assert_eq!(size, 3 * bincode::serialize(&blocks[0]).unwrap().len() as u64);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +0,0 @@
-pub mod bit_vec;
-pub mod murmur3;
-pub mod lib;
\ No newline at end of file
@@ -1,3 +0,0 @@
-pub mod hash;
-pub mod merkle;
-pub mod key_pair;
@@ -0,0 +1,379 @@
+//! Queries over the archived account states, backed by secondary indexes of the blocks built as
+//! they connect.
+
+use bitcoin::block::Block;
+use bitcoin::blockchain::Blockchain;
+use bitcoin::crypto::hash::{H160, H256, Hashable};
+use bitcoin::state::{self, AccountProof};
+use crate::state_store::StateStore;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Maximum number of blocks returned by `/history/blocks`
+pub const MAX_BLOCKS: usize = 500;
+
+/// Position of a transaction in the block tree
+#[derive(Clone, Copy)]
+struct TxLocation {
+    block: H256,
+    index: usize,
+}
+
+/// Secondary indexes of every connected block, including those off the longest chain; queries
+/// only answer from the longest chain.
+pub struct History {
+    /// Transactions sent or received by each address
+    by_address: HashMap<H160, Vec<TxLocation>>,
+    /// Blocks by timestamp
+    by_time: BTreeMap<u128, Vec<H256>>,
+}
+
+#[derive(Serialize)]
+pub struct BlockRef {
+    pub hash: String,
+    pub height: usize,
+}
+
+#[derive(Serialize)]
+pub struct AccountView {
+    pub address: String,
+    pub block: BlockRef,
+    pub nonce: u32,
+    pub balance: u32,
+}
+
+#[derive(Serialize)]
+pub struct AccountProofView {
+    pub block: BlockRef,
+    pub state_root: String,
+    pub address: String,
+    pub nonce: u32,
+    pub balance: u32,
+    /// Siblings of the path to the account's leaf in the state tree, from the root down
+    pub siblings: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct TxView {
+    pub hash: String,
+    pub block: BlockRef,
+    /// Position of the transaction in its block
+    pub index: usize,
+    pub sender: String,
+    pub recipient: String,
+    pub value: u32,
+    pub fee: u32,
+    pub nonce: u32,
+}
+
+#[derive(Serialize)]
+pub struct BlockSummary {
+    pub hash: String,
+    pub height: usize,
+    pub timestamp: u128,
+    pub txs: usize,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct AccountState {
+    pub nonce: u32,
+    pub balance: u32,
+}
+
+/// An account whose state differs between two blocks, absent from one of them if `None`
+#[derive(Serialize)]
+pub struct AccountDiff {
+    pub address: String,
+    pub before: Option<AccountState>,
+    pub after: Option<AccountState>,
+}
+
+#[derive(Serialize)]
+pub struct StateDiff {
+    pub from: BlockRef,
+    pub to: BlockRef,
+    pub accounts: Vec<AccountDiff>,
+}
+
+fn block_ref(chain: &Blockchain, hash: &H256) -> BlockRef {
+    BlockRef {
+        hash: hash.to_string(),
+        height: chain.chain.get(hash).unwrap().1,
+    }
+}
+
+fn block_summary(chain: &Blockchain, timestamp: u128, hash: &H256) -> BlockSummary {
+    let (block, height) = chain.chain.get(hash).unwrap();
+    BlockSummary {
+        hash: hash.to_string(),
+        height: *height,
+        timestamp,
+        txs: block.content.content.len(),
+    }
+}
+
+fn account_state((nonce, balance): &(u32, u32)) -> AccountState {
+    AccountState {
+        nonce: *nonce,
+        balance: *balance,
+    }
+}
+
+/// A block given by its height in the longest chain or by its hash
+pub fn resolve(chain: &Blockchain, block: &str) -> Result<H256, String> {
+    if let Ok(height) = block.parse::<usize>() {
+        return chain.block_at_height(height).ok_or_else(|| "block not found".to_string());
+    }
+    let hash: H256 = block.parse().map_err(|e| format!("error parsing block: {}", e))?;
+    if !chain.chain.contains_key(&hash) {
+        return Err("block not found".to_string());
+    }
+    Ok(hash)
+}
+
+impl History {
+    /// Create the indexes of a chain, indexing the blocks it already holds
+    pub fn new(chain: &Blockchain) -> Self {
+        let mut history = History {
+            by_address: HashMap::new(),
+            by_time: BTreeMap::new(),
+        };
+        for (block, _) in chain.chain.values() {
+            history.connect(block);
+        }
+        history
+    }
+
+    /// Index a block inserted into the chain
+    pub fn connect(&mut self, block: &Block) {
+        let hash = block.hash();
+        for (index, tx) in block.content.content.iter().enumerate() {
+            let location = TxLocation { block: hash, index };
+            let sender = tx.sender();
+            self.by_address.entry(sender).or_default().push(location);
+            if tx.transaction.address != sender {
+                self.by_address.entry(tx.transaction.address).or_default().push(location);
+            }
+        }
+        self.by_time.entry(block.head.timestamp).or_default().push(hash);
+    }
+
+    /// Nonce and balance of an address after a block
+    pub fn account_at(
+        &self,
+        chain: &Blockchain,
+        states: &StateStore,
+        address: &H160,
+        block: &H256,
+    ) -> Result<AccountView, String> {
+        let state = states.get(block).ok_or("no state computed for the block")?;
+        // accounts still in their default state are left out of the state
+        let account = account_state(state.get(address).unwrap_or(&state::DEFAULT_ACCOUNT));
+        Ok(AccountView {
+            address: address.to_hex(),
+            block: block_ref(chain, block),
+            nonce: account.nonce,
+            balance: account.balance,
+        })
+    }
+
+    /// Proof of the nonce and balance of an address after a block, against its state root
+    pub fn account_proof(
+        &self,
+        chain: &Blockchain,
+        states: &StateStore,
+        address: &H160,
+        block: &H256,
+    ) -> Result<AccountProofView, String> {
+        let state = states.get(block).ok_or("no state computed for the block")?;
+        let proof = AccountProof::new(&state, address);
+        Ok(AccountProofView {
+            block: block_ref(chain, block),
+            state_root: chain.chain.get(block).unwrap().0.head.state_root.to_string(),
+            address: address.to_hex(),
+            nonce: proof.account.0,
+            balance: proof.account.1,
+            siblings: proof.siblings.iter().map(H256::to_string).collect(),
+        })
+    }
+
+    /// Transactions of the longest chain sent or received by an address, oldest first
+    pub fn transactions(&self, chain: &Blockchain, address: &H160) -> Vec<TxView> {
+        let mut locations: Vec<(usize, TxLocation)> = self
+            .by_address
+            .get(address)
+            .into_iter()
+            .flatten()
+            .filter(|location| chain.in_longest_chain(&location.block))
+            .map(|location| (chain.chain.get(&location.block).unwrap().1, *location))
+            .collect();
+        locations.sort_by_key(|(height, location)| (*height, location.index));
+        locations
+            .into_iter()
+            .map(|(height, location)| {
+                let tx = &chain.chain.get(&location.block).unwrap().0.content.content[location.index];
+                TxView {
+                    hash: tx.hash().to_string(),
+                    block: BlockRef {
+                        hash: location.block.to_string(),
+                        height,
+                    },
+                    index: location.index,
+                    sender: tx.sender().to_hex(),
+                    recipient: tx.transaction.address.to_hex(),
+                    value: tx.transaction.value,
+                    fee: tx.transaction.fee,
+                    nonce: tx.transaction.nonce,
+                }
+            })
+            .collect()
+    }
+
+    /// Blocks of the longest chain with a timestamp between `from` and `to`, both included, by
+    /// timestamp
+    pub fn blocks_between(&self, chain: &Blockchain, from: u128, to: u128) -> Result<Vec<BlockSummary>, String> {
+        if from > to {
+            return Err("from is above to".to_string());
+        }
+        let blocks: Vec<BlockSummary> = self
+            .by_time
+            .range(from..=to)
+            .flat_map(|(timestamp, hashes)| hashes.iter().map(move |hash| (*timestamp, hash)))
+            .filter(|(_, hash)| chain.in_longest_chain(hash))
+            .map(|(timestamp, hash)| block_summary(chain, timestamp, hash))
+            .take(MAX_BLOCKS + 1)
+            .collect();
+        if blocks.len() > MAX_BLOCKS {
+            return Err(format!("more than {} blocks in the range", MAX_BLOCKS));
+        }
+        Ok(blocks)
+    }
+
+    /// The latest `MAX_BLOCKS` blocks of the longest chain with a timestamp up to `to`, included,
+    /// by timestamp
+    pub fn latest_blocks(&self, chain: &Blockchain, to: u128) -> Vec<BlockSummary> {
+        let mut blocks: Vec<BlockSummary> = self
+            .by_time
+            .range(..=to)
+            .rev()
+            .flat_map(|(timestamp, hashes)| hashes.iter().rev().map(move |hash| (*timestamp, hash)))
+            .filter(|(_, hash)| chain.in_longest_chain(hash))
+            .map(|(timestamp, hash)| block_summary(chain, timestamp, hash))
+            .take(MAX_BLOCKS)
+            .collect();
+        blocks.reverse();
+        blocks
+    }
+
+    /// Accounts whose state changed between two blocks, by address
+    pub fn diff(&self, chain: &Blockchain, states: &StateStore, from: &H256, to: &H256) -> Result<StateDiff, String> {
+        let before = states.get(from).ok_or("no state computed for from")?;
+        let after = states.get(to).ok_or("no state computed for to")?;
+        let mut addresses: Vec<&H160> = before.keys().chain(after.keys().filter(|a| !before.contains_key(a))).collect();
+        addresses.sort_by_key(|address| address.to_hex());
+        let accounts = addresses
+            .into_iter()
+            .map(|address| AccountDiff {
+                address: address.to_hex(),
+                before: before.get(address).map(account_state),
+                after: after.get(address).map(account_state),
+            })
+            .filter(|diff| diff.before != diff.after)
+            .collect();
+        Ok(StateDiff {
+            from: block_ref(chain, from),
+            to: block_ref(chain, to),
+            accounts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::block::{Content, Header};
+    use bitcoin::crypto::key_pair;
+    use bitcoin::crypto::merkle::MerkleTree;
+    use bitcoin::transaction::{self, SignedTransaction, Transaction};
+    use ring::signature::KeyPair;
+
+    fn block_with(parent: H256, timestamp: u128, txs: Vec<SignedTransaction>) -> Block {
+        Block {
+            head: Header {
+                parent_hash: parent,
+                nonce: 0,
+                difficulty: H256::default(),
+                timestamp,
+                merkle_root: MerkleTree::new(&txs).root(),
+                state_root: H256::default(),
+            },
+            content: Content { content: txs },
+        }
+    }
+
+    #[test]
+    fn queries_follow_the_longest_chain() {
+        let mut chain = Blockchain::new();
+        let genesis = chain.tip();
+        let mut history = History::new(&chain);
+        let key = key_pair::random();
+        let sender = key_pair::address(&key);
+        let recipient = key_pair::address(&key_pair::random());
+        let tx = Transaction {
+            self_balance: 100,
+            address: recipient,
+            value: 10,
+            nonce: 1,
+            fee: 1,
+        };
+        let tx = SignedTransaction {
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: transaction::sign(&tx, &key).as_ref().to_vec(),
+            transaction: tx,
+        };
+
+        let block = block_with(genesis, 10, vec![tx]);
+        chain.insert(&block);
+        history.connect(&block);
+        let fork = block_with(genesis, 20, block.content.content.clone());
+        chain.insert(&fork);
+        history.connect(&fork);
+
+        assert_eq!(history.transactions(&chain, &recipient).len(), 1);
+        let sent = history.transactions(&chain, &sender);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].block.hash, block.hash().to_string());
+        let blocks = history.blocks_between(&chain, 5, 30).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].hash, block.hash().to_string());
+        let mut parent = block.hash();
+        for timestamp in 0..MAX_BLOCKS as u128 {
+            let next = block_with(parent, 100 + timestamp, Vec::new());
+            chain.insert(&next);
+            history.connect(&next);
+            parent = next.hash();
+        }
+        assert!(history.blocks_between(&chain, 0, u128::MAX).is_err());
+        let latest = history.latest_blocks(&chain, u128::MAX);
+        assert_eq!(latest.len(), MAX_BLOCKS);
+        assert_eq!((latest[0].height, latest[MAX_BLOCKS - 1].hash.clone()), (2, parent.to_string()));
+        let early = history.latest_blocks(&chain, 100);
+        assert_eq!(early.iter().map(|block| block.timestamp).collect::<Vec<_>>(), vec![0, 10, 100]);
+        assert_eq!(resolve(&chain, "1"), Ok(block.hash()));
+
+        let mut states = StateStore::new();
+        let genesis_state = vec![(sender, (0, 100)), (recipient, (0, 100))].into_iter().collect();
+        states.insert(genesis, &H256::default(), &genesis_state).unwrap();
+        let block_state = vec![(sender, (1, 89)), (recipient, (0, 110))].into_iter().collect();
+        states.insert(block.hash(), &genesis, &block_state).unwrap();
+        let account = history.account_at(&chain, &states, &sender, &block.hash()).unwrap();
+        assert_eq!((account.nonce, account.balance), (1, 89));
+        let untouched = key_pair::address(&key_pair::random());
+        let account = history.account_at(&chain, &states, &untouched, &block.hash()).unwrap();
+        assert_eq!((account.nonce, account.balance), state::DEFAULT_ACCOUNT);
+        let diff = history.diff(&chain, &states, &genesis, &block.hash()).unwrap();
+        assert_eq!(diff.accounts.len(), 2);
+        assert_eq!(diff.accounts.iter().find(|a| a.address == recipient.to_hex()).unwrap().after,
+            Some(AccountState { nonce: 0, balance: 110 }));
+    }
+}
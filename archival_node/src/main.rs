@@ -1,51 +1,73 @@
-#[macro_use]
-extern crate hex_literal;
-
-pub mod block;
-pub mod blockchain;
-pub mod crypto;
-pub mod network;
-pub mod transaction;
-pub mod bloomfilter;
+mod api;
+mod block_log;
+mod history;
+mod state_store;
+mod worker;
 
 use clap::clap_app;
 use crossbeam::channel;
 use log::{error, info};
-use network::{server, worker};
+use bitcoin::network::server;
 use std::net;
 use std::process;
 use std::thread;
 use std::time;
 use std::sync::Mutex;
 use std::sync::Arc;
-use crate::blockchain::Blockchain;
+use bitcoin::blockchain::Blockchain;
 use std::collections::HashMap;
-use crate::network::worker::OrphanBuffer;
-use crate::crypto::key_pair;
+use bitcoin::network::worker::OrphanBuffer;
+use bitcoin::crypto::key_pair;
 use ring::signature::Ed25519KeyPair;
 use ring::signature::KeyPair;
-use crate::crypto::hash::{H256, H160};
-use crate::network::peer::ReadResult::Message;
-use crate::bloomfilter::lib::BloomFilter;
+use bitcoin::crypto::hash::{H256, H160};
+use bitcoin::network::peer::ReadResult::Message;
+use bitcoin::bloomfilter::lib::BloomFilter;
+use bitcoin::events::EventBus;
+use crate::block_log::BlockLog;
+use crate::history::History;
+use crate::state_store::StateStore;
 
 fn main() {
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
      (version: "0.1")
-     (about: "Bitcoin client")
+     (about: "Archival node computing the account state of every block")
      (@arg verbose: -v ... "Increases the verbosity of logging")
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
+     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the history API server")
+     (@arg state_dir: --("state-dir") [DIR] default_value("archival-state") "Sets the directory storing the account state of every block")
+     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg ping_interval: --("ping-interval") [SECS] default_value("30") "Sets the interval between pings sent to each peer")
+     (@arg max_missed_pongs: --("max-missed-pongs") [INT] default_value("3") "Sets the number of unanswered pings after which a peer is disconnected")
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let new_chain = Arc::new(Mutex::new(Blockchain::new()));
     let new_buf = Arc::new(Mutex::new(OrphanBuffer::new()));
     let mut state = Arc::new(Mutex::new(HashMap::new()));
-    let mut block_state = Arc::new(Mutex::new(HashMap::new()));
+    let state_dir = matches.value_of("state_dir").unwrap();
+    let block_state = StateStore::open(std::path::Path::new(state_dir)).unwrap_or_else(|e| {
+        error!("Error opening the state directory {}: {}", state_dir, e);
+        process::exit(1);
+    });
+    let block_state = Arc::new(Mutex::new(block_state));
+    // rebuild the chain from the blocks archived before the last shutdown
+    let (block_log, archived) = BlockLog::open(std::path::Path::new(state_dir)).unwrap_or_else(|e| {
+        error!("Error opening the block log in {}: {}", state_dir, e);
+        process::exit(1);
+    });
+    let mut chain = Blockchain::new();
+    for block in &archived {
+        chain.insert(block);
+    }
+    info!("Replayed {} archived blocks, chain length {}", archived.len(), chain.height() + 1);
+    let new_chain = Arc::new(Mutex::new(chain));
+    let block_log = Arc::new(Mutex::new(block_log));
+    let history = Arc::new(Mutex::new(History::new(&new_chain.lock().unwrap())));
     let mut bloom_filter = BloomFilter::new(1000, 0.03);
 
     // parse p2p server address
@@ -58,13 +80,48 @@ fn main() {
             process::exit(1);
         });
 
+    // parse api server address
+    let api_addr = matches
+        .value_of("api_addr")
+        .unwrap()
+        .parse::<net::SocketAddr>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing API server address: {}", e);
+            process::exit(1);
+        });
+
+
+    // parse peer liveness settings
+    let ping_interval = matches
+        .value_of("ping_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ping interval: {}", e);
+            process::exit(1);
+        });
+    let max_missed_pongs = matches
+        .value_of("max_missed_pongs")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max missed pongs: {}", e);
+            process::exit(1);
+        });
 
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    let (server_ctx, server) = server::new(
+        p2p_addr,
+        msg_tx,
+        time::Duration::from_secs(ping_interval),
+        max_missed_pongs,
+        &EventBus::new(),
+    )
+    .unwrap();
+    let server_thread = server_ctx.start().unwrap();
 
     // start the worker
     let p2p_workers = matches
@@ -91,15 +148,22 @@ fn main() {
         &new_buf,
         &state,
         address.clone(),
-        &block_state
+        &block_state,
+        &history,
+        &block_log,
     );
-    worker_ctx.start();
+    let worker_threads = worker_ctx.start();
 
-    // connect to known peers
+    // start the history API server
+    let (api, api_threads) = api::Server::start(api_addr, &new_chain, &block_state, &history);
+
+    // connect to known peers, until the node shuts down
+    let (connect_exit, connect_exit_chan) = channel::unbounded::<()>();
+    let mut connect_thread = None;
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
-        thread::spawn(move || {
+        let thread = thread::Builder::new().name("connect".to_string()).spawn(move || {
             for peer in known_peers {
                 loop {
                     let addr = match peer.parse::<net::SocketAddr>() {
@@ -119,15 +183,60 @@ fn main() {
                                 "Error connecting to peer {}, retrying in one second: {}",
                                 addr, e
                             );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
+                            let retry = connect_exit_chan.recv_timeout(time::Duration::from_millis(1000));
+                            if let Err(channel::RecvTimeoutError::Timeout) = retry {
+                                continue;
+                            }
+                            return;
                         }
                     }
                 }
             }
         });
+        connect_thread = Some(thread.unwrap());
+    }
+    // wait for SIGINT or SIGTERM
+    let (shutdown_sender, shutdown_receiver) = channel::unbounded();
+    ctrlc::set_handler(move || {
+        let _ = shutdown_sender.send(());
+    })
+    .unwrap_or_else(|e| {
+        error!("Error setting the signal handler: {}", e);
+        process::exit(1);
+    });
+    shutdown_receiver.recv().unwrap();
+    info!("Shutting down");
+
+    // stop connecting to peers and answering API requests, so that nothing reaches the P2P server
+    // once it stops
+    let mut status = 0;
+    std::mem::drop(connect_exit);
+    if let Some(thread) = connect_thread {
+        if thread.join().is_err() {
+            error!("Thread connect panicked");
+            status = 1;
+        }
+    }
+    api.exit();
+    // drain the network; the workers exit once the P2P server has stopped and they have handled
+    // the messages it received, then nothing writes to the archive anymore
+    server.shutdown();
+    let threads = std::iter::once(server_thread).chain(api_threads).chain(worker_threads);
+    for thread in threads {
+        let name = thread.thread().name().unwrap_or("unnamed").to_string();
+        if thread.join().is_err() {
+            error!("Thread {} panicked", name);
+            status = 1;
+        }
+    }
+    if let Err(e) = block_state.lock().unwrap().close() {
+        error!("Error closing the state store: {}", e);
+        status = 1;
     }
-    loop {
-        std::thread::park();
+    if let Err(e) = block_log.lock().unwrap().close() {
+        error!("Error closing the block log: {}", e);
+        status = 1;
     }
+    info!("Shutdown complete");
+    process::exit(status);
 }
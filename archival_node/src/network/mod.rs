@@ -1,4 +0,0 @@
-pub mod message;
-pub mod peer;
-pub mod server;
-pub mod worker;
@@ -0,0 +1,176 @@
+//! Account state after every archived block. Each block stores the accounts it changed, with a
+//! full checkpoint every `CHECKPOINT_INTERVAL` blocks of a branch, so rebuilding a state applies
+//! at most that many diffs. Stored states are appended to a log replayed at start.
+
+use bitcoin::crypto::hash::H256;
+use bitcoin::state::AccountStates;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use crate::block_log::open_log;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Maximum number of diffs between a block and the checkpoint its state is rebuilt from
+pub const CHECKPOINT_INTERVAL: usize = 64;
+
+/// Name of the log in the state directory
+const LOG_FILE: &str = "states.log";
+
+#[derive(Serialize, Deserialize)]
+enum Stored {
+    /// Every account, after a block of parent `parent`
+    Checkpoint { parent: H256, state: AccountStates },
+    /// The accounts changed since the state of `parent`, `depth` blocks after a checkpoint.
+    /// Accounts are never removed, so the changes are enough to rebuild the state.
+    Diff {
+        parent: H256,
+        depth: usize,
+        changed: AccountStates,
+    },
+}
+
+pub struct StateStore {
+    states: HashMap<H256, Stored>,
+    /// Blocks whose child has a stored state, built on theirs
+    parents: HashSet<H256>,
+    /// The log new states are appended to, none for a store kept in memory
+    log: Option<File>,
+}
+
+impl StateStore {
+    /// Create an empty store kept in memory
+    pub fn new() -> Self {
+        StateStore {
+            states: HashMap::new(),
+            parents: HashSet::new(),
+            log: None,
+        }
+    }
+
+    /// Open the store persisted in `dir`, creating it if needed. A record cut short by a crash
+    /// at the end of the log is dropped.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let (log, records) = open_log::<(H256, Stored)>(&dir.join(LOG_FILE))?;
+        let mut store = Self::new();
+        for (hash, stored) in records {
+            store.parents.insert(*stored.parent());
+            store.states.insert(hash, stored);
+        }
+        store.log = Some(log);
+        Ok(store)
+    }
+
+    /// Rebuild the state after a block from the closest checkpoint
+    pub fn get(&self, hash: &H256) -> Option<AccountStates> {
+        let mut diffs = Vec::new();
+        let mut hash = *hash;
+        let mut state = loop {
+            match self.states.get(&hash)? {
+                Stored::Checkpoint { state, .. } => break state.clone(),
+                Stored::Diff { parent, changed, .. } => {
+                    diffs.push(changed);
+                    hash = *parent;
+                }
+            }
+        };
+        for changed in diffs.into_iter().rev() {
+            state.extend(changed.iter().map(|(address, account)| (*address, *account)));
+        }
+        Some(state)
+    }
+
+    /// Store the state after a block as a diff from its parent's state, or as a checkpoint if
+    /// the parent has none or is `CHECKPOINT_INTERVAL` blocks after the last one. A state
+    /// already stored is replaced, unless the states of its children were built on it.
+    pub fn insert(&mut self, hash: H256, parent: &H256, state: &AccountStates) -> io::Result<()> {
+        if self.states.contains_key(&hash) && self.parents.contains(&hash) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("the state of block {} already has children", hash),
+            ));
+        }
+        let depth = match self.states.get(parent) {
+            Some(Stored::Checkpoint { .. }) => Some(1),
+            Some(Stored::Diff { depth, .. }) if depth + 1 < CHECKPOINT_INTERVAL => Some(depth + 1),
+            _ => None,
+        };
+        let stored = match (depth, self.get(parent)) {
+            (Some(depth), Some(parent_state)) => Stored::Diff {
+                parent: *parent,
+                depth,
+                changed: state
+                    .iter()
+                    .filter(|(address, account)| parent_state.get(address) != Some(account))
+                    .map(|(address, account)| (*address, *account))
+                    .collect(),
+            },
+            _ => Stored::Checkpoint {
+                parent: *parent,
+                state: state.clone(),
+            },
+        };
+        if let Some(log) = &mut self.log {
+            let record = bincode::serialize(&(hash, &stored)).unwrap();
+            log.write_all(&record)?;
+        }
+        self.parents.insert(*parent);
+        self.states.insert(hash, stored);
+        Ok(())
+    }
+
+    /// Flush the log to disk, before the node exits
+    pub fn close(&mut self) -> io::Result<()> {
+        match &self.log {
+            Some(log) => log.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Stored {
+    fn parent(&self) -> &H256 {
+        match self {
+            Stored::Checkpoint { parent, .. } | Stored::Diff { parent, .. } => parent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::crypto::hash::H160;
+
+    fn block_hash(height: usize) -> H256 {
+        ring::digest::digest(&ring::digest::SHA256, height.to_string().as_bytes()).into()
+    }
+
+    #[test]
+    fn states_rebuild_from_diffs_and_the_log() {
+        let dir = std::env::temp_dir().join(format!("archival-states-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let address: H160 = block_hash(1000).into();
+        let mut store = StateStore::open(&dir).unwrap();
+        let mut parent = H256::default();
+        for height in 0..CHECKPOINT_INTERVAL + 2 {
+            let state: AccountStates = vec![(address, (height as u32, 100))].into_iter().collect();
+            store.insert(block_hash(height), &parent, &state).unwrap();
+            parent = block_hash(height);
+        }
+        assert!(matches!(store.states.get(&block_hash(0)), Some(Stored::Checkpoint { .. })));
+        assert!(matches!(store.states.get(&block_hash(1)), Some(Stored::Diff { depth: 1, .. })));
+        assert!(matches!(store.states.get(&block_hash(CHECKPOINT_INTERVAL)), Some(Stored::Checkpoint { .. })));
+        let tip = block_hash(CHECKPOINT_INTERVAL + 1);
+        let replaced: AccountStates = vec![(address, (0, 1))].into_iter().collect();
+        store.insert(tip, &block_hash(CHECKPOINT_INTERVAL), &replaced).unwrap();
+        assert_eq!(store.get(&tip).unwrap().get(&address), Some(&(0, 1)));
+        assert!(store.insert(block_hash(1), &block_hash(0), &replaced).is_err());
+
+        let store = StateStore::open(&dir).unwrap();
+        let state = store.get(&block_hash(CHECKPOINT_INTERVAL - 1)).unwrap();
+        assert_eq!(state.get(&address), Some(&(CHECKPOINT_INTERVAL as u32 - 1, 100)));
+        assert_eq!(store.states.len(), CHECKPOINT_INTERVAL + 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
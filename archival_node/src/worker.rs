@@ -0,0 +1,336 @@
+use bitcoin::network::message::{Message, PROTOCOL_VERSION};
+use bitcoin::network::peer;
+use bitcoin::network::spv::MerkleBlock;
+use bitcoin::network::server::Handle as ServerHandle;
+use bitcoin::network::sync::MAX_HEADERS;
+use bitcoin::network::worker::{Connection, OrphanBuffer};
+use crossbeam::channel;
+use log::{debug, error, warn};
+use std::sync::Arc;
+use bitcoin::blockchain::Blockchain;
+use std::sync::Mutex;
+use std::thread;
+use bitcoin::crypto::hash::{H256, Hashable, H160};
+use bitcoin::block::Block;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, VecDeque, HashSet};
+use bitcoin::transaction::SignedTransaction;
+use std::borrow::BorrowMut;
+use bitcoin::bloomfilter::lib::BloomFilter;
+use bitcoin::state::{self, AccountProof};
+use crate::block_log::BlockLog;
+use crate::history::History;
+use crate::state_store::StateStore;
+
+#[derive(Clone)]
+pub struct Context {
+    bloom_filter: BloomFilter,
+    msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    num_worker: usize,
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    orphanBuf: Arc<Mutex<OrphanBuffer>>,
+    init_state: Arc<Mutex<HashMap<H160,(u32, u32)>>>, // <address, (nonce, balance)>
+    address: H160,
+    block_state: Arc<Mutex<StateStore>>,
+    /// Indexes of the connected blocks, locked after `orphanBuf`
+    history: Arc<Mutex<History>>,
+    /// Log of the connected blocks, locked after `history`
+    block_log: Arc<Mutex<BlockLog>>,
+}
+
+/// The archive a connected block is added to
+struct Archive<'a> {
+    states: &'a mut StateStore,
+    history: &'a mut History,
+    block_log: &'a mut BlockLog,
+}
+
+/// Validate a block whose parent is in the chain against the stored state of its parent, then
+/// insert it, archive it with its state and send the state to the full nodes as a witness
+fn connect_block(chain: &mut Blockchain, archive: &mut Archive, server: &ServerHandle, block: &Block) -> Connection {
+    let parent_state = match archive.states.get(&block.head.parent_hash) {
+        Some(state) => state,
+        None => return Connection::Held,
+    };
+    let state = match state::state_after(&parent_state, block) {
+        Ok(state) => state,
+        Err(e) => {
+            warn!("Block {} invalid: {}", block.hash(), e);
+            return Connection::Invalid;
+        }
+    };
+    chain.insert(block);
+    archive.history.connect(block);
+    // the state goes first, a replayed block always finds it
+    if let Err(e) = archive.states.insert(block.hash(), &block.head.parent_hash, &state) {
+        error!("Error storing the state of block {}: {}", block.hash(), e);
+    } else if let Err(e) = archive.block_log.append(block) {
+        error!("Error archiving block {}: {}", block.hash(), e);
+    }
+    server.broadcast(Message::NewState((block.hash(), state)));
+    debug!("Connected block {}, chain length {}", block.hash(), chain.height() + 1);
+    Connection::Connected
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn new(
+    bloom_filter: BloomFilter,
+    num_worker: usize,
+    msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    server: &ServerHandle,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    orphanBuf: &Arc<Mutex<OrphanBuffer>>,
+    init_state: &Arc<Mutex<HashMap<H160, (u32, u32)>>>,
+    address: H160,
+    block_state: &Arc<Mutex<StateStore>>,
+    history: &Arc<Mutex<History>>,
+    block_log: &Arc<Mutex<BlockLog>>,
+) -> Context {
+    let blockchain = blockchain.clone();
+    let init_state = init_state.clone();
+    let mut block_state = block_state.clone();
+    Context {
+        bloom_filter: bloom_filter,
+        msg_chan: msg_src,
+        num_worker,
+        server: server.clone(),
+        blockchain: blockchain,
+        orphanBuf: orphanBuf.clone(),
+        init_state: init_state,
+        address: address,
+        block_state: block_state,
+        history: history.clone(),
+        block_log: block_log.clone(),
+    }
+}
+
+impl Context {
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
+        let num_worker = self.num_worker;
+        (0..num_worker)
+            .map(|i| {
+                let mut cloned = self.clone();
+                thread::Builder::new()
+                    .name(format!("worker-{}", i))
+                    .spawn(move || {
+                        cloned.worker_loop();
+                        debug!("Worker thread {} exited", i);
+                    })
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Handle messages until the P2P server stops and closes the message channel.
+    fn worker_loop(&mut self) {
+        while let Ok(msg) = self.msg_chan.recv() {
+            let (msg, peer) = msg;
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Disconnecting peer {} sending an undecodable message: {}", peer.addr(), e);
+                    self.server.drop_peer(peer.addr());
+                    continue;
+                }
+            };
+            let mut current_chain = self.blockchain.lock().unwrap();
+            //println!("len:{:?}",current_chain.height());
+            let mut curr_block_state = self.block_state.lock().unwrap();
+            let mut init_state = self.init_state.lock().unwrap();
+            let mut bloom_filter = self.bloom_filter.clone();
+            match msg {
+                Message::Version(version) => {
+                    debug!("Version: {}", version);
+                    if version != PROTOCOL_VERSION {
+                        warn!("Disconnecting peer {} speaking protocol version {}", peer.addr(), version);
+                        self.server.drop_peer(peer.addr());
+                    } else {
+                        peer.set_version(version);
+                    }
+                }
+                Message::Ping(nonce) => {
+                    debug!("Ping: {}", nonce);
+                    peer.write(Message::Pong(nonce));
+                }
+                Message::Pong(nonce) => {
+                    debug!("Pong: {}", nonce);
+                    peer.pong(&nonce);
+                }
+                Message::GetHeaders(locator) => {
+                    // full nodes sync their headers from any peer
+                    peer.write(Message::Headers(current_chain.headers_after(&locator, MAX_HEADERS)));
+                }
+                Message::Headers(_) => {
+                    debug!("Headers");
+                }
+                Message::CompactBlock(compact) => {
+                    // there is no mempool to rebuild the block from, fetch all of it
+                    let hash = compact.hash();
+                    if !current_chain.chain.contains_key(&hash) {
+                        peer.write(Message::GetBlocks(vec![hash]));
+                    }
+                }
+                Message::GetBlockTxn(..) | Message::BlockTxn(..) => {
+                    debug!("Compact block transactions");
+                }
+
+                Message::NewPeer(newPeer) => {
+                    // Receive init message by a new coming peer
+                    let mut init_block_state = curr_block_state;
+                    if !init_state.contains_key(&newPeer){
+                        // println!("Got new peer");
+                        init_state.insert(newPeer,(0,100));
+                        bloom_filter.insert((H160::to_string(&newPeer.clone())+"0"+"100").as_str());
+                    }
+                    // the initial accounts are the state of the genesis block, the states of
+                    // later blocks come from their transactions
+                    let genesis = current_chain.block_at_height(0).unwrap();
+                    if let Err(e) = init_block_state.insert(genesis, &H256::default(), &init_state) {
+                        debug!("Keeping the stored genesis state: {}", e);
+                    }
+                    // the genesis state lets the new peer start, the tip state lets it validate
+                    // the blocks after the tip; the others are served on request
+                    let tip = current_chain.tip();
+                    let hashes = if tip == genesis { vec![genesis] } else { vec![genesis, tip] };
+                    for hash in hashes {
+                        if let Some(state) = init_block_state.get(&hash) {
+                            self.server.broadcast(Message::NewState((hash, state)));
+                        }
+                    }
+                }
+                Message::GetAccountProof(address, block_hash) => {
+                    if let Some(state) = curr_block_state.get(&block_hash) {
+                        peer.write(Message::AccountProof(block_hash, AccountProof::new(&state, &address)));
+                    }
+                }
+                Message::AccountProof(..) => {
+                    debug!("AccountProof");
+                }
+                Message::GetMerkleBlocks(hashes, addresses) => {
+                    // light nodes download the filtered blocks from any full node
+                    let merkle_blocks: Vec<MerkleBlock> = hashes
+                        .iter()
+                        .filter_map(|hash| current_chain.chain.get(hash))
+                        .map(|(block, _)| MerkleBlock::new(block, &addresses))
+                        .collect();
+                    if !merkle_blocks.is_empty() {
+                        peer.write(Message::MerkleBlocks(merkle_blocks));
+                    }
+                }
+                Message::MerkleBlocks(_) => {
+                    debug!("MerkleBlocks");
+                }
+                Message::Ack(newPeerList) => {
+                    debug!("Ack");
+                }
+                Message::NewState(NewState)=>{
+                    debug!("NewState");
+                }
+                Message::NewTransactionHashes(NewTransactionHashes) =>{
+                    debug!("NewTransactionHashes");
+                }
+                Message::GetTransactions(GetTransactions) =>{
+                    debug!("GetTransactions");
+                }
+                Message::Transactions(Transactions) =>{
+                    debug!("Transactions");
+                }
+                Message::NewBlockHashes(NewBlockHashes) =>{
+                    //debug!("NewBlockHashes");
+                    let mut block_vec = Vec::new();
+                    //println!("receiver chain height {:?}",current_chain.height());
+                    for hash in NewBlockHashes.clone(){
+                        if !current_chain.chain.contains_key(&hash) {
+                            block_vec.push(hash);
+                        }
+                    }
+                    println!("receiver missing block {:?}",block_vec.len());
+                    if (&block_vec).len() > 0 {
+                        peer.write(Message::GetBlocks(block_vec));
+                    }
+                }
+
+                Message::GetBlocks(GetBlocks)=>{
+                    //debug!("GetBlocks");
+                    //println!("Sender get request {:?}",GetBlocks.len());
+                    let mut block_vec = Vec::new();
+                    for hash in GetBlocks{
+                        if let Some((block, _)) = current_chain.chain.get(&hash){
+                            block_vec.push(block.clone());
+                            //println!("sent:{:?}",newBlock.hash());
+                        }
+                    }
+                    //println!("sender send {:?}",block_vec.len());
+                    if (&block_vec).len() > 0{
+                        peer.write(Message::Blocks(block_vec));
+                    }
+                }
+
+                Message::Blocks(Blocks)=>{
+                    //debug!("Blocks");
+                    let mut verified_blocks = Vec::new();
+                    let mut orphan_buffer = self.orphanBuf.lock().unwrap();
+                    let mut history = self.history.lock().unwrap();
+                    let mut block_log = self.block_log.lock().unwrap();
+                    let mut archive = Archive {
+                        states: &mut curr_block_state,
+                        history: &mut history,
+                        block_log: &mut block_log,
+                    };
+                    for block in Blocks{
+                        if !current_chain.chain.contains_key(&(block.hash())){
+                            let newBlock = block.clone();
+                            //PoW validity check, and the transactions must be the ones committed
+                            if current_chain.check_block(&newBlock) {
+                                verified_blocks.push(newBlock.hash());
+                                if current_chain.chain.contains_key(&newBlock.head.parent_hash) {
+                                    // 4. Check the transfers against the bloom filter of known accounts
+                                    let mut flag = true;
+                                    for tx in newBlock.content.content.iter() {
+                                        if !bloom_filter.maybe_present((H160::to_string(&tx.transaction.address.clone())+&tx.transaction.nonce.wrapping_sub(1).to_string()+&tx.transaction.self_balance.to_string()).as_str()) {
+                                            flag = false;
+                                            warn!("Mismatch account nonce or value");
+                                        }
+                                    }
+                                    let connection = if flag {
+                                        connect_block(&mut current_chain, &mut archive, &self.server, &newBlock)
+                                    } else {
+                                        Connection::Invalid
+                                    };
+                                    match connection {
+                                        Connection::Connected => {
+                                            for tx in newBlock.content.content.iter() {
+                                                //Update bloom filter
+                                                bloom_filter.insert((H160::to_string(&tx.transaction.address.clone())+&tx.transaction.nonce.to_string()+&tx.transaction.self_balance.to_string()).as_str());
+                                            }
+                                        }
+                                        Connection::Invalid => {}
+                                        Connection::Held => orphan_buffer.addOrphan(&newBlock),
+                                    }
+                                }else{
+                                    // Add Orphan to buffer
+                                    orphan_buffer.addOrphan(&newBlock);
+                                    debug!("Found orphan {}", newBlock.hash());
+                                }
+                            }
+                        }
+                    }
+                    let server = self.server.clone();
+                    orphan_buffer.connect_orphans(&mut current_chain, |chain, block| {
+                        connect_block(chain, &mut archive, &server, block)
+                    });
+                    let orphan_vec = orphan_buffer.missing_parents(&current_chain);
+                    //println!("orphan_vector:{:?}",orphan_vec.len());
+                    if orphan_vec.len() > 0 {
+                        self.server.broadcast(Message::GetBlocks(orphan_vec));
+                    }
+                    if verified_blocks.len() > 0{
+                        self.server.broadcast(Message::NewBlockHashes(verified_blocks));
+                    }
+                }
+
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! Read-only views of the chain, the mempool and the account state, with hashes and addresses
+//! rendered as hex.
+
+use crate::block::Header;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::SignedTransaction;
+use crate::txgenerator::TxMempool;
+use crate::network::worker;
+use crate::state::{self, AccountProof};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Maximum number of headers returned by `/blocks`
+pub const MAX_BLOCKS: usize = 500;
+
+#[derive(Serialize)]
+pub struct TipView {
+    pub hash: String,
+    pub height: usize,
+}
+
+#[derive(Serialize)]
+pub struct HeaderView {
+    pub hash: String,
+    pub height: usize,
+    pub parent_hash: String,
+    pub nonce: u32,
+    pub difficulty: String,
+    pub timestamp: u128,
+    pub merkle_root: String,
+    pub state_root: String,
+}
+
+#[derive(Serialize)]
+pub struct TxView {
+    pub hash: String,
+    pub sender: String,
+    pub recipient: String,
+    pub value: u32,
+    pub nonce: u32,
+    /// Hash and height of the block of the longest chain confirming the transaction
+    pub block: Option<TipView>,
+}
+
+#[derive(Serialize)]
+pub struct BlockView {
+    pub header: HeaderView,
+    pub transactions: Vec<TxView>,
+}
+
+#[derive(Serialize)]
+pub struct AccountView {
+    pub address: String,
+    pub nonce: u32,
+    pub balance: u32,
+    pub pending: Vec<TxView>,
+}
+
+#[derive(Serialize)]
+pub struct AccountProofView {
+    pub block: TipView,
+    pub state_root: String,
+    pub address: String,
+    pub nonce: u32,
+    pub balance: u32,
+    /// Siblings of the path to the account's leaf in the state tree, from the root down
+    pub siblings: Vec<String>,
+}
+
+/// Proof that a transaction is in a block, checked against the block's Merkle root
+#[derive(Serialize)]
+pub struct TxProofView {
+    pub block: TipView,
+    pub merkle_root: String,
+    pub transaction: TxView,
+    /// Position of the transaction in its block
+    pub index: usize,
+    /// Number of transactions in the block
+    pub tx_count: usize,
+    /// Siblings of the path to the transaction's leaf, from the root down
+    pub proof: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct MempoolView {
+    pub size: usize,
+    pub transactions: Vec<TxView>,
+}
+
+fn header_view(header: &Header, height: usize) -> HeaderView {
+    HeaderView {
+        hash: header.hash().to_string(),
+        height,
+        parent_hash: header.parent_hash.to_string(),
+        nonce: header.nonce,
+        difficulty: header.difficulty.to_string(),
+        timestamp: header.timestamp,
+        merkle_root: header.merkle_root.to_string(),
+        state_root: header.state_root.to_string(),
+    }
+}
+
+fn tx_view(tx: &SignedTransaction, block: Option<(H256, usize)>) -> TxView {
+    TxView {
+        hash: tx.hash().to_string(),
+        sender: tx.sender().to_hex(),
+        recipient: tx.transaction.address.to_hex(),
+        value: tx.transaction.value,
+        nonce: tx.transaction.nonce,
+        block: block.map(|(hash, height)| TipView {
+            hash: hash.to_string(),
+            height,
+        }),
+    }
+}
+
+fn parse_hash(hash: &str) -> Result<H256, String> {
+    hash.parse().map_err(|e| format!("error parsing hash: {}", e))
+}
+
+pub fn tip(chain: &Blockchain) -> TipView {
+    TipView {
+        hash: chain.tip().to_string(),
+        height: chain.height(),
+    }
+}
+
+pub fn block(chain: &Blockchain, hash: &str) -> Result<BlockView, String> {
+    let hash = parse_hash(hash)?;
+    let (block, height) = chain.chain.get(&hash).ok_or("block not found")?;
+    let confirmed = if chain.in_longest_chain(&hash) { Some((hash, *height)) } else { None };
+    Ok(BlockView {
+        header: header_view(&block.head, *height),
+        transactions: block.content.content.iter().map(|tx| tx_view(tx, confirmed)).collect(),
+    })
+}
+
+pub fn block_at_height(chain: &Blockchain, height: &str) -> Result<BlockView, String> {
+    let height: usize = height.parse().map_err(|e| format!("error parsing height: {}", e))?;
+    let hash = chain.block_at_height(height).ok_or("block not found")?;
+    block(chain, &hash.to_string())
+}
+
+/// Headers of the longest chain between two heights, both included. Without bounds, the latest
+/// `MAX_BLOCKS` headers.
+pub fn blocks(chain: &Blockchain, params: &HashMap<String, String>) -> Result<Vec<HeaderView>, String> {
+    let to = match params.get("to") {
+        Some(v) => v.parse().map_err(|e| format!("error parsing to: {}", e))?,
+        None => chain.height(),
+    };
+    let from = match params.get("from") {
+        Some(v) => v.parse().map_err(|e| format!("error parsing from: {}", e))?,
+        None => to.saturating_sub(MAX_BLOCKS - 1),
+    };
+    if from > to {
+        return Err("from is above to".to_string());
+    }
+    if to - from >= MAX_BLOCKS {
+        return Err(format!("at most {} blocks can be requested", MAX_BLOCKS));
+    }
+    Ok(chain
+        .all_blocks_in_longest_chain()
+        .iter()
+        .enumerate()
+        .skip(from)
+        .take(to - from + 1)
+        .map(|(height, hash)| header_view(&chain.chain.get(hash).unwrap().0.head, height))
+        .collect())
+}
+
+/// A transaction of the longest chain, with its block and height, or of the mempool
+pub fn find_transaction(
+    chain: &Blockchain,
+    pool: &TxMempool,
+    hash: &str,
+) -> Result<(SignedTransaction, Option<(H256, usize)>), String> {
+    let hash = parse_hash(hash)?;
+    if let Some(block_hash) = chain.confirming_block(&hash) {
+        let (block, height) = chain.chain.get(&block_hash).unwrap();
+        let tx = block.content.content.iter().find(|tx| tx.hash() == hash).unwrap();
+        return Ok((tx.clone(), Some((block_hash, *height))));
+    }
+    match pool.map.get(&hash) {
+        Some(tx) => Ok((tx.clone(), None)),
+        None => Err("transaction not found".to_string()),
+    }
+}
+
+pub fn transaction(chain: &Blockchain, pool: &TxMempool, hash: &str) -> Result<TxView, String> {
+    let (tx, block) = find_transaction(chain, pool, hash)?;
+    Ok(tx_view(&tx, block))
+}
+
+pub fn account(
+    pool: &TxMempool,
+    state: &HashMap<H160, (u32, u32)>,
+    address: &str,
+) -> Result<AccountView, String> {
+    let address: H160 = address.parse().map_err(|e| format!("error parsing address: {}", e))?;
+    // accounts still in their default state are left out of the state
+    let (nonce, balance) = state.get(&address).cloned().unwrap_or(state::DEFAULT_ACCOUNT);
+    let pending = pool
+        .buf
+        .iter()
+        .filter(|tx| tx.sender() == address || tx.transaction.address == address)
+        .map(|tx| tx_view(tx, None))
+        .collect();
+    Ok(AccountView {
+        address: address.to_hex(),
+        nonce,
+        balance,
+        pending,
+    })
+}
+
+/// Proof of the state of an account after a block, the tip if none is given
+pub fn account_proof(
+    chain: &Blockchain,
+    state: &HashMap<H160, (u32, u32)>,
+    address: &str,
+    block: Option<&String>,
+) -> Result<AccountProofView, String> {
+    let address: H160 = address.parse().map_err(|e| format!("error parsing address: {}", e))?;
+    let block = match block {
+        Some(hash) => parse_hash(hash)?,
+        None => chain.tip(),
+    };
+    let state = worker::state_at(chain, state, &block).ok_or("state not known, only the state of the tip is kept")?;
+    let proof = AccountProof::new(state, &address);
+    let (block, height) = chain.chain.get(&block).unwrap();
+    Ok(AccountProofView {
+        block: TipView {
+            hash: block.hash().to_string(),
+            height: *height,
+        },
+        state_root: block.head.state_root.to_string(),
+        address: address.to_hex(),
+        nonce: proof.account.0,
+        balance: proof.account.1,
+        siblings: proof.siblings.iter().map(H256::to_string).collect(),
+    })
+}
+
+/// Proof of inclusion of a transaction in the block of the longest chain confirming it
+pub fn tx_proof(chain: &Blockchain, hash: &str) -> Result<TxProofView, String> {
+    let hash = parse_hash(hash)?;
+    let block_hash = chain.confirming_block(&hash).ok_or("transaction not confirmed")?;
+    let (block, height) = chain.chain.get(&block_hash).unwrap();
+    let index = block.content.content.iter().position(|tx| tx.hash() == hash).unwrap();
+    Ok(TxProofView {
+        block: TipView {
+            hash: block_hash.to_string(),
+            height: *height,
+        },
+        merkle_root: block.head.merkle_root.to_string(),
+        transaction: tx_view(&block.content.content[index], Some((block_hash, *height))),
+        index,
+        tx_count: block.content.content.len(),
+        proof: MerkleTree::new(&block.content.content).proof(index).iter().map(H256::to_string).collect(),
+    })
+}
+
+pub fn mempool(pool: &TxMempool) -> MempoolView {
+    MempoolView {
+        size: pool.buf.len(),
+        transactions: pool.buf.iter().map(|tx| tx_view(tx, None)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_mined_block;
+
+    #[test]
+    fn blocks_default_to_the_latest_headers() {
+        let mut chain = Blockchain::new();
+        let easy: H256 = [255u8; 32].into();
+        for _ in 0..MAX_BLOCKS + 10 {
+            let block = generate_mined_block(&chain.tip(), &easy);
+            chain.insert(&block);
+        }
+        let headers = blocks(&chain, &HashMap::new()).unwrap();
+        assert_eq!(headers.len(), MAX_BLOCKS);
+        assert_eq!((headers[0].height, headers[MAX_BLOCKS - 1].height), (11, MAX_BLOCKS + 10));
+
+        let params: HashMap<String, String> = vec![("to".to_string(), "20".to_string())].into_iter().collect();
+        assert_eq!(blocks(&chain, &params).unwrap().len(), 21);
+        let params: HashMap<String, String> = vec![("from".to_string(), "0".to_string())].into_iter().collect();
+        assert!(blocks(&chain, &params).is_err());
+    }
+
+    #[test]
+    fn untouched_accounts_have_the_default_state() {
+        let address = crate::crypto::key_pair::address(&crate::crypto::key_pair::random());
+        let view = account(&TxMempool::new(), &HashMap::new(), &address.to_hex()).unwrap();
+        assert_eq!((view.nonce, view.balance), state::DEFAULT_ACCOUNT);
+        assert!(account(&TxMempool::new(), &HashMap::new(), "not an address").is_err());
+    }
+}
@@ -1,12 +1,30 @@
-use serde::Serialize;
+mod explorer;
+mod rpc;
+
+use serde::{Deserialize, Serialize};
 use crate::miner::Handle as MinerHandle;
 use crate::txgenerator::Handle as GeneratorHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::network::server::DEFAULT_BAN_DURATION;
+use crate::crypto::hash::H160;
+use crate::blockchain::Blockchain;
+use crate::network::sync::Synchronizer;
+use crate::txgenerator::TxMempool;
+use crate::network::worker::{self, OrphanTxPool, TxAcceptance, TxRejection};
+use crate::transaction::{self, SignedTransaction, Transaction};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use crate::crypto::hash::Hashable;
+use crate::events::EventBus;
+use crate::metrics;
+use crate::wallet::{hd, Keystore, Wallet};
+use std::sync::{Arc, Mutex, RwLock};
 
-use log::info;
-use std::collections::HashMap;
+use crossbeam::channel::{self, select, TryRecvError};
+use log::{error, info};
+use std::collections::{HashMap, VecDeque};
 use std::thread;
+use std::time;
 use tiny_http::Header;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
@@ -14,17 +32,155 @@ use url::Url;
 
 pub struct Server {
     handle: HTTPServer,
+    node: Node,
+}
+
+/// Handles to the node shared by the REST routes and the JSON-RPC methods
+#[derive(Clone)]
+struct Node {
     generator: GeneratorHandle,
     miner: MinerHandle,
     network: NetworkServerHandle,
+    address: H160,
+    /// The wallet key of the node, signing the unsigned transactions submitted to it
+    key: Arc<Ed25519KeyPair>,
+    /// The wallet keystore, if the node was started with one
+    keystore: Option<Arc<Mutex<Keystore>>>,
+    /// Balances of the keystore's addresses, or of the node's address without a keystore
+    wallet: Arc<Mutex<Wallet>>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    tx_pool: Arc<RwLock<TxMempool>>,
+    curr_state: Arc<RwLock<HashMap<H160, (u32, u32)>>>,
+    orphan_txs: Arc<Mutex<OrphanTxPool>>,
+    sync: Arc<Mutex<Synchronizer>>,
+    /// The latest events published on the event bus, oldest first
+    recent_events: Arc<Mutex<VecDeque<String>>>,
+    /// Asks the main thread to shut the node down
+    shutdown: crossbeam::channel::Sender<()>,
 }
 
+/// Number of events kept for `/events/recent`
+const MAX_RECENT_EVENTS: usize = 100;
+/// Interval at which the API server checks whether it should stop, when no request comes in
+const EXIT_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
 #[derive(Serialize)]
 struct ApiResponse {
     success: bool,
     message: String,
 }
 
+#[derive(Serialize)]
+struct SubmitResponse {
+    success: bool,
+    hash: String,
+    /// Whether the transaction waits in the orphan pool for the transactions before it
+    orphan: bool,
+    rejection: Option<TxRejection>,
+    message: String,
+}
+
+impl SubmitResponse {
+    fn new(tx: &SignedTransaction, result: Result<TxAcceptance, TxRejection>) -> Self {
+        match result {
+            Ok(acceptance) => SubmitResponse {
+                success: true,
+                hash: tx.hash().to_string(),
+                orphan: acceptance == TxAcceptance::Orphan,
+                rejection: None,
+                message: "ok".to_string(),
+            },
+            Err(reason) => SubmitResponse {
+                success: false,
+                hash: tx.hash().to_string(),
+                orphan: false,
+                message: reason.to_string(),
+                rejection: Some(reason),
+            },
+        }
+    }
+}
+
+/// Body of `/wallet/send`
+#[derive(Deserialize)]
+struct SendRequest {
+    to: String,
+    value: u32,
+    #[serde(default)]
+    fee: u32,
+    /// Address of the wallet key paying, the node's key if absent
+    from: Option<String>,
+}
+
+impl Node {
+    /// Build and sign the transfer of a `/wallet/send` request
+    fn build_transfer(&self, request: &SendRequest) -> Result<SignedTransaction, String> {
+        let to: H160 = request.to.parse().map_err(|e| format!("error parsing to: {}", e))?;
+        let from: Option<H160> = match &request.from {
+            Some(from) => Some(from.parse().map_err(|e| format!("error parsing from: {}", e))?),
+            None => None,
+        };
+        let wallet_key;
+        let key = match from {
+            Some(from) if from != self.address => {
+                let keystore = self.keystore.as_ref().ok_or("the node has no wallet")?;
+                wallet_key = keystore.lock().unwrap().key_pair(&from).map_err(|e| e.to_string())?;
+                &wallet_key
+            }
+            _ => &*self.key,
+        };
+        let pool = self.tx_pool.read().unwrap();
+        let wallet = self.wallet.lock().unwrap();
+        wallet
+            .build_transfer(key, &pool, to, request.value, request.fee)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Validate a submitted transaction, add it to the mempool and relay it, along with the
+    /// orphan transactions it unblocks.
+    fn submit_transaction(&self, tx: &SignedTransaction) -> Result<TxAcceptance, TxRejection> {
+        let mut pool = self.tx_pool.write().unwrap();
+        let state = self.curr_state.read().unwrap();
+        let mut orphans = self.orphan_txs.lock().unwrap();
+        let result = worker::accept_transaction(tx, &mut pool, &state, &mut orphans);
+        let mut relayed = Vec::new();
+        if let Ok(TxAcceptance::Mempool) = result {
+            relayed.push(tx.hash());
+            relayed.extend(worker::promote_orphan_txs(&mut orphans, &mut pool, &state));
+        }
+        std::mem::drop(orphans);
+        std::mem::drop(state);
+        std::mem::drop(pool);
+        if !relayed.is_empty() {
+            self.network.relay_transactions(relayed, None);
+        }
+        result
+    }
+
+    /// Parse a submitted transaction like `parse_transaction`, signing it with the node's key if
+    /// it is a bare JSON `Transaction`
+    fn parse_or_sign_transaction(&self, body: &str) -> Result<SignedTransaction, String> {
+        parse_transaction(body).or_else(|e| match serde_json::from_str::<Transaction>(body.trim()) {
+            Ok(tx) => Ok(SignedTransaction {
+                public_key: self.key.public_key().as_ref().to_vec(),
+                signature: transaction::sign(&tx, &self.key).as_ref().to_vec(),
+                transaction: tx,
+            }),
+            Err(_) => Err(e),
+        })
+    }
+}
+
+/// Parse a submitted transaction, either as JSON or as hex of its bincode serialization
+fn parse_transaction(body: &str) -> Result<SignedTransaction, String> {
+    let body = body.trim();
+    if body.starts_with('{') {
+        return serde_json::from_str(body).map_err(|e| format!("error parsing transaction: {}", e));
+    }
+    let bytes = hex::decode(body).map_err(|e| format!("error parsing transaction: {}", e))?;
+    bincode::deserialize(&bytes).map_err(|e| format!("error parsing transaction: {}", e))
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -38,25 +194,88 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: std::net::SocketAddr,
         generator: &GeneratorHandle,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
-    ) {
+        address: H160,
+        blockchain: &Arc<RwLock<Blockchain>>,
+        tx_pool: &Arc<RwLock<TxMempool>>,
+        curr_state: &Arc<RwLock<HashMap<H160, (u32, u32)>>>,
+        orphan_txs: &Arc<Mutex<OrphanTxPool>>,
+        sync: &Arc<Mutex<Synchronizer>>,
+        key: Arc<Ed25519KeyPair>,
+        keystore: Option<Arc<Mutex<Keystore>>>,
+        wallet: &Arc<Mutex<Wallet>>,
+        events: &EventBus,
+        shutdown: &crossbeam::channel::Sender<()>,
+    ) -> (Handle, Vec<thread::JoinHandle<()>>) {
         let handle = HTTPServer::http(&addr).unwrap();
-        let server = Self {
-            handle,
+        let (exit_sender, exit_chan) = channel::unbounded::<()>();
+        let recent_events = Arc::new(Mutex::new(VecDeque::new()));
+        let subscription = events.subscribe();
+        let recent = recent_events.clone();
+        let exit = exit_chan.clone();
+        let events_thread = thread::Builder::new()
+            .name("api-events".to_string())
+            .spawn(move || loop {
+                select! {
+                    recv(subscription) -> event => {
+                        let event = match event {
+                            Ok(event) => event,
+                            Err(_) => return,
+                        };
+                        let mut recent = recent.lock().unwrap();
+                        if recent.len() == MAX_RECENT_EVENTS {
+                            recent.pop_front();
+                        }
+                        recent.push_back(event.to_string());
+                    }
+                    recv(exit) -> _ => return,
+                }
+            })
+            .unwrap();
+        let node = Node {
             generator: generator.clone(),
             miner: miner.clone(),
             network: network.clone(),
+            address,
+            key,
+            keystore,
+            wallet: wallet.clone(),
+            blockchain: blockchain.clone(),
+            tx_pool: tx_pool.clone(),
+            curr_state: curr_state.clone(),
+            orphan_txs: orphan_txs.clone(),
+            sync: sync.clone(),
+            recent_events,
+            shutdown: shutdown.clone(),
         };
-        thread::spawn(move || {
-            for req in server.handle.incoming_requests() {
-                let generator = server.generator.clone();
-                let miner = server.miner.clone();
-                let network = server.network.clone();
+        let server = Self { handle, node };
+        let server_thread = thread::Builder::new()
+            .name("api".to_string())
+            .spawn(move || while let Err(TryRecvError::Empty) = exit_chan.try_recv() {
+                let req = match server.handle.recv_timeout(EXIT_POLL_INTERVAL) {
+                    Ok(Some(req)) => req,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!("Error receiving API request: {}", e);
+                        return;
+                    }
+                };
+                let node = server.node.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -89,12 +308,312 @@ impl Server {
                                     return;
                                 }
                             };
-                            generator.start(lambda);
-                            miner.start(lambda);
+                            node.generator.start(lambda);
+                            node.miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/admin/shutdown" => {
+                            respond_result!(req, true, "shutting down");
+                            let _ = node.shutdown.send(());
+                        }
+                        "/wallet/balance" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let pool = node.tx_pool.read().unwrap();
+                            let wallet = node.wallet.lock().unwrap();
+                            let addresses = match params.get("address").map(|v| v.parse::<H160>()) {
+                                None => wallet.addresses(),
+                                Some(Ok(address)) => vec![address],
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                            };
+                            let balances: Result<Vec<_>, _> =
+                                addresses.iter().map(|address| wallet.balance(address, &pool)).collect();
+                            match balances {
+                                Ok(balances) => respond_json!(req, balances),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/history" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let address = match params.get("address").map(|v| v.parse::<H160>()) {
+                                None => None,
+                                Some(Ok(address)) => Some(address),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                            };
+                            let pool = node.tx_pool.read().unwrap();
+                            respond_json!(req, node.wallet.lock().unwrap().history(address.as_ref(), &pool));
+                        }
+                        "/wallet/send" => {
+                            let mut req = req;
+                            if *req.method() != tiny_http::Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let request: SendRequest = match serde_json::from_str(&body) {
+                                Ok(request) => request,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing request: {}", e));
+                                    return;
+                                }
+                            };
+                            match node.build_transfer(&request) {
+                                Ok(tx) => respond_json!(req, SubmitResponse::new(&tx, node.submit_transaction(&tx))),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/scan" => {
+                            let keystore = match &node.keystore {
+                                Some(keystore) => keystore,
+                                None => {
+                                    respond_result!(req, false, "the node has no wallet");
+                                    return;
+                                }
+                            };
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let gap_limit = match params.get("gap_limit").map(|v| v.parse::<u32>()) {
+                                None => hd::DEFAULT_GAP_LIMIT,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing gap_limit: {}", e));
+                                    return;
+                                }
+                            };
+                            let chain = node.blockchain.read().unwrap();
+                            let found = keystore.lock().unwrap().scan(gap_limit, |address| chain.address_used(address));
+                            match found {
+                                Ok(found) => {
+                                    let mut wallet = node.wallet.lock().unwrap();
+                                    for address in &found {
+                                        wallet.watch(*address, &chain);
+                                    }
+                                    respond_json!(req, found.iter().map(H160::to_hex).collect::<Vec<_>>())
+                                }
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
                         "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            node.network.broadcast(Message::Ping(String::from("Test ping")));
+                            respond_result!(req, true, "ok");
+                        }
+                        "/sync/status" => {
+                            let chain = node.blockchain.read().unwrap();
+                            let progress = node.sync.lock().unwrap().progress(&chain);
+                            respond_json!(req, progress);
+                        }
+                        "/chain/tip" => {
+                            respond_json!(req, explorer::tip(&node.blockchain.read().unwrap()));
+                        }
+                        "/chain/height" => {
+                            respond_json!(req, node.blockchain.read().unwrap().height());
+                        }
+                        "/blocks" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            match explorer::blocks(&node.blockchain.read().unwrap(), &params) {
+                                Ok(headers) => respond_json!(req, headers),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        path if path.starts_with("/block/height/") => {
+                            let height = &path["/block/height/".len()..];
+                            match explorer::block_at_height(&node.blockchain.read().unwrap(), height) {
+                                Ok(block) => respond_json!(req, block),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        path if path.starts_with("/block/") => {
+                            let hash = &path["/block/".len()..];
+                            match explorer::block(&node.blockchain.read().unwrap(), hash) {
+                                Ok(block) => respond_json!(req, block),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/tx/submit" => {
+                            let mut req = req;
+                            if *req.method() != tiny_http::Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let tx = match node.parse_or_sign_transaction(&body) {
+                                Ok(tx) => tx,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            respond_json!(req, SubmitResponse::new(&tx, node.submit_transaction(&tx)));
+                        }
+                        "/rpc" => {
+                            let mut req = req;
+                            if *req.method() != tiny_http::Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                            // a request made only of notifications gets no response body
+                            let resp = Response::from_string(rpc::handle(&node, &body).unwrap_or_default())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        path if path.starts_with("/tx/proof/") => {
+                            let hash = &path["/tx/proof/".len()..];
+                            let chain = node.blockchain.read().unwrap();
+                            if node.sync.lock().unwrap().is_light() {
+                                respond_result!(req, false, "light nodes only hold the transactions of their addresses");
+                                return;
+                            }
+                            match explorer::tx_proof(&chain, hash) {
+                                Ok(proof) => respond_json!(req, proof),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        path if path.starts_with("/tx/") => {
+                            let hash = &path["/tx/".len()..];
+                            let chain = node.blockchain.read().unwrap();
+                            let pool = node.tx_pool.read().unwrap();
+                            match explorer::transaction(&chain, &pool, hash) {
+                                Ok(tx) => respond_json!(req, tx),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        path if path.starts_with("/account/") => {
+                            let address = &path["/account/".len()..];
+                            let pool = node.tx_pool.read().unwrap();
+                            let state = node.curr_state.read().unwrap();
+                            match explorer::account(&pool, &state, address) {
+                                Ok(account) => respond_json!(req, account),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        path if path.starts_with("/state/proof/") => {
+                            let address = &path["/state/proof/".len()..];
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let chain = node.blockchain.read().unwrap();
+                            let state = node.curr_state.read().unwrap();
+                            match explorer::account_proof(&chain, &state, address, params.get("block")) {
+                                Ok(proof) => respond_json!(req, proof),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/mempool" => {
+                            respond_json!(req, explorer::mempool(&node.tx_pool.read().unwrap()));
+                        }
+                        "/metrics" => {
+                            let content_type = "Content-Type: text/plain; version=0.0.4"
+                                .parse::<Header>()
+                                .unwrap();
+                            let resp = Response::from_string(metrics::global().render())
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/events/recent" => {
+                            let recent: Vec<String> = node.recent_events.lock().unwrap().iter().cloned().collect();
+                            respond_json!(req, recent);
+                        }
+                        "/network/peers" => {
+                            respond_json!(req, node.network.peers());
+                        }
+                        "/network/connect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let addr = match addr.parse::<std::net::SocketAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                            };
+                            match node.network.connect(addr) {
+                                Ok(peer) => {
+                                    peer.write(Message::NewPeer(node.address));
+                                    respond_result!(req, true, "ok");
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error connecting to peer: {}", e));
+                                }
+                            }
+                        }
+                        "/network/disconnect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let addr = match addr.parse::<std::net::SocketAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                            };
+                            if node.network.disconnect(addr) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "peer not connected");
+                            }
+                        }
+                        "/network/ban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            // accept either a bare IP or a socket address of a connected peer
+                            let ip = match addr.parse::<std::net::IpAddr>() {
+                                Ok(v) => v,
+                                Err(_) => match addr.parse::<std::net::SocketAddr>() {
+                                    Ok(v) => v.ip(),
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing addr: {}", e));
+                                        return;
+                                    }
+                                },
+                            };
+                            let duration = match params.get("duration") {
+                                Some(v) => match v.parse::<u64>() {
+                                    Ok(v) => std::time::Duration::from_secs(v),
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing duration: {}", e));
+                                        return;
+                                    }
+                                },
+                                None => DEFAULT_BAN_DURATION,
+                            };
+                            node.network.ban(ip, duration);
                             respond_result!(req, true, "ok");
                         }
                         _ => {
@@ -113,8 +632,55 @@ impl Server {
                         }
                     }
                 });
-            }
-        });
+            })
+            .unwrap();
         info!("API server listening at {}", &addr);
+        (Handle { exit_chan: exit_sender }, vec![server_thread, events_thread])
+    }
+}
+
+/// Stops the API server
+pub struct Handle {
+    exit_chan: channel::Sender<()>,
+}
+
+impl Handle {
+    /// Stop accepting requests. The server threads exit once the channel is dropped, the requests
+    /// being handled are still answered.
+    pub fn exit(self) {
+        std::mem::drop(self.exit_chan);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::transaction::{self, Transaction};
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn transactions_parse_from_json_and_hex() {
+        let key = key_pair::random();
+        let tx = Transaction {
+            self_balance: 100,
+            address: key_pair::address(&key_pair::random()),
+            value: 10,
+            nonce: 1,
+            fee: 1,
+        };
+        let tx = SignedTransaction {
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: transaction::sign(&tx, &key).as_ref().to_vec(),
+            transaction: tx,
+        };
+        let json = serde_json::to_string(&tx).unwrap();
+        assert_eq!(parse_transaction(&json).unwrap().hash(), tx.hash());
+        let encoded = hex::encode(bincode::serialize(&tx).unwrap());
+        assert_eq!(parse_transaction(&encoded).unwrap().hash(), tx.hash());
+
+        assert!(parse_transaction(&encoded[1..]).is_err());
+        let signed = format!("+{}", &encoded[1..]);
+        assert!(parse_transaction(&signed).is_err());
     }
 }
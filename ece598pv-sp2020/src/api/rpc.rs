@@ -0,0 +1,255 @@
+//! JSON-RPC 2.0 methods, answered by the same handlers as the REST routes. Batch requests are
+//! answered with an array of responses, in the same order.
+
+use super::{explorer, parse_transaction, Node};
+use crate::crypto::hash::Hashable;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The requested block, transaction or account does not exist
+const NOT_FOUND: i64 = -5;
+/// The transaction was rejected by validation
+const VERIFY_REJECTED: i64 = -26;
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Answer a request body, which is a single request or a batch. Returns `None` when there is
+/// nothing to answer, because every request was a notification.
+pub fn handle(node: &Node, body: &str) -> Option<String> {
+    let request: Value = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(e) => return Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, e))).to_string()),
+    };
+    match request {
+        Value::Array(batch) => {
+            if batch.is_empty() {
+                let error = RpcError::new(INVALID_REQUEST, "empty batch");
+                return Some(response(Value::Null, Err(error)).to_string());
+            }
+            let responses: Vec<Value> = batch.into_iter().filter_map(|r| call(node, r)).collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(Value::Array(responses).to_string())
+            }
+        }
+        request => call(node, request).map(|r| r.to_string()),
+    }
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+/// Run a single request. Notifications, which have no id, get no response.
+fn call(node: &Node, request: Value) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let method = match (request.get("jsonrpc"), request.get("method")) {
+        (Some(Value::String(version)), Some(Value::String(method))) if version == "2.0" => method,
+        _ => {
+            let error = RpcError::new(INVALID_REQUEST, "invalid request");
+            return Some(response(id.unwrap_or(Value::Null), Err(error)));
+        }
+    };
+    let params = match request.get("params") {
+        None => Vec::new(),
+        Some(Value::Array(params)) => params.clone(),
+        Some(_) => {
+            let error = RpcError::new(INVALID_PARAMS, "params must be an array");
+            return id.map(|id| response(id, Err(error)));
+        }
+    };
+    let result = dispatch(node, method, &params);
+    id.map(|id| response(id, result))
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    Ok(serde_json::to_value(value).unwrap())
+}
+
+/// String parameter at `index`, or `default` if it is absent
+fn string_param<'a>(params: &'a [Value], index: usize, default: Option<&'a str>) -> Result<&'a str, RpcError> {
+    match params.get(index) {
+        Some(Value::String(s)) => Ok(s),
+        None => default.ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing parameter {}", index))),
+        Some(_) => Err(RpcError::new(INVALID_PARAMS, format!("parameter {} must be a string", index))),
+    }
+}
+
+fn dispatch(node: &Node, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+    match method {
+        "getblockcount" => to_value(node.blockchain.read().unwrap().height()),
+        "getbestblockhash" => to_value(node.blockchain.read().unwrap().tip().to_string()),
+        "getblock" => {
+            let hash = string_param(params, 0, None)?;
+            explorer::block(&node.blockchain.read().unwrap(), hash)
+                .map_err(|e| RpcError::new(NOT_FOUND, e))
+                .and_then(to_value)
+        }
+        "getrawtransaction" => {
+            let hash = string_param(params, 0, None)?;
+            let verbose = params.get(1).and_then(Value::as_bool).unwrap_or(false);
+            let chain = node.blockchain.read().unwrap();
+            let pool = node.tx_pool.read().unwrap();
+            if verbose {
+                return explorer::transaction(&chain, &pool, hash)
+                    .map_err(|e| RpcError::new(NOT_FOUND, e))
+                    .and_then(to_value);
+            }
+            let (tx, _) = explorer::find_transaction(&chain, &pool, hash).map_err(|e| RpcError::new(NOT_FOUND, e))?;
+            to_value(hex::encode(bincode::serialize(&tx).unwrap()))
+        }
+        "sendrawtransaction" => {
+            let tx = parse_transaction(string_param(params, 0, None)?).map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+            node.submit_transaction(&tx)
+                .map_err(|reason| RpcError::new(VERIFY_REJECTED, reason))?;
+            to_value(tx.hash().to_string())
+        }
+        "getbalance" => {
+            let own_address = node.address.to_hex();
+            let address = string_param(params, 0, Some(&own_address))?;
+            let pool = node.tx_pool.read().unwrap();
+            let state = node.curr_state.read().unwrap();
+            explorer::account(&pool, &state, address)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, e))
+                .and_then(|account| to_value(account.balance))
+        }
+        "getpeerinfo" => to_value(node.network.peers()),
+        "getmempoolinfo" => {
+            let size = node.tx_pool.read().unwrap().buf.len();
+            let orphans = node.orphan_txs.lock().unwrap().len();
+            to_value(json!({ "size": size, "orphans": orphans }))
+        }
+        "generate" => {
+            let blocks = match params.first() {
+                Some(v) => v.as_u64().ok_or_else(|| RpcError::new(INVALID_PARAMS, "block count must be a number"))?,
+                None => 1,
+            };
+            // mining needs transactions, so this only schedules the blocks
+            node.miner.generate(blocks);
+            to_value(blocks)
+        }
+        "stopminer" => {
+            node.miner.pause();
+            to_value(true)
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("method {} not found", method))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::crypto::key_pair;
+    use crate::network::server;
+    use crate::network::sync::Synchronizer;
+    use crate::network::worker::OrphanTxPool;
+    use crate::state::{StateCache, MAX_CACHED_STATES};
+    use crate::txgenerator::{self, TxMempool};
+    use crate::wallet::Wallet;
+    use crate::{events::EventBus, miner};
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Mutex, RwLock};
+
+    fn node() -> Node {
+        let network = server::test_handle();
+        let key = key_pair::random();
+        let address = key_pair::address(&key);
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let tx_pool = Arc::new(RwLock::new(TxMempool::new()));
+        let curr_state = Arc::new(RwLock::new(HashMap::new()));
+        let sync = Arc::new(Mutex::new(Synchronizer::new()));
+        let wallet = Arc::new(Mutex::new(Wallet::new(&[address])));
+        let (_, generator) = txgenerator::new(&network, &tx_pool, key_pair::random(), &Arc::new(RwLock::new(HashMap::new())), &wallet);
+        let block_states = Arc::new(Mutex::new(StateCache::new(MAX_CACHED_STATES)));
+        let (_, miner) = miner::new(&network, &blockchain, &tx_pool, &curr_state, &block_states, address, &sync, &EventBus::new());
+        Node {
+            generator,
+            miner,
+            network,
+            address,
+            key: Arc::new(key),
+            keystore: None,
+            wallet,
+            blockchain,
+            tx_pool,
+            curr_state,
+            orphan_txs: Arc::new(Mutex::new(OrphanTxPool::new())),
+            sync,
+            recent_events: Arc::new(Mutex::new(VecDeque::new())),
+            shutdown: crossbeam::channel::unbounded().0,
+        }
+    }
+
+    fn answer(node: &Node, body: &str) -> Value {
+        serde_json::from_str(&handle(node, body).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn malformed_requests_get_their_error_codes() {
+        let node = node();
+        let response = answer(&node, "{\"jsonrpc\": \"2.0\", ");
+        assert_eq!((response["error"]["code"].as_i64(), &response["id"]), (Some(PARSE_ERROR), &Value::Null));
+
+        let response = answer(&node, r#"{"jsonrpc": "1.0", "method": "getblockcount", "id": 1}"#);
+        assert_eq!((response["error"]["code"].as_i64(), &response["id"]), (Some(INVALID_REQUEST), &json!(1)));
+        let response = answer(&node, "[]");
+        assert_eq!(response["error"]["code"].as_i64(), Some(INVALID_REQUEST));
+
+        let response = answer(&node, r#"{"jsonrpc": "2.0", "method": "getblocks", "id": "a"}"#);
+        assert_eq!((response["error"]["code"].as_i64(), &response["id"]), (Some(METHOD_NOT_FOUND), &json!("a")));
+
+        let response = answer(&node, r#"{"jsonrpc": "2.0", "method": "getblock", "params": [1], "id": 2}"#);
+        assert_eq!(response["error"]["code"].as_i64(), Some(INVALID_PARAMS));
+        let response = answer(&node, r#"{"jsonrpc": "2.0", "method": "getblock", "params": {"hash": "00"}, "id": 3}"#);
+        assert_eq!(response["error"]["code"].as_i64(), Some(INVALID_PARAMS));
+    }
+
+    #[test]
+    fn batches_answer_in_order_without_notifications() {
+        let node = node();
+        let batch = r#"[
+            {"jsonrpc": "2.0", "method": "getblockcount", "id": 1},
+            {"jsonrpc": "2.0", "method": "getbestblockhash"},
+            {"jsonrpc": "2.0", "method": "nosuchmethod", "id": 3},
+            {"jsonrpc": "2.0", "method": "getbestblockhash", "id": 4}
+        ]"#;
+        let responses = answer(&node, batch);
+        let responses = responses.as_array().unwrap();
+        assert_eq!(responses.iter().map(|r| r["id"].clone()).collect::<Vec<_>>(), vec![json!(1), json!(3), json!(4)]);
+        assert_eq!(responses[0]["result"], json!(0));
+        assert_eq!(responses[1]["error"]["code"].as_i64(), Some(METHOD_NOT_FOUND));
+        let tip = node.blockchain.read().unwrap().tip().to_string();
+        assert_eq!(responses[2]["result"], json!(tip));
+    }
+
+    #[test]
+    fn notifications_get_no_response() {
+        let node = node();
+        assert_eq!(handle(&node, r#"{"jsonrpc": "2.0", "method": "getblockcount"}"#), None);
+        assert_eq!(handle(&node, r#"{"jsonrpc": "2.0", "method": "getblock", "params": 1}"#), None);
+        let batch = r#"[{"jsonrpc": "2.0", "method": "getblockcount"}, {"jsonrpc": "2.0", "method": "nosuchmethod"}]"#;
+        assert_eq!(handle(&node, batch), None);
+    }
+}
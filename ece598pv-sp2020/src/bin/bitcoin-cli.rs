@@ -0,0 +1,319 @@
+//! Command-line client of a node's API server.
+
+use bitcoin::crypto::hash::{H160, H256};
+use bitcoin::crypto::key_pair;
+use bitcoin::transaction::SignedTransaction;
+use clap::clap_app;
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process;
+
+/// Print `rows` under `headers`, each column as wide as its widest cell.
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    };
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+/// A JSON value as a table cell: strings without quotes, null as `-`
+fn cell(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".to_string(),
+        v => v.to_string(),
+    }
+}
+
+/// Prints the output of a command in a readable form
+type Printer = Box<dyn Fn(&Value)>;
+
+struct Client {
+    api: String,
+}
+
+impl Client {
+    /// Send a request to the API server and return the body of its response.
+    fn request(&self, method: &str, path: &str, body: &str) -> Result<String, String> {
+        let mut stream = TcpStream::connect(&self.api).map_err(|e| format!("error connecting to {}: {}", self.api, e))?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            self.api,
+            body.len(),
+            body
+        )
+        .map_err(|e| format!("error sending request: {}", e))?;
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .map_err(|e| format!("error reading response: {}", e))?;
+        let status = response.lines().next().unwrap_or_default();
+        if !status.split(' ').nth(1).is_some_and(|code| code.starts_with('2')) {
+            return Err(format!("the API server answered {}", status));
+        }
+        match response.find("\r\n\r\n") {
+            Some(end) => Ok(response[end + 4..].to_string()),
+            None => Err("malformed response".to_string()),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<Value, String> {
+        let body = self.request("GET", path, "")?;
+        serde_json::from_str(&body).map_err(|e| format!("error parsing response: {}", e))
+    }
+
+    fn post(&self, path: &str, body: &Value) -> Result<Value, String> {
+        let body = self.request("POST", path, &body.to_string())?;
+        serde_json::from_str(&body).map_err(|e| format!("error parsing response: {}", e))
+    }
+
+    /// Call a JSON-RPC method, returning its result.
+    fn rpc(&self, method: &str, params: Value) -> Result<Value, String> {
+        let response = self.post("/rpc", &json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }))?;
+        match response.get("error") {
+            Some(error) => Err(cell(&error["message"])),
+            None => Ok(response["result"].clone()),
+        }
+    }
+}
+
+/// The REST routes answer failures with `{"success": false, "message": ...}`
+fn check(response: Value) -> Result<Value, String> {
+    if response.get("success") == Some(&Value::Bool(false)) {
+        return Err(cell(&response["message"]));
+    }
+    Ok(response)
+}
+
+fn parse<T: std::str::FromStr>(what: &str, value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e| format!("error parsing {}: {}", what, e))
+}
+
+fn run(matches: &clap::ArgMatches) -> Result<(), String> {
+    let client = Client {
+        api: matches.value_of("api").unwrap().to_string(),
+    };
+    let raw = matches.is_present("json");
+    let (output, table): (Value, Printer) = match matches.subcommand() {
+        ("getbalance", Some(args)) => {
+            let path = match args.value_of("address") {
+                Some(address) => format!("/wallet/balance?address={}", parse::<H160>("address", address)?.to_hex()),
+                None => "/wallet/balance".to_string(),
+            };
+            let balances = check(client.get(&path)?)?;
+            (
+                balances,
+                Box::new(|balances: &Value| {
+                    let rows: Vec<Vec<String>> = balances
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .map(|b| vec![cell(&b["address"]), cell(&b["confirmed"]), cell(&b["pending"]), cell(&b["next_nonce"])])
+                        .collect();
+                    print_table(&["ADDRESS", "CONFIRMED", "PENDING", "NEXT NONCE"], &rows);
+                }),
+            )
+        }
+        ("send", Some(args)) => {
+            let to = parse::<H160>("address", args.value_of("address").unwrap())?;
+            let mut request = json!({
+                "to": to.to_hex(),
+                "value": parse::<u32>("amount", args.value_of("amount").unwrap())?,
+                "fee": parse::<u32>("fee", args.value_of("fee").unwrap())?,
+            });
+            if let Some(from) = args.value_of("from") {
+                request["from"] = json!(parse::<H160>("from", from)?.to_hex());
+            }
+            let response = check(client.post("/wallet/send", &request)?)?;
+            (
+                response,
+                Box::new(|response: &Value| {
+                    let status = if response["orphan"] == Value::Bool(true) { "waiting for earlier nonces" } else { "in the mempool" };
+                    println!("Sent {}, {}", cell(&response["hash"]), status);
+                }),
+            )
+        }
+        ("getblock", Some(args)) => {
+            let block = args.value_of("block").unwrap();
+            let path = match block.parse::<usize>() {
+                Ok(height) => format!("/block/height/{}", height),
+                Err(_) => format!("/block/{}", parse::<H256>("block hash", block)?),
+            };
+            let block = check(client.get(&path)?)?;
+            (
+                block,
+                Box::new(|block: &Value| {
+                    let header = &block["header"];
+                    for field in &["hash", "height", "parent_hash", "timestamp", "nonce", "difficulty", "merkle_root", "state_root"] {
+                        println!("{:<12} {}", field, cell(&header[*field]));
+                    }
+                    println!();
+                    let rows: Vec<Vec<String>> = block["transactions"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .map(|tx| vec![cell(&tx["hash"]), cell(&tx["sender"]), cell(&tx["recipient"]), cell(&tx["value"])])
+                        .collect();
+                    print_table(&["TRANSACTION", "FROM", "TO", "VALUE"], &rows);
+                }),
+            )
+        }
+        ("gettx", Some(args)) => {
+            let hash = parse::<H256>("transaction hash", args.value_of("hash").unwrap())?;
+            let raw = client.rpc("getrawtransaction", json!([hash.to_string()]))?;
+            let bytes = hex::decode(cell(&raw)).map_err(|e| format!("error decoding transaction: {}", e))?;
+            let tx: SignedTransaction =
+                bincode::deserialize(&bytes).map_err(|e| format!("error decoding transaction: {}", e))?;
+            let status = client.rpc("getrawtransaction", json!([hash.to_string(), true]))?;
+            let output = json!({
+                "hash": hash.to_string(),
+                "from": tx.sender().to_hex(),
+                "to": tx.transaction.address.to_hex(),
+                "value": tx.transaction.value,
+                "fee": tx.transaction.fee,
+                "nonce": tx.transaction.nonce,
+                "block": status["block"],
+            });
+            (
+                output,
+                Box::new(|tx: &Value| {
+                    for field in &["hash", "from", "to", "value", "fee", "nonce"] {
+                        println!("{:<6} {}", field, cell(&tx[*field]));
+                    }
+                    match tx["block"].get("hash") {
+                        Some(hash) => println!("{:<6} {} at height {}", "block", cell(hash), cell(&tx["block"]["height"])),
+                        None => println!("{:<6} pending", "block"),
+                    }
+                }),
+            )
+        }
+        ("peers", _) => {
+            let peers = check(client.get("/network/peers")?)?;
+            (
+                peers,
+                Box::new(|peers: &Value| {
+                    let rows: Vec<Vec<String>> = peers
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .map(|p| {
+                            vec![
+                                cell(&p["addr"]),
+                                cell(&p["direction"]),
+                                cell(&p["version"]),
+                                cell(&p["latency_ms"]),
+                                cell(&p["bytes_in"]),
+                                cell(&p["bytes_out"]),
+                                cell(&p["ban_score"]),
+                            ]
+                        })
+                        .collect();
+                    print_table(&["ADDRESS", "DIRECTION", "VERSION", "LATENCY MS", "BYTES IN", "BYTES OUT", "BAN SCORE"], &rows);
+                }),
+            )
+        }
+        ("mine", Some(args)) => {
+            let response = match args.subcommand() {
+                ("start", Some(args)) => {
+                    let lambda = parse::<u64>("lambda", args.value_of("lambda").unwrap())?;
+                    check(client.get(&format!("/miner/start?lambda={}", lambda))?)?
+                }
+                ("stop", _) => client.rpc("stopminer", json!([]))?,
+                ("generate", Some(args)) => {
+                    let blocks = parse::<u64>("block count", args.value_of("blocks").unwrap())?;
+                    client.rpc("generate", json!([blocks]))?
+                }
+                _ => return Err("unknown mine command, see --help".to_string()),
+            };
+            (response, Box::new(|_: &Value| println!("ok")))
+        }
+        ("keygen", _) => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            let output = json!({
+                "address": key_pair::address(&key).to_hex(),
+                "pkcs8": hex::encode(pkcs8.as_ref()),
+            });
+            (
+                output,
+                Box::new(|key: &Value| {
+                    println!("address {}", cell(&key["address"]));
+                    println!("pkcs8   {}", cell(&key["pkcs8"]));
+                }),
+            )
+        }
+        _ => return Err("unknown command, see --help".to_string()),
+    };
+    if raw {
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    } else {
+        table(&output);
+    }
+    Ok(())
+}
+
+fn main() {
+    let matches = clap_app!(("bitcoin-cli") =>
+     (version: "0.1")
+     (about: "Command-line client of a Bitcoin node's API server")
+     (@setting SubcommandRequiredElseHelp)
+     (@arg api: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the node's API server")
+     (@arg json: --json "Prints the raw JSON instead of a table")
+     (@subcommand getbalance =>
+      (about: "Shows the confirmed and pending balances of the node's wallet")
+      (@arg address: "Address to show, instead of every address of the wallet"))
+     (@subcommand send =>
+      (about: "Sends coins from the node's wallet")
+      (@arg address: +required "Recipient address")
+      (@arg amount: +required "Amount to send")
+      (@arg fee: --fee [AMOUNT] default_value("0") "Sets the fee paid on top of the amount")
+      (@arg from: --from [ADDRESS] "Sets the wallet address paying, instead of the node's address"))
+     (@subcommand getblock =>
+      (about: "Shows a block of the chain")
+      (@arg block: +required "Hash or height of the block"))
+     (@subcommand gettx =>
+      (about: "Shows a confirmed or pending transaction")
+      (@arg hash: +required "Hash of the transaction"))
+     (@subcommand peers => (about: "Lists the connected peers"))
+     (@subcommand mine =>
+      (about: "Controls the miner")
+      (@setting SubcommandRequiredElseHelp)
+      (@subcommand start =>
+       (about: "Starts mining continuously")
+       (@arg lambda: --lambda [MICROS] default_value("0") "Sets the pause between mining attempts"))
+      (@subcommand stop => (about: "Pauses the miner"))
+      (@subcommand generate =>
+       (about: "Mines a number of blocks, then pauses")
+       (@arg blocks: +required "Number of blocks")))
+     (@subcommand keygen => (about: "Generates a key, printing its address and its PKCS#8 document as hex"))
+    )
+    .get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
@@ -1,7 +1,6 @@
 extern crate chrono;
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable};
-use crate::crypto::merkle::MerkleTree;
 use super::transaction::{Transaction, SignedTransaction};
 use chrono::prelude::*;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -13,7 +12,10 @@ pub struct Header {
     pub nonce: u32,
     pub difficulty: H256,
     pub timestamp: u128,
-    pub merkle_root: MerkleTree,
+    /// Root of the Merkle tree of the block's transactions
+    pub merkle_root: H256,
+    /// Root of the account state after the block, see `state::state_root`
+    pub state_root: H256,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -51,34 +53,44 @@ impl Hashable for Block {
     }
 }
 
-// #[cfg(any(test, test_utilities))]
-// pub mod test {
-//     use super::*;
-//     use crate::crypto::hash::H256;
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::crypto::hash::H160;
+    use crate::crypto::merkle::MerkleTree;
 
-//     pub fn generate_random_block(parent: &H256) -> Block {
-//         let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("").as_millis();
-//         let mut content_test = Content{
-//             content: Vec::<Transaction>::new(),
-//         };
-//         let array: [u8; 16]  = rand::random();
-//         let trans = Transaction {
-//             content: array.to_vec(),
-//         };
-//         content_test.content.push(trans);
-//         let rand_diff_arr: [u8;32] = rand::random();
-//         let diff_h256: H256 = hex!("0010000000000000000000000000000000000000000000000000000000000000").into();
-//         let rand_nounce: u32 = rand::random();
-//         let head_rand = Header{
-//             parent_hash: *parent,
-//             nonce: rand_nounce,
-//             difficulty: diff_h256,
-//             timestamp: now,
-//             merkle_root: MerkleTree::new(&(content_test.content)),
-//         };
-//         Block {
-//             head: head_rand,
-//             content: content_test.clone(),
-//         }
-//     }
-// }
+    /// A block on `parent` holding a random transaction, with a nonce meeting `difficulty`
+    pub fn generate_mined_block(parent: &H256, difficulty: &H256) -> Block {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("").as_millis();
+        let address: [u8; 32] = rand::random();
+        let trans = Transaction {
+            self_balance: 100,
+            address: H160::from(H256::from(address)),
+            value: 0,
+            nonce: 1,
+            fee: 0,
+        };
+        let content_test = Content {
+            content: vec![SignedTransaction {
+                public_key: Vec::new(),
+                signature: Vec::new(),
+                transaction: trans,
+            }],
+        };
+        let mut head_rand = Header {
+            parent_hash: *parent,
+            nonce: rand::random(),
+            difficulty: *difficulty,
+            timestamp: now,
+            merkle_root: MerkleTree::new(&(content_test.content)).root(),
+            state_root: H256::default(),
+        };
+        while head_rand.hash() > *difficulty {
+            head_rand.nonce = head_rand.nonce.wrapping_add(1);
+        }
+        Block {
+            head: head_rand,
+            content: content_test,
+        }
+    }
+}
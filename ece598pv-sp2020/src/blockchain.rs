@@ -6,15 +6,47 @@ use chrono::prelude::*;
 use super::block::{Content, Header};
 use super::transaction::{Transaction, SignedTransaction};
 use crate::crypto::merkle::MerkleTree;
+use crate::events::{Event, EventBus};
+use crate::state;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
 
 
+/// Maximum time a header's timestamp may be ahead of the local clock, in milliseconds
+const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// Reasons a header is rejected from the header tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderError {
+    UnknownParent,
+    WrongDifficulty,
+    InsufficientWork,
+    TimestampTooFarInFuture,
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeaderError::UnknownParent => write!(f, "parent header unknown"),
+            HeaderError::WrongDifficulty => write!(f, "difficulty does not match the chain"),
+            HeaderError::InsufficientWork => write!(f, "hash does not meet the difficulty"),
+            HeaderError::TimestampTooFarInFuture => write!(f, "timestamp too far in the future"),
+        }
+    }
+}
+
 pub struct Blockchain {
     pub chain: HashMap<H256, (Block,usize)>,
     pub tail: H256,
     pub diff: H256,
+    /// Every known header with its height, including the headers of blocks not downloaded yet
+    pub headers: HashMap<H256, (Header, usize)>,
+    /// The last header of the longest header chain
+    pub best_header: H256,
+    /// Blocks containing each transaction, on any branch
+    pub tx_index: HashMap<H256, Vec<H256>>,
+    events: EventBus,
 }
 
 impl Blockchain {
@@ -31,6 +63,7 @@ impl Blockchain {
             address: address.clone(),
             value: 0,
             nonce: 0,
+            fee: 0,
         };
         let signed = SignedTransaction {
             public_key: public_key.clone(),
@@ -47,19 +80,159 @@ impl Blockchain {
             nonce: zero_nonce,
             difficulty: diff_h256,
             timestamp: 0,
-            merkle_root: MerkleTree::new(&(content_genesis.content)),
+            merkle_root: MerkleTree::new(&(content_genesis.content)).root(),
+            // every account starts in its default state
+            state_root: state::state_root(&HashMap::new()),
         };
         let mut chain_map = HashMap::new();
+        let mut header_map = HashMap::new();
         let genesis_block = Block {
             head: head_rand,
             content: content_genesis.clone(),
         };
         chain_map.insert(genesis_block.hash(), (genesis_block.clone(),0));
+        header_map.insert(genesis_block.hash(), (genesis_block.head.clone(), 0));
+        let tx_index = genesis_block
+            .content
+            .content
+            .iter()
+            .map(|tx| (tx.hash(), vec![genesis_block.hash()]))
+            .collect();
         Blockchain{
             chain: chain_map,
             tail: genesis_block.hash(),
             diff: diff_h256,
+            headers: header_map,
+            best_header: genesis_block.hash(),
+            tx_index,
+            events: EventBus::new(),
+        }
+    }
+
+    /// Create a new blockchain publishing its connected and disconnected blocks on `events`
+    pub fn with_events(events: &EventBus) -> Self {
+        let mut chain = Self::new();
+        chain.events = events.clone();
+        chain
+    }
+
+    /// Check a header against the header tree: its parent must be known, and it must carry the
+    /// chain difficulty and a hash meeting it.
+    pub fn validate_header(&self, header: &Header) -> Result<(), HeaderError> {
+        if !self.headers.contains_key(&header.parent_hash) {
+            return Err(HeaderError::UnknownParent);
+        }
+        if header.difficulty != self.diff {
+            return Err(HeaderError::WrongDifficulty);
+        }
+        if header.hash() > header.difficulty {
+            return Err(HeaderError::InsufficientWork);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("").as_millis();
+        if header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(HeaderError::TimestampTooFarInFuture);
+        }
+        Ok(())
+    }
+
+    /// Check what a block commits to, short of the state of its parent: the chain difficulty, a
+    /// hash meeting it, and the Merkle root of its transactions.
+    pub fn check_block(&self, block: &Block) -> bool {
+        block.head.difficulty == self.diff
+            && block.hash() <= block.head.difficulty
+            && MerkleTree::new(&block.content.content).root() == block.head.merkle_root
+    }
+
+    /// Validate a header and add it to the header tree. Returns whether the header was new.
+    pub fn insert_header(&mut self, header: &Header) -> Result<bool, HeaderError> {
+        let hash = header.hash();
+        if self.headers.contains_key(&hash) {
+            return Ok(false);
+        }
+        self.validate_header(header)?;
+        self.add_header(header);
+        Ok(true)
+    }
+
+    fn add_header(&mut self, header: &Header) {
+        let par_height = match self.headers.get(&header.parent_hash) {
+            Some((_, height)) => *height,
+            None => return,
+        };
+        let best_height = self.headers.get(&self.best_header).unwrap().1;
+        if par_height >= best_height {
+            self.best_header = header.hash();
+        }
+        self.headers.insert(header.hash(), (header.clone(), par_height + 1));
+    }
+
+    /// Drop a header whose block turned out to be invalid, together with all its descendants.
+    pub fn discard_header(&mut self, hash: &H256) {
+        if self.chain.contains_key(hash) || !self.headers.contains_key(hash) {
+            return;
+        }
+        self.headers.remove(hash);
+        loop {
+            let detached: Vec<H256> = self
+                .headers
+                .iter()
+                .filter(|(_, (header, height))| *height > 0 && !self.headers.contains_key(&header.parent_hash))
+                .map(|(hash, _)| *hash)
+                .collect();
+            if detached.is_empty() {
+                break;
+            }
+            for hash in detached {
+                self.headers.remove(&hash);
+            }
+        }
+        let best = self.headers.iter().max_by_key(|(_, (_, height))| *height).map(|(hash, _)| *hash);
+        self.best_header = best.unwrap();
+    }
+
+    /// Height of the longest header chain
+    pub fn best_header_height(&self) -> usize {
+        self.headers.get(&self.best_header).unwrap().1
+    }
+
+    /// Build a block locator from the best header: the hashes of the last ten headers, then
+    /// exponentially sparser ancestors, always ending with the genesis block.
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator = Vec::new();
+        let mut hash = self.best_header;
+        let mut step = 1;
+        loop {
+            locator.push(hash);
+            let height = self.headers.get(&hash).unwrap().1;
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            let target = height.saturating_sub(step);
+            while self.headers.get(&hash).unwrap().1 > target {
+                hash = self.headers.get(&hash).unwrap().0.parent_hash;
+            }
         }
+        locator
+    }
+
+    /// Headers of the longest chain following the first locator hash that is on it, at most `max`
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let longest = self.all_blocks_in_longest_chain();
+        let start = locator
+            .iter()
+            .filter_map(|hash| self.chain.get(hash).map(|(_, height)| (hash, *height)))
+            .find(|(hash, height)| longest.get(*height) == Some(hash))
+            .map(|(_, height)| height)
+            .unwrap_or(0);
+        longest
+            .iter()
+            .skip(start + 1)
+            .take(max)
+            .map(|hash| self.chain.get(hash).unwrap().0.head.clone())
+            .collect()
     }
 
     /// Insert a block into blockchain
@@ -67,13 +240,45 @@ impl Blockchain {
         let tip_height = self.chain.get(&self.tip()).unwrap().1;
         if self.chain.contains_key(&block.head.parent_hash) {
             let par_height = self.chain.get(&block.head.parent_hash).unwrap().1;
+            self.chain.insert((*block).hash(), ((*block).clone(), par_height + 1));
+            for tx in block.content.content.iter() {
+                self.tx_index.entry(tx.hash()).or_default().push(block.hash());
+            }
             if par_height >= tip_height {
+                let old_tip = self.tail;
                 self.tail = (*block).hash();
+                self.publish_reorg(old_tip);
+            }
+            if !self.headers.contains_key(&(*block).hash()) {
+                self.add_header(&block.head);
             }
-            self.chain.insert((*block).hash(), ((*block).clone(), par_height + 1));
         }
     }
 
+    /// Publish the blocks that left and joined the longest chain when the tip moved away from
+    /// `old_tip`, followed by the new tip.
+    fn publish_reorg(&self, old_tip: H256) {
+        let mut disconnected = old_tip;
+        let mut connected = self.tail;
+        let mut new_branch = Vec::new();
+        while disconnected != connected {
+            let (old_block, old_height) = self.chain.get(&disconnected).unwrap();
+            let (new_block, new_height) = self.chain.get(&connected).unwrap();
+            if old_height >= new_height {
+                self.events.publish(Event::BlockDisconnected { hash: disconnected, height: *old_height });
+                disconnected = old_block.head.parent_hash;
+            } else {
+                new_branch.push((connected, *new_height));
+                connected = new_block.head.parent_hash;
+            }
+        }
+        for (hash, height) in new_branch.into_iter().rev() {
+            let txs = self.chain.get(&hash).unwrap().0.content.content.len();
+            self.events.publish(Event::BlockConnected { hash, height, txs });
+        }
+        self.events.publish(Event::TipChanged { tip: self.tail, height: self.height() });
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
         self.tail
@@ -81,6 +286,48 @@ impl Blockchain {
 
     pub fn height(&self) -> usize{self.chain.get(&self.tip()).unwrap().1}
 
+    /// Hash of the block at `height` in the longest chain
+    pub fn block_at_height(&self, height: usize) -> Option<H256> {
+        let tip_height = self.height();
+        if height > tip_height {
+            return None;
+        }
+        let mut hash = self.tail;
+        for _ in height..tip_height {
+            hash = self.chain.get(&hash).unwrap().0.head.parent_hash;
+        }
+        Some(hash)
+    }
+
+    /// Whether a block is part of the longest chain
+    pub fn in_longest_chain(&self, hash: &H256) -> bool {
+        match self.chain.get(hash) {
+            Some((_, height)) => self.block_at_height(*height) == Some(*hash),
+            None => false,
+        }
+    }
+
+    /// The block of the longest chain containing a transaction
+    pub fn confirming_block(&self, tx_hash: &H256) -> Option<H256> {
+        self.tx_index
+            .get(tx_hash)?
+            .iter()
+            .find(|hash| self.in_longest_chain(hash))
+            .cloned()
+    }
+
+    /// Whether an address sent or received a transaction in the longest chain
+    pub fn address_used(&self, address: &H160) -> bool {
+        self.all_blocks_in_longest_chain().iter().any(|hash| {
+            let block = &self.chain.get(hash).unwrap().0;
+            block
+                .content
+                .content
+                .iter()
+                .any(|tx| tx.transaction.address == *address || tx.sender() == *address)
+        })
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut all_hash: Vec<H256> = Vec::new();
@@ -94,19 +341,76 @@ impl Blockchain {
     }
 }
 
-// #[cfg(any(test, test_utilities))]
-// mod tests {
-//     use super::*;
-//     use crate::block::test::generate_random_block;
-//     use crate::crypto::hash::Hashable;
-
-//     #[test]
-//     fn insert_one() {
-//         let mut blockchain = Blockchain::new();
-//         let genesis_hash = blockchain.tip();
-//         let block = generate_random_block(&genesis_hash);
-//         blockchain.insert(&block);
-//         assert_eq!(blockchain.tip(), block.hash());
-
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_mined_block;
+    use crate::crypto::hash::Hashable;
+
+    #[test]
+    fn insert_one() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_mined_block(&genesis_hash, &blockchain.diff);
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip(), block.hash());
+    }
+
+    /// Extend the chain from `parent` with `length` blocks, returning their hashes
+    fn extend(chain: &mut Blockchain, parent: H256, length: usize) -> Vec<H256> {
+        let mut hashes = Vec::new();
+        let mut parent = parent;
+        for _ in 0..length {
+            let block = generate_mined_block(&parent, &chain.diff);
+            chain.insert(&block);
+            parent = block.hash();
+            hashes.push(parent);
+        }
+        hashes
+    }
+
+    #[test]
+    fn headers_after_a_fork_start_at_the_common_ancestor() {
+        let mut chain = Blockchain::new();
+        let genesis = chain.tip();
+        let main = extend(&mut chain, genesis, 15);
+        let mut other = Blockchain::new();
+        for hash in &main[..3] {
+            other.insert(&chain.chain.get(hash).unwrap().0);
+        }
+        let fork = extend(&mut other, main[2], 2);
+
+        let locator = other.locator();
+        assert_eq!(locator, vec![fork[1], fork[0], main[2], main[1], main[0], genesis]);
+        let headers = chain.headers_after(&locator, 10);
+        let hashes: Vec<H256> = headers.iter().map(|header| header.hash()).collect();
+        assert_eq!(hashes, main[3..13].to_vec());
+
+        // past ten headers, the locator steps back exponentially
+        let locator = chain.locator();
+        assert_eq!(locator.len(), 12);
+        assert_eq!(locator[..10].to_vec(), main[5..].iter().rev().cloned().collect::<Vec<_>>());
+        assert_eq!(locator[10..].to_vec(), vec![main[3], genesis]);
+    }
+
+    #[test]
+    fn headers_need_a_known_parent() {
+        let mut chain = Blockchain::new();
+        let genesis = chain.tip();
+        let first = generate_mined_block(&genesis, &chain.diff);
+        let second = generate_mined_block(&first.hash(), &chain.diff);
+        assert_eq!(chain.insert_header(&second.head), Err(HeaderError::UnknownParent));
+        assert!(!chain.headers.contains_key(&second.hash()));
+
+        assert_eq!(chain.insert_header(&first.head), Ok(true));
+        assert_eq!(chain.insert_header(&second.head), Ok(true));
+        assert_eq!(chain.insert_header(&second.head), Ok(false));
+        assert_eq!(chain.best_header, second.hash());
+        assert_eq!(chain.best_header_height(), 2);
+
+        // discarding a header drops its descendants too
+        chain.discard_header(&first.hash());
+        assert!(!chain.headers.contains_key(&second.hash()));
+        assert_eq!(chain.best_header, genesis);
+    }
+}
@@ -23,6 +23,16 @@ impl std::fmt::Debug for H160 {
     }
 }
 
+impl std::str::FromStr for H160 {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<H160, ParseHashError> {
+        let mut buffer: [u8; 20] = [0; 20];
+        decode_hex(s, &mut buffer)?;
+        Ok(H160(buffer))
+    }
+}
+
 impl Ord for H160 {
     fn cmp(&self, other: &H160) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -47,6 +57,39 @@ impl H160 {
     pub fn to_string(&self) -> String {
         u32::from_be_bytes(self.0[0..4].try_into().unwrap()).to_string()
     }
+
+    /// The full address in hex, as parsed by `from_str`
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:>02x}", byte)).collect()
+    }
+}
+
+/// Error parsing a hash or an address from hex
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseHashError {
+    InvalidLength(usize),
+    InvalidCharacter,
+}
+
+impl std::fmt::Display for ParseHashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseHashError::InvalidLength(len) => write!(f, "invalid length {}", len),
+            ParseHashError::InvalidCharacter => write!(f, "invalid hex character"),
+        }
+    }
+}
+
+/// Decode a hex string of exactly `2 * out.len()` digits into `out`
+fn decode_hex(s: &str, out: &mut [u8]) -> Result<(), ParseHashError> {
+    if s.len() != out.len() * 2 {
+        return Err(ParseHashError::InvalidLength(s.len()));
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        let digits = s.get(i * 2..i * 2 + 2).ok_or(ParseHashError::InvalidCharacter)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| ParseHashError::InvalidCharacter)?;
+    }
+    Ok(())
 }
 
 /// An object that can be meaningfully hashed.
@@ -83,6 +126,16 @@ impl std::fmt::Display for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<H256, ParseHashError> {
+        let mut buffer: [u8; 32] = [0; 32];
+        decode_hex(s, &mut buffer)?;
+        Ok(H256(buffer))
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -93,6 +146,12 @@ impl std::fmt::Debug for H256 {
     }
 }
 
+impl std::convert::AsRef<[u8]> for H160 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H256 {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -168,4 +227,18 @@ pub mod tests {
         (&raw_bytes).into()
     }
 
+    #[test]
+    fn parse_displayed_hash() {
+        let hash = generate_random_hash();
+        assert_eq!(hash.to_string().parse::<H256>(), Ok(hash));
+        let address: super::H160 = hash.into();
+        assert_eq!(address.to_hex().parse::<super::H160>(), Ok(address));
+    }
+
+    #[test]
+    fn parse_invalid_hash() {
+        assert_eq!("00".parse::<H256>(), Err(super::ParseHashError::InvalidLength(2)));
+        let invalid = "zz".repeat(32);
+        assert_eq!(invalid.parse::<H256>(), Err(super::ParseHashError::InvalidCharacter));
+    }
 }
@@ -1,5 +1,6 @@
-use ring::rand;
-use ring::signature::Ed25519KeyPair;
+use crate::crypto::hash::{H160, H256};
+use ring::{hmac, rand};
+use ring::signature::{Ed25519KeyPair, KeyPair};
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
@@ -7,3 +8,96 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// The address of a key pair: the last 20 bytes of the SHA256 of its public key.
+pub fn address(key: &Ed25519KeyPair) -> H160 {
+    let public_hash: H256 = ring::digest::digest(&ring::digest::SHA256, key.public_key().as_ref()).into();
+    public_hash.into()
+}
+
+/// Index offset of hardened children. SLIP-0010 only defines hardened derivation for Ed25519.
+pub const HARDENED: u32 = 0x8000_0000;
+
+/// A SLIP-0010 extended private key: an Ed25519 seed and the chain code deriving its children
+pub struct ExtendedKey {
+    pub key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    fn from_hmac(key: &[u8], data: &[u8]) -> Self {
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, key), data);
+        let (key, chain_code) = tag.as_ref().split_at(32);
+        let mut extended = ExtendedKey {
+            key: [0; 32],
+            chain_code: [0; 32],
+        };
+        extended.key.copy_from_slice(key);
+        extended.chain_code.copy_from_slice(chain_code);
+        extended
+    }
+
+    /// The master key of a seed
+    pub fn master(seed: &[u8]) -> Self {
+        Self::from_hmac(b"ed25519 seed", seed)
+    }
+
+    /// The hardened child at `index`, which is hardened whether or not it has the `HARDENED` bit.
+    pub fn child(&self, index: u32) -> Self {
+        let mut data = Vec::with_capacity(37);
+        data.push(0);
+        data.extend_from_slice(&self.key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        Self::from_hmac(&self.chain_code, &data)
+    }
+
+    pub fn key_pair(&self) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&self.key).unwrap()
+    }
+}
+
+/// Parse a derivation path such as `m/44'/0'/0'`. Every level is hardened, with or without `'`.
+pub fn parse_path(path: &str) -> Result<Vec<u32>, String> {
+    let mut levels = path.split('/');
+    if levels.next() != Some("m") {
+        return Err("derivation path must start with m".to_string());
+    }
+    levels
+        .map(|level| {
+            let index = level.trim_end_matches(['\'', 'h', 'H']);
+            match index.parse::<u32>() {
+                Ok(index) if index < HARDENED => Ok(index | HARDENED),
+                _ => Err(format!("invalid derivation path level {}", level)),
+            }
+        })
+        .collect()
+}
+
+/// Derive the key at `path` from a seed.
+pub fn derive(seed: &[u8], path: &[u32]) -> ExtendedKey {
+    path.iter().fold(ExtendedKey::master(seed), |key, index| key.child(*index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SLIP-0010 test vector 1 for ed25519
+    #[test]
+    fn slip10_test_vector() {
+        let seed = hex!("000102030405060708090a0b0c0d0e0f");
+        let master = derive(&seed, &parse_path("m").unwrap());
+        assert_eq!(master.chain_code, hex!("90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb"));
+        assert_eq!(master.key, hex!("2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"));
+        let child = derive(&seed, &parse_path("m/0'").unwrap());
+        assert_eq!(child.chain_code, hex!("8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69"));
+        assert_eq!(child.key, hex!("68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"));
+    }
+
+    #[test]
+    fn parse_paths() {
+        assert_eq!(parse_path("m/44'/1/0h").unwrap(), vec![44 | HARDENED, 1 | HARDENED, HARDENED]);
+        assert!(parse_path("44'/0'").is_err());
+        assert!(parse_path("m/x").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Serialize, Deserialize};
-use super::hash::{Hashable, H256};
+use super::hash::{Hashable, H160, H256};
+use std::collections::BTreeMap;
 use std::mem;
 
 
@@ -29,6 +30,10 @@ pub struct MerkleTree {
 
 impl MerkleTree {
     pub fn new<T>(data: &[T]) -> Self where T: Hashable, {
+        // the root of no data is the zero hash
+        if data.is_empty() {
+            return MerkleTree::default();
+        }
         let length = data.len();
         let mut cur_layer = Vec::with_capacity(length);
         for element in data {
@@ -75,6 +80,10 @@ impl MerkleTree {
 
     /// Returns the Merkle Proof of data at index i
     pub fn proof(&self, index: usize) -> Vec<H256> {
+        // the root of a single datum is its hash
+        if self.height == 0 {
+            return Vec::new();
+        }
         let mut cur_height = 1;
         let indexu32 = index as u32;
         let mut cmp_boundry = 2u32.pow(self.height-1);
@@ -98,7 +107,6 @@ impl MerkleTree {
             }
             cur_height+=1;
         }
-        println!("{}", path.len());
         path.into()
     }
 }
@@ -106,6 +114,9 @@ impl MerkleTree {
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
+    if index >= leaf_size || proof.len() != height(leaf_size) {
+        return false;
+    }
     let mut merged_hash = *datum;
     let mut side = index as u32;
     for partner in proof.iter().rev() {
@@ -125,6 +136,122 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
     *root == merged_hash
 }
 
+/// Number of levels above the leaves of the Merkle tree of `leaf_size` data, an odd level
+/// being padded with a copy of its last node
+fn height(leaf_size: usize) -> usize {
+    let mut height = 0;
+    let mut width = leaf_size;
+    while width > 1 {
+        width = width.div_ceil(2);
+        height += 1;
+    }
+    height
+}
+
+/// Verify that `leaf` is the leaf of `key` in the sparse Merkle tree of `root`, given the
+/// siblings of its path from the root down. The zero hash as `leaf` proves that the key is not set.
+pub fn verify_sparse(root: &H256, key: &H160, leaf: &H256, proof: &[H256]) -> bool {
+    if proof.len() != SPARSE_DEPTH {
+        return false;
+    }
+    let mut merged_hash = *leaf;
+    for (depth, sibling) in proof.iter().enumerate().rev() {
+        merged_hash = if key_bit(key, depth) {
+            hash_pair(sibling, &merged_hash)
+        } else {
+            hash_pair(&merged_hash, sibling)
+        };
+    }
+    *root == merged_hash
+}
+
+/// Number of levels of a `SparseMerkleTree`, one per bit of its keys
+pub const SPARSE_DEPTH: usize = 160;
+
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut concatenated: Vec<u8> = Vec::with_capacity(64);
+    concatenated.extend_from_slice(left.as_ref());
+    concatenated.extend_from_slice(right.as_ref());
+    ring::digest::digest(&ring::digest::SHA256, &concatenated).into()
+}
+
+/// Bit `index` of a key, the most significant bit first
+fn key_bit(key: &H160, index: usize) -> bool {
+    key.as_ref()[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+/// Roots of the empty subtrees, by height
+fn empty_roots() -> Vec<H256> {
+    let mut roots = vec![H256::default()];
+    for height in 0..SPARSE_DEPTH {
+        roots.push(hash_pair(&roots[height], &roots[height]));
+    }
+    roots
+}
+
+/// A Merkle tree with a leaf for every 160-bit key, all of them the zero hash but the ones set.
+/// Only the paths to the set leaves are hashed, the empty subtrees having a known root.
+#[derive(Debug, Default, Clone)]
+pub struct SparseMerkleTree {
+    leaves: BTreeMap<H160, H256>,
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the leaf of a key, the zero hash removing it
+    pub fn insert(&mut self, key: H160, leaf: H256) {
+        if leaf == H256::default() {
+            self.leaves.remove(&key);
+        } else {
+            self.leaves.insert(key, leaf);
+        }
+    }
+
+    pub fn root(&self) -> H256 {
+        let leaves: Vec<(&H160, &H256)> = self.leaves.iter().collect();
+        subtree_root(&leaves, 0, &empty_roots())
+    }
+
+    /// Returns the siblings of the path to the leaf of `key`, from the root down, whether the
+    /// leaf is set or not
+    pub fn proof(&self, key: &H160) -> Vec<H256> {
+        let empty = empty_roots();
+        let leaves: Vec<(&H160, &H256)> = self.leaves.iter().collect();
+        let mut leaves = &leaves[..];
+        let mut path = Vec::with_capacity(SPARSE_DEPTH);
+        for depth in 0..SPARSE_DEPTH {
+            let split = leaves.iter().take_while(|(key, _)| !key_bit(key, depth)).count();
+            let (left, right) = leaves.split_at(split);
+            if key_bit(key, depth) {
+                path.push(subtree_root(left, depth + 1, &empty));
+                leaves = right;
+            } else {
+                path.push(subtree_root(right, depth + 1, &empty));
+                leaves = left;
+            }
+        }
+        path
+    }
+}
+
+/// Root of the subtree at `depth` holding `leaves`, sorted by key
+fn subtree_root(leaves: &[(&H160, &H256)], depth: usize, empty: &[H256]) -> H256 {
+    if leaves.is_empty() {
+        return empty[SPARSE_DEPTH - depth];
+    }
+    if depth == SPARSE_DEPTH {
+        return *leaves[0].1;
+    }
+    let split = leaves.iter().take_while(|(key, _)| !key_bit(key, depth)).count();
+    hash_pair(
+        &subtree_root(&leaves[..split], depth + 1, empty),
+        &subtree_root(&leaves[split..], depth + 1, empty),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use crate::crypto::hash::H256;
@@ -169,11 +296,40 @@ mod tests {
         // "0101010101010101010101010101010101010101010101010101010101010202"
     }
 
+    #[test]
+    fn sparse_root_and_proofs() {
+        let mut tree = SparseMerkleTree::new();
+        assert_eq!(tree.root(), empty_roots()[SPARSE_DEPTH]);
+        let low: H160 = H256::from(hex!("0000000000000000000000007fffffffffffffffffffffffffffffffffffffff")).into();
+        let high: H160 = H256::from(hex!("000000000000000000000000ffffffffffffffffffffffffffffffffffffffff")).into();
+        let leaf: H256 = (hex!("0101010101010101010101010101010101010101010101010101010101010202")).into();
+        tree.insert(low, leaf);
+        tree.insert(high, leaf);
+        // the keys part at the first bit, then each is the right child of empty siblings
+        let mut left = leaf;
+        let mut right = leaf;
+        let empty = empty_roots();
+        for empty_root in empty.iter().take(SPARSE_DEPTH - 1) {
+            left = hash_pair(empty_root, &left);
+            right = hash_pair(empty_root, &right);
+        }
+        assert_eq!(tree.root(), hash_pair(&left, &right));
+        assert!(verify_sparse(&tree.root(), &low, &leaf, &tree.proof(&low)));
+        assert!(!verify_sparse(&tree.root(), &low, &H256::default(), &tree.proof(&low)));
+        let absent = H160::default();
+        assert!(verify_sparse(&tree.root(), &absent, &H256::default(), &tree.proof(&absent)));
+        tree.insert(high, H256::default());
+        tree.insert(low, H256::default());
+        assert_eq!(tree.root(), empty[SPARSE_DEPTH]);
+    }
+
     #[test]
     fn verifying() {
         let input_data: Vec<H256> = gen_merkle_tree_data!();
         let merkle_tree = MerkleTree::new(&input_data);
         let proof = merkle_tree.proof(0);
         assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
+        assert!(!verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 2, input_data.len()));
+        assert!(!verify(&merkle_tree.root(), &input_data[0].hash(), &[], 0, input_data.len()));
     }
 }
@@ -0,0 +1,63 @@
+use crate::crypto::hash::H256;
+use crate::network::peer::Direction;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Something that happened to the chain, the mempool or the peer set
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A block with `txs` transactions became part of the longest chain
+    BlockConnected { hash: H256, height: usize, txs: usize },
+    /// A block left the longest chain in a reorganization
+    BlockDisconnected { hash: H256, height: usize },
+    /// The tip of the longest chain moved
+    TipChanged { tip: H256, height: usize },
+    /// A transaction entered the mempool
+    TxAccepted(H256),
+    /// A transaction left the mempool, because it was included in a block or dropped
+    TxEvicted(H256),
+    PeerConnected(std::net::SocketAddr, Direction),
+    PeerDisconnected(std::net::SocketAddr),
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Event::BlockConnected { hash, height, txs } => {
+                write!(f, "block {} connected at height {} with {} transactions", hash, height, txs)
+            }
+            Event::BlockDisconnected { hash, height } => write!(f, "block {} disconnected from height {}", hash, height),
+            Event::TipChanged { tip, height } => write!(f, "tip changed to {} at height {}", tip, height),
+            Event::TxAccepted(hash) => write!(f, "transaction {} accepted", hash),
+            Event::TxEvicted(hash) => write!(f, "transaction {} evicted", hash),
+            Event::PeerConnected(addr, direction) => write!(f, "peer {} connected ({:?})", addr, direction),
+            Event::PeerDisconnected(addr) => write!(f, "peer {} disconnected", addr),
+        }
+    }
+}
+
+/// Publish/subscribe bus. Every subscriber gets its own channel receiving all events published
+/// after it subscribed; subscribers that dropped their receiver are forgotten.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,17 @@
+#[macro_use]
+extern crate hex_literal;
+
+pub mod api;
+pub mod block;
+pub mod blockchain;
+pub mod crypto;
+pub mod events;
+pub mod metrics;
+pub mod miner;
+pub mod network;
+pub mod state;
+pub mod transaction;
+pub mod txgenerator;
+pub mod bloomfilter;
+pub mod wallet;
+
@@ -1,37 +1,32 @@
-#[macro_use]
-extern crate hex_literal;
-
-pub mod api;
-pub mod block;
-pub mod blockchain;
-pub mod crypto;
-pub mod miner;
-pub mod network;
-pub mod transaction;
-pub mod txgenerator;
-pub mod bloomfilter;
-
 use clap::clap_app;
 use crossbeam::channel;
-use log::{error, info};
-use api::Server as ApiServer;
-use network::{server, worker};
+use log::{error, info, warn};
+use bitcoin::api::Server as ApiServer;
+use bitcoin::network::{server, worker};
+use bitcoin::{metrics, miner, network, txgenerator, wallet};
 use std::net;
 use std::process;
 use std::thread;
 use std::time;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 use std::sync::Arc;
-use crate::blockchain::Blockchain;
+use bitcoin::blockchain::Blockchain;
 use std::collections::HashMap;
-use crate::network::worker::OrphanBuffer;
-use crate::txgenerator::TxMempool;
-use crate::crypto::key_pair;
+use bitcoin::network::worker::{OrphanBuffer, OrphanTxPool};
+use bitcoin::network::sync::{self, Synchronizer};
+use bitcoin::txgenerator::TxMempool;
+use bitcoin::crypto::key_pair;
 use ring::signature::Ed25519KeyPair;
-use ring::signature::KeyPair;
-use crate::crypto::hash::{H256, H160};
-use crate::network::peer::ReadResult::Message;
-use crate::bloomfilter::lib::BloomFilter;
+use bitcoin::crypto::hash::H160;
+use bitcoin::network::peer::ReadResult::Message;
+use bitcoin::bloomfilter::lib::BloomFilter;
+use bitcoin::events::EventBus;
+use bitcoin::state::{StateCache, MAX_CACHED_STATES};
+use bitcoin::wallet::{Keystore, Wallet};
+use std::path::Path;
+
+/// Environment variable holding the passphrase of the wallet keystore
+const PASSPHRASE_VAR: &str = "BITCOIN_WALLET_PASSPHRASE";
 
 fn main() {
     // parse command line arguments
@@ -43,6 +38,20 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg ping_interval: --("ping-interval") [SECS] default_value("30") "Sets the interval between pings sent to each peer")
+     (@arg max_missed_pongs: --("max-missed-pongs") [INT] default_value("3") "Sets the number of unanswered pings after which a peer is disconnected")
+     (@arg wallet: --wallet [PATH] "Sets the wallet keystore file, encrypted with the passphrase in BITCOIN_WALLET_PASSPHRASE")
+     (@arg key: --key [ADDRESS] "Sets the address of the wallet key used by the node, instead of the first one")
+     (@arg light: --light "Runs a light node, downloading headers and only the proven transactions of the wallet's addresses")
+     (@subcommand wallet =>
+      (about: "Manages the keys of the wallet keystore and exits")
+      (@subcommand create => (about: "Generates a new key"))
+      (@subcommand list => (about: "Lists the addresses of the keys, with the derivation paths of the derived ones"))
+      (@subcommand seed => (about: "Sets the seed of derived keys, printing a new seed phrase unless one is given to restore") (@arg phrase: "Seed phrase to restore"))
+      (@subcommand derive => (about: "Derives a key from the seed, at the next address of the default account unless a path is given") (@arg path: "Derivation path, such as m/44'/1'/0'/5'"))
+      (@subcommand import => (about: "Imports a key") (@arg pkcs8: +required "Hex of the PKCS#8 document of the key"))
+      (@subcommand export => (about: "Exports a key as the hex of its PKCS#8 document") (@arg address: +required "Address of the key"))
+     )
     )
     .get_matches();
 
@@ -50,13 +59,40 @@ fn main() {
     let self_balance = 100 as u32;
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let new_chain = Arc::new(Mutex::new(Blockchain::new()));
+
+    if let Some(command) = matches.subcommand_matches("wallet") {
+        let path = matches.value_of("wallet").unwrap_or_else(|| {
+            error!("The wallet command needs --wallet");
+            process::exit(1);
+        });
+        wallet_command(Path::new(path), command);
+        return;
+    }
+    // chain, mempool and peer events, for the subsystems that react to each other
+    let events = EventBus::new();
+    let event_log = events.subscribe();
+    thread::Builder::new()
+        .name("events".to_string())
+        .spawn(move || {
+            for event in event_log.iter() {
+                info!("{}", event);
+            }
+        })
+        .unwrap();
+    metrics::global().observe_events(events.subscribe());
+    let new_chain = Arc::new(RwLock::new(Blockchain::with_events(&events)));
     let new_buf = Arc::new(Mutex::new(OrphanBuffer::new()));
-    let new_txpool = Arc::new(Mutex::new(TxMempool::new()));
+    let new_txpool = Arc::new(RwLock::new(TxMempool::with_events(&events)));
+    let new_orphan_txs = Arc::new(Mutex::new(OrphanTxPool::new()));
     let mut bloom_filter  = BloomFilter::new(1000, 0.03);
-    let mut init_state = Arc::new(Mutex::new(HashMap::new()));
-    let mut state = Arc::new(Mutex::new(HashMap::new()));
+    let mut init_state = Arc::new(RwLock::new(HashMap::new()));
+    let mut state = Arc::new(RwLock::new(HashMap::new()));
     let mut witness_map = Arc::new(Mutex::new(HashMap::new()));
+    // every account starts in its default state, the state after the genesis block
+    let mut block_states = StateCache::new(MAX_CACHED_STATES);
+    block_states.insert(new_chain.read().unwrap().tip(), HashMap::new());
+    let block_states = Arc::new(Mutex::new(block_states));
+    let (shutdown_sender, shutdown_receiver) = channel::unbounded();
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -77,12 +113,37 @@ fn main() {
             process::exit(1);
         });
 
+    // parse peer liveness settings
+    let ping_interval = matches
+        .value_of("ping_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ping interval: {}", e);
+            process::exit(1);
+        });
+    let max_missed_pongs = matches
+        .value_of("max_missed_pongs")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max missed pongs: {}", e);
+            process::exit(1);
+        });
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    let (server_ctx, server) = server::new(
+        p2p_addr,
+        msg_tx,
+        time::Duration::from_secs(ping_interval),
+        max_missed_pongs,
+        &events,
+    )
+    .unwrap();
+    let server_thread = server_ctx.start().unwrap();
 
     // start the worker
     let p2p_workers = matches
@@ -94,22 +155,66 @@ fn main() {
             process::exit(1);
         });
 
-    let key = key_pair::random();
-    let trusted_public = key.public_key().as_ref().to_vec();
-    let public_hash: H256 = ring::digest::digest(&ring::digest::SHA256, &trusted_public).into();
-    let address: H160 = public_hash.into();
-    init_state.lock().unwrap().insert(address.clone(),(0,100));
+    let mut keystore = None;
+    let (key, api_key) = match matches.value_of("wallet") {
+        Some(path) => {
+            let wallet = open_wallet(Path::new(path));
+            let address = match matches.value_of("key") {
+                Some(address) => address.parse::<H160>().unwrap_or_else(|e| {
+                    error!("Error parsing key address: {}", e);
+                    process::exit(1);
+                }),
+                None => match wallet.addresses().first() {
+                    Some(address) => *address,
+                    None => {
+                        error!("Wallet {} has no keys, create one with `wallet create`", path);
+                        process::exit(1);
+                    }
+                },
+            };
+            let load = || {
+                wallet.key_pair(&address).unwrap_or_else(|e| {
+                    error!("Error loading key: {}", e);
+                    process::exit(1);
+                })
+            };
+            let keys = (load(), load());
+            keystore = Some(Arc::new(Mutex::new(wallet)));
+            keys
+        }
+        None => {
+            warn!("No wallet given, using a random key that is lost on exit");
+            let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+            let load = || Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            (load(), load())
+        }
+    };
+    let address = key_pair::address(&key);
+    info!("Using address {}", address.to_hex());
+    init_state.write().unwrap().insert(address.clone(),(0,100));
     bloom_filter.insert((H160::to_string(&address.clone())+"0"+"100").as_str());
+    // follow the balances of the wallet's addresses, or of the node's address without a wallet
+    let owned = match &keystore {
+        Some(keystore) => keystore.lock().unwrap().addresses(),
+        None => vec![address],
+    };
+    let wallet = Arc::new(Mutex::new(Wallet::new(&owned)));
+    let new_sync = if matches.is_present("light") {
+        info!("Running as a light node, following the transactions of {} addresses", owned.len());
+        Arc::new(Mutex::new(Synchronizer::light(&owned)))
+    } else {
+        Arc::new(Mutex::new(Synchronizer::new()))
+    };
+    Wallet::track(&wallet, &new_chain, events.subscribe());
     // start transcation generator
     let (txpool_ctx, generator) = txgenerator::new(
         &server,
         &new_txpool,
         key,
         &init_state,
-        &state,
-        &new_chain,
+        &wallet,
     );
-    txpool_ctx.start();
+    let generator_thread = txpool_ctx.start();
 
     // start worker
     let worker_ctx = worker::new(
@@ -123,10 +228,16 @@ fn main() {
         &init_state,
         address.clone(),
         &state,
-        &witness_map
-
+        &witness_map,
+        &block_states,
+        &new_sync,
+        &new_orphan_txs,
     );
-    worker_ctx.start();
+    let worker_threads = worker_ctx.start();
+
+    // start expiring stalled block downloads
+    let (sync_ctx, sync_handle) = sync::new(&new_sync, &new_chain, &events);
+    let sync_thread = sync_ctx.start();
 
 
 
@@ -136,16 +247,21 @@ fn main() {
         &new_chain,
         &new_txpool,
         &state,
+        &block_states,
         address,
+        &new_sync,
+        &events,
     );
-    miner_ctx.start();
+    let miner_thread = miner_ctx.start();
 
 
-    // connect to known peers
+    // connect to known peers, until the node shuts down
+    let (connect_exit, connect_exit_chan) = channel::unbounded::<()>();
+    let mut connect_thread = None;
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
-        thread::spawn(move || {
+        let thread = thread::Builder::new().name("connect".to_string()).spawn(move || {
             for peer in known_peers {
                 loop {
                     let addr = match peer.parse::<net::SocketAddr>() {
@@ -166,25 +282,145 @@ fn main() {
                                 "Error connecting to peer {}, retrying in one second: {}",
                                 addr, e
                             );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
+                            let retry = connect_exit_chan.recv_timeout(time::Duration::from_millis(1000));
+                            if let Err(channel::RecvTimeoutError::Timeout) = retry {
+                                continue;
+                            }
+                            return;
                         }
                     }
                 }
             }
         });
+        connect_thread = Some(thread.unwrap());
     }
 
 
     // start the API server
-    ApiServer::start(
+    let (api, api_threads) = ApiServer::start(
         api_addr,
         &generator,
         &miner,
         &server,
+        address,
+        &new_chain,
+        &new_txpool,
+        &state,
+        &new_orphan_txs,
+        &new_sync,
+        Arc::new(api_key),
+        keystore,
+        &wallet,
+        &events,
+        &shutdown_sender,
     );
 
-    loop {
-        std::thread::park();
+    // wait for SIGINT, SIGTERM or a shutdown request on the API
+    let signal_sender = shutdown_sender.clone();
+    ctrlc::set_handler(move || {
+        let _ = signal_sender.send(());
+    })
+    .unwrap_or_else(|e| {
+        error!("Error setting the signal handler: {}", e);
+        process::exit(1);
+    });
+    shutdown_receiver.recv().unwrap();
+    info!("Shutting down");
+
+    // stop connecting to peers and answering API requests, so that nothing reaches the P2P server
+    // once it stops
+    let mut status = 0;
+    std::mem::drop(connect_exit);
+    if let Some(thread) = connect_thread {
+        if thread.join().is_err() {
+            error!("Thread connect panicked");
+            status = 1;
+        }
+    }
+    api.exit();
+    // stop producing blocks and transactions, then drain the network; the workers exit once the
+    // P2P server has stopped and they have handled the messages it received
+    miner.exit();
+    generator.exit();
+    sync_handle.exit();
+    server.shutdown();
+    let threads = vec![miner_thread, generator_thread, sync_thread, server_thread]
+        .into_iter()
+        .chain(api_threads)
+        .chain(worker_threads);
+    for thread in threads {
+        let name = thread.thread().name().unwrap_or("unnamed").to_string();
+        if thread.join().is_err() {
+            error!("Thread {} panicked", name);
+            status = 1;
+        }
+    }
+    info!("Shutdown complete");
+    process::exit(status);
+}
+
+fn passphrase() -> String {
+    std::env::var(PASSPHRASE_VAR).unwrap_or_else(|_| {
+        error!("Set the wallet passphrase in {}", PASSPHRASE_VAR);
+        process::exit(1);
+    })
+}
+
+fn open_wallet(path: &Path) -> Keystore {
+    Keystore::open_or_create(path, &passphrase()).unwrap_or_else(|e| {
+        error!("Error opening wallet {}: {}", path.display(), e);
+        process::exit(1);
+    })
+}
+
+/// Run a `wallet` subcommand on the keystore at `path`, printing its result.
+fn wallet_command(path: &Path, command: &clap::ArgMatches) {
+    let mut keystore = open_wallet(path);
+    let result = match command.subcommand() {
+        ("create", _) => keystore.new_key().map(|address| address.to_hex()),
+        ("list", _) => {
+            let lines: Vec<String> = keystore
+                .addresses()
+                .iter()
+                .map(|address| match keystore.address_book().iter().find(|(_, known)| known == address) {
+                    Some((path, _)) => format!("{} {}", address.to_hex(), path),
+                    None => address.to_hex(),
+                })
+                .collect();
+            Ok(lines.join("\n"))
+        }
+        ("seed", Some(args)) => match args.value_of("phrase") {
+            Some(phrase) => keystore.set_seed_phrase(phrase).map(|_| {
+                "Seed restored, scan for its used addresses with /wallet/scan once the node is synced".to_string()
+            }),
+            None => {
+                let phrase = wallet::hd::generate_phrase();
+                keystore.set_seed_phrase(&phrase).map(|_| phrase)
+            }
+        },
+        ("derive", Some(args)) => match args.value_of("path") {
+            Some(path) => keystore.derive(path),
+            None => keystore.derive_next(),
+        }
+        .map(|address| address.to_hex()),
+        ("import", Some(args)) => keystore.import(args.value_of("pkcs8").unwrap()).map(|address| address.to_hex()),
+        ("export", Some(args)) => match args.value_of("address").unwrap().parse::<H160>() {
+            Ok(address) => keystore.export(&address),
+            Err(e) => {
+                error!("Error parsing address: {}", e);
+                process::exit(1);
+            }
+        },
+        _ => {
+            error!("Unknown wallet command, see --help");
+            process::exit(1);
+        }
+    };
+    match result {
+        Ok(output) => println!("{}", output),
+        Err(e) => {
+            error!("Wallet error: {}", e);
+            process::exit(1);
+        }
     }
 }
@@ -0,0 +1,262 @@
+use crate::events::Event;
+use crossbeam::channel::Receiver;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+/// Upper bounds of the block propagation delay buckets, in seconds
+const PROPAGATION_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+/// Upper bounds of the reorganization depth buckets, in blocks
+const REORG_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct HistogramData {
+    /// Number of observations in each bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            data: Mutex::new(HistogramData {
+                counts: vec![0; buckets.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut data = self.data.lock().unwrap();
+        if let Some(bucket) = self.buckets.iter().position(|bound| value <= *bound) {
+            data.counts[bucket] += 1;
+        }
+        data.sum += value;
+        data.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let data = self.data.lock().unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in self.buckets.iter().zip(data.counts.iter()) {
+            cumulative += count;
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, data.count).unwrap();
+        writeln!(out, "{}_sum {}", name, data.sum).unwrap();
+        writeln!(out, "{}_count {}", name, data.count).unwrap();
+    }
+}
+
+/// Node telemetry, exposed in the Prometheus text format on the API server
+pub struct Metrics {
+    pub blocks_mined: Counter,
+    pub blocks_received: Counter,
+    pub orphan_blocks: Gauge,
+    pub reorg_depth: Histogram,
+    pub mempool_size: Gauge,
+    /// Transactions in the blocks connected to the longest chain; its rate is the throughput
+    pub txs_confirmed: Counter,
+    pub peers: Gauge,
+    /// Delay from a block's header timestamp to its receipt
+    pub block_propagation_seconds: Histogram,
+    /// Bytes of P2P messages by message type and direction
+    message_bytes: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            blocks_mined: Counter::default(),
+            blocks_received: Counter::default(),
+            orphan_blocks: Gauge::default(),
+            reorg_depth: Histogram::new(REORG_BUCKETS),
+            mempool_size: Gauge::default(),
+            txs_confirmed: Counter::default(),
+            peers: Gauge::default(),
+            block_propagation_seconds: Histogram::new(PROPAGATION_BUCKETS),
+            message_bytes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn message_received(&self, kind: &'static str, bytes: usize) {
+        *self.message_bytes.lock().unwrap().entry((kind, "in")).or_insert(0) += bytes as u64;
+    }
+
+    pub fn message_sent(&self, kind: &'static str, bytes: usize) {
+        *self.message_bytes.lock().unwrap().entry((kind, "out")).or_insert(0) += bytes as u64;
+    }
+
+    /// Keep the metrics derived from chain, mempool and peer events up to date.
+    pub fn observe_events(&'static self, events: Receiver<Event>) {
+        thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                let mut disconnected = 0;
+                for event in events.iter() {
+                    match event {
+                        Event::BlockConnected { txs, .. } => self.txs_confirmed.add(txs as u64),
+                        Event::BlockDisconnected { .. } => disconnected += 1,
+                        Event::TipChanged { .. } => {
+                            if disconnected > 0 {
+                                self.reorg_depth.observe(disconnected as f64);
+                                disconnected = 0;
+                            }
+                        }
+                        Event::TxAccepted(_) => self.mempool_size.inc(),
+                        Event::TxEvicted(_) => self.mempool_size.dec(),
+                        Event::PeerConnected(..) => self.peers.inc(),
+                        Event::PeerDisconnected(_) => self.peers.dec(),
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    /// Render every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("bitcoin_blocks_mined_total", "Blocks mined by this node", &self.blocks_mined),
+            ("bitcoin_blocks_received_total", "New blocks received from peers", &self.blocks_received),
+            ("bitcoin_transactions_confirmed_total", "Transactions in blocks connected to the longest chain", &self.txs_confirmed),
+        ];
+        for (name, help, counter) in counters.iter() {
+            writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, counter.get()).unwrap();
+        }
+        let gauges = [
+            ("bitcoin_orphan_blocks", "Blocks waiting for their parent", &self.orphan_blocks),
+            ("bitcoin_mempool_transactions", "Transactions in the mempool", &self.mempool_size),
+            ("bitcoin_peers", "Connected peers", &self.peers),
+        ];
+        for (name, help, gauge) in gauges.iter() {
+            writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, gauge.get()).unwrap();
+        }
+        let histograms = [
+            ("bitcoin_reorg_depth_blocks", "Blocks disconnected by a chain reorganization", &self.reorg_depth),
+            ("bitcoin_block_propagation_seconds", "Delay from a block's timestamp to its receipt", &self.block_propagation_seconds),
+        ];
+        for (name, help, histogram) in histograms.iter() {
+            writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name).unwrap();
+            histogram.render(&mut out, name);
+        }
+        let name = "bitcoin_message_bytes_total";
+        writeln!(out, "# HELP {} Bytes of P2P messages by type and direction\n# TYPE {} counter", name, name).unwrap();
+        for ((kind, direction), bytes) in self.message_bytes.lock().unwrap().iter() {
+            writeln!(out, "{}{{type=\"{}\",direction=\"{}\"}} {}", name, kind, direction, bytes).unwrap();
+        }
+        out
+    }
+}
+
+/// The metrics of this node
+pub fn global() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventBus;
+    use crate::network::peer::Direction;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+        histogram.observe(7.0);
+        let mut out = String::new();
+        histogram.render(&mut out, "delay");
+        assert_eq!(
+            out,
+            "delay_bucket{le=\"1\"} 1\ndelay_bucket{le=\"5\"} 2\ndelay_bucket{le=\"+Inf\"} 3\ndelay_sum 10.5\ndelay_count 3\n"
+        );
+    }
+
+    #[test]
+    fn render_message_bytes() {
+        let metrics = Metrics::new();
+        metrics.message_received("Blocks", 100);
+        metrics.message_received("Blocks", 20);
+        metrics.message_sent("Ping", 8);
+        let out = metrics.render();
+        assert!(out.contains("bitcoin_message_bytes_total{type=\"Blocks\",direction=\"in\"} 120\n"));
+        assert!(out.contains("bitcoin_message_bytes_total{type=\"Ping\",direction=\"out\"} 8\n"));
+        assert!(out.contains("# TYPE bitcoin_peers gauge\nbitcoin_peers 0\n"));
+    }
+
+    /// Wait for the metrics thread to catch up with the published events
+    fn wait_for(gauge: &Gauge, value: i64) {
+        for _ in 0..200 {
+            if gauge.get() == value {
+                return;
+            }
+            thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert_eq!(gauge.get(), value);
+    }
+
+    #[test]
+    fn peers_gauge_follows_connections() {
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+        let events = EventBus::new();
+        metrics.observe_events(events.subscribe());
+        let first: std::net::SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let second: std::net::SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        events.publish(Event::PeerConnected(first, Direction::Outgoing));
+        events.publish(Event::PeerConnected(second, Direction::Incoming));
+        wait_for(&metrics.peers, 2);
+        events.publish(Event::PeerDisconnected(first));
+        wait_for(&metrics.peers, 1);
+        assert!(metrics.render().contains("\nbitcoin_peers 1\n"));
+    }
+}
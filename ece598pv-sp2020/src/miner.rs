@@ -1,27 +1,34 @@
 use crate::network::server::Handle as ServerHandle;
 use std::sync::Arc;
 use crate::blockchain::Blockchain;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 use log::info;
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{select, unbounded, Receiver, Sender, TryRecvError};
 use std::time;
 use crate::block;
 use std::thread;
 use crate::crypto::hash::{H256, Hashable, H160};
 use std::time::{SystemTime, UNIX_EPOCH};
 use super::block::{Content, Header};
-use super::transaction::{Transaction,SignedTransaction};
+use super::transaction::Transaction;
 use crate::crypto::merkle::MerkleTree;
 use crate::block::Block;
 use serde::{Serialize, Deserialize};
 use super::network::message::Message;
 use crate::txgenerator::TxMempool;
+use crate::network::sync::Synchronizer;
+use crate::network::compact::CompactBlock;
+use crate::events::{Event, EventBus};
+use crate::metrics;
+use crate::state::{self, StateCache};
 use std::collections::HashMap;
 use url::quirks::search;
 
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    Generate(u64), // mine this many blocks without delay, then pause
+    Pause,
     Exit,
 }
 
@@ -36,10 +43,28 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    tx_pool: Arc<Mutex<TxMempool>>,
-    curr_state:Arc<Mutex<HashMap<H160,(u32,u32)>>>,  // <address, (nonce, balance)>
+    blockchain: Arc<RwLock<Blockchain>>,
+    tx_pool: Arc<RwLock<TxMempool>>,
+    curr_state: Arc<RwLock<HashMap<H160, (u32, u32)>>>,
+    /// States after recent blocks, shared with the workers validating the blocks of peers
+    block_states: Arc<Mutex<StateCache>>,
     address: H160,
+    sync: Arc<Mutex<Synchronizer>>,
+    events: Receiver<Event>,
+    /// The block being mined, rebuilt when the tip or mempool changes
+    template: Option<Template>,
+    /// Blocks left to mine before pausing, when generating a fixed number of blocks
+    blocks_to_generate: Option<u64>,
+}
+
+/// Parent and transactions of the block being mined, with the state after it
+#[derive(Clone)]
+struct Template {
+    parent: H256,
+    content: Content,
+    merkle_root: H256,
+    state: HashMap<H160, (u32, u32)>,
+    state_root: H256,
 }
 
 #[derive(Clone)]
@@ -48,26 +73,33 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn new(
     server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>,
-    tx_pool: &Arc<Mutex<TxMempool>>,
-    curr_state: &Arc<Mutex<HashMap<H160, (u32, u32)>>>,
+    blockchain: &Arc<RwLock<Blockchain>>,
+    tx_pool: &Arc<RwLock<TxMempool>>,
+    curr_state: &Arc<RwLock<HashMap<H160, (u32, u32)>>>,
+    block_states: &Arc<Mutex<StateCache>>,
     address: H160,
-
+    sync: &Arc<Mutex<Synchronizer>>,
+    events: &EventBus,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let block = blockchain.clone();
     let mempool_buf = tx_pool.clone();
-    let curr_state = curr_state.clone();
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         server: server.clone(),
         blockchain: block,
         tx_pool:mempool_buf,
-        curr_state: curr_state,
+        curr_state: curr_state.clone(),
+        block_states: block_states.clone(),
         address: address,
+        sync: sync.clone(),
+        events: events.subscribe(),
+        template: None,
+        blocks_to_generate: None,
     };
 
     let handle = Handle {
@@ -88,17 +120,29 @@ impl Handle {
             .unwrap();
     }
 
+    pub fn generate(&self, blocks: u64) {
+        self.control_chan
+            .send(ControlSignal::Generate(blocks))
+            .unwrap();
+    }
+
+    pub fn pause(&self) {
+        self.control_chan.send(ControlSignal::Pause).unwrap();
+    }
+
 }
 
 impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
+    /// Start the miner thread, which runs until `Handle::exit` is called.
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let thread = thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        thread
     }
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
@@ -110,9 +154,59 @@ impl Context {
             ControlSignal::Start(i) => {
                 info!("Miner starting in continuous mode with lambda {}", i);
                 self.operating_state = OperatingState::Run(i);
+                self.blocks_to_generate = None;
+            }
+            ControlSignal::Generate(blocks) => {
+                info!("Miner generating {} blocks", blocks);
+                self.operating_state = if blocks == 0 { OperatingState::Paused } else { OperatingState::Run(0) };
+                self.blocks_to_generate = Some(blocks);
+            }
+            ControlSignal::Pause => {
+                info!("Miner paused");
+                self.operating_state = OperatingState::Paused;
+                self.blocks_to_generate = None;
+            }
+        }
+    }
+    /// Snapshot the tip and the transactions to include, so that no lock is held while hashing.
+    /// There is nothing to mine without transactions, while the initial block download is
+    /// running, as the tip is stale, or before the state of the tip is known.
+    fn block_template(&self) -> Option<Template> {
+        let chain = self.blockchain.read().unwrap();
+        let pool = self.tx_pool.read().unwrap();
+        if pool.buf.is_empty() || !self.sync.lock().unwrap().is_synced(&chain) {
+            return None;
+        }
+        let tip = chain.tip();
+        let curr_state = self.curr_state.read().unwrap();
+        if state::state_root(&curr_state) != chain.chain.get(&tip).unwrap().0.head.state_root {
+            return None;
+        }
+        // blocks from peers may have spent the balance or used the nonce of pooled transactions,
+        // only the ones still valid in order are mined
+        let mut state = curr_state.clone();
+        let mut content = Content { content: Vec::new() };
+        for tx in pool.buf.iter() {
+            if content.content.len() == 9 {
+                break;
+            }
+            if state::can_apply(&state, tx) {
+                state = state::apply_transactions(&state, std::slice::from_ref(tx));
+                content.content.push(tx.clone());
             }
         }
+        if content.content.is_empty() {
+            return None;
+        }
+        Some(Template {
+            parent: tip,
+            merkle_root: MerkleTree::new(&content.content).root(),
+            content,
+            state_root: state::state_root(&state),
+            state,
+        })
     }
+
     fn miner_loop(&mut self) {
         // main mining loop
         loop {
@@ -120,8 +214,12 @@ impl Context {
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
-                    let signal = self.control_chan.recv().unwrap();
-                    self.handle_control_signal(signal);
+                    // events are not needed while paused, the template is rebuilt when mining resumes
+                    select! {
+                        recv(self.control_chan) -> signal => self.handle_control_signal(signal.unwrap()),
+                        recv(self.events) -> _ => {}
+                    }
+                    self.template = None;
                     continue;
                 }
                 OperatingState::ShutDown => {
@@ -138,69 +236,73 @@ impl Context {
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
-            let current_state = self.curr_state.lock().unwrap();
-            let mut chain = self.blockchain.lock().unwrap();
-            let mut pool = self.tx_pool.lock().unwrap();
-            if pool.buf.len() > 0 {
-                let parent = chain.tip();
+            let mut stale = self.template.is_none();
+            for event in self.events.try_iter() {
+                if let Event::TipChanged { .. } | Event::TxAccepted(_) | Event::TxEvicted(_) = event {
+                    stale = true;
+                }
+            }
+            if stale {
+                self.template = self.block_template();
+            }
 
+            if let Some(template) = &self.template {
                 // Generate new block
                 let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("").as_millis();
-                let mut content_new = Content {
-                    content: Vec::<SignedTransaction>::new(),
-                };
-
-                // TODO: Add transactions into Block
-                for element in pool.buf.iter() {
-                    if content_new.content.len() <= 8 {
-                        content_new.content.push(element.clone());
-                    } else {
-                        break;
-                    }
-                }
-                if content_new.content.len() == 0 {
-                    continue;
-                }
                 //println!("current block transaction len {:?}", content_new.content.len());
-                //println!("current transaction pool len {:?}", pool.map.len());
                 // TODO: Generate new block
                 let diff_h256: H256 = hex!("1000000000000000000000000000000000000000000000000000000000000000").into();
                 let rand_nonce: u32 = rand::random();
                 let head_rand = Header {
-                    parent_hash: parent,
+                    parent_hash: template.parent,
                     nonce: rand_nonce,
                     difficulty: diff_h256,
                     timestamp: now,
-                    merkle_root: MerkleTree::new(&(content_new.content)),
-                };
-
-                let new_block = Block {
-                    head: head_rand,
-                    content: content_new.clone(),
+                    merkle_root: template.merkle_root,
+                    state_root: template.state_root,
                 };
 
                 //Calculate block hash
-                let result = new_block.hash();
-                let height = chain.height();
-                if result.le(&new_block.head.difficulty) {
+                let result = head_rand.hash();
+                if result.le(&head_rand.difficulty) {
+                    let new_block = Block {
+                        head: head_rand,
+                        content: template.content.clone(),
+                    };
+                    let state = template.state.clone();
+                    let mut chain = self.blockchain.write().unwrap();
+                    let mut pool = self.tx_pool.write().unwrap();
+                    // the tip may have moved while hashing, the block is then a side branch
                     chain.insert(&new_block);
-                    info!("Find new block");
-                    info!("Length of transactions in this block {:?}", content_new.content.len());
-                    println!("Block hash : {:?}", new_block.clone().hash());
-                    println!("---------------------");
-                    let all_hash = chain.all_blocks_in_longest_chain();
-                    self.server.broadcast(Message::NewBlockHashes(all_hash.clone()));
-                    for tx in content_new.content {
-                        // Update tx_pool
-                        pool.pop_tx(&tx);
+                    if chain.tip() == result {
+                        *self.curr_state.write().unwrap() = state.clone();
+                    }
+                    self.block_states.lock().unwrap().insert(result, state);
+                    info!("Find new block {}", result);
+                    metrics::global().blocks_mined.inc();
+                    info!("Length of transactions in this block {:?}", new_block.content.content.len());
+                    for tx in &new_block.content.content {
+                        // Update tx_pool, the transactions may have been removed by another block
+                        if pool.map.contains_key(&tx.hash()) {
+                            pool.pop_tx(tx);
+                        }
+                    }
+                    std::mem::drop(pool);
+                    std::mem::drop(chain);
+                    // push the new block as a compact block, peers rebuild it from their mempool
+                    // and ask for its ancestors if they miss them
+                    self.server.broadcast(Message::CompactBlock(CompactBlock::new(&new_block)));
+                    if let Some(blocks) = self.blocks_to_generate {
+                        if blocks <= 1 {
+                            info!("Miner generated the requested blocks, pausing");
+                            self.operating_state = OperatingState::Paused;
+                            self.blocks_to_generate = None;
+                        } else {
+                            self.blocks_to_generate = Some(blocks - 1);
+                        }
                     }
-
                 }
-
             }
-            std::mem::drop(pool);
-            std::mem::drop(chain);
-            std::mem::drop(current_state);
 
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
@@ -0,0 +1,121 @@
+use serde::{Serialize, Deserialize};
+use crate::block::{Block, Content, Header};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::SignedTransaction;
+use crate::txgenerator::TxMempool;
+use std::collections::HashMap;
+
+/// Short transaction ID: the first six bytes of SHA256(salt || block hash || transaction hash)
+pub type ShortId = [u8; 6];
+
+/// A block relayed as its header plus short IDs of its transactions. Receivers rebuild the block
+/// from their mempool and only ask for the transactions they do not have.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub head: Header,
+    /// Random per-relay salt, so that short ID collisions cannot be precomputed
+    pub salt: u64,
+    pub short_ids: Vec<ShortId>,
+}
+
+pub fn short_id(salt: u64, block_hash: &H256, tx_hash: &H256) -> ShortId {
+    let mut preimage: Vec<u8> = Vec::with_capacity(72);
+    preimage.extend_from_slice(&salt.to_be_bytes());
+    preimage.extend_from_slice(block_hash.as_ref());
+    preimage.extend_from_slice(tx_hash.as_ref());
+    let digest = ring::digest::digest(&ring::digest::SHA256, &preimage);
+    let mut id = [0; 6];
+    id.copy_from_slice(&digest.as_ref()[0..6]);
+    id
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let salt: u64 = rand::random();
+        let block_hash = block.hash();
+        let short_ids = block
+            .content
+            .content
+            .iter()
+            .map(|tx| short_id(salt, &block_hash, &tx.hash()))
+            .collect();
+        CompactBlock {
+            head: block.head.clone(),
+            salt,
+            short_ids,
+        }
+    }
+
+    pub fn hash(&self) -> H256 {
+        self.head.hash()
+    }
+}
+
+/// A block being reconstructed from a compact block
+pub struct PartialBlock {
+    head: Header,
+    content: Vec<Option<SignedTransaction>>,
+}
+
+impl PartialBlock {
+    /// Fill in the transactions of a compact block that are in the mempool. Short IDs matching
+    /// several mempool transactions are left missing.
+    pub fn new(compact: &CompactBlock, mempool: &TxMempool) -> Self {
+        let block_hash = compact.hash();
+        let mut candidates: HashMap<ShortId, Option<&SignedTransaction>> = HashMap::new();
+        for (tx_hash, tx) in mempool.map.iter() {
+            let id = short_id(compact.salt, &block_hash, tx_hash);
+            candidates
+                .entry(id)
+                .and_modify(|c| *c = None)
+                .or_insert(Some(tx));
+        }
+        let content = compact
+            .short_ids
+            .iter()
+            .map(|id| candidates.get(id).cloned().flatten().cloned())
+            .collect();
+        PartialBlock {
+            head: compact.head.clone(),
+            content,
+        }
+    }
+
+    /// Indexes of the transactions that still have to be fetched
+    pub fn missing(&self) -> Vec<u32> {
+        self.content
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Fill in the transactions requested with `missing`, in the same order. Returns false if the
+    /// number of transactions does not match.
+    pub fn fill(&mut self, txs: Vec<SignedTransaction>) -> bool {
+        let missing = self.missing();
+        if missing.len() != txs.len() {
+            return false;
+        }
+        for (index, tx) in missing.into_iter().zip(txs) {
+            self.content[index as usize] = Some(tx);
+        }
+        true
+    }
+
+    /// The rebuilt block, if every transaction is present and they match the Merkle root of the
+    /// header. A mismatch means a short ID collided with a different transaction.
+    pub fn into_block(self) -> Option<Block> {
+        let content: Option<Vec<SignedTransaction>> = self.content.into_iter().collect();
+        let content = content?;
+        if content.is_empty() || MerkleTree::new(&content).root() != self.head.merkle_root {
+            return None;
+        }
+        Some(Block {
+            head: self.head,
+            content: Content { content },
+        })
+    }
+}
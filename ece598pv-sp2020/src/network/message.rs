@@ -1,9 +1,17 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable, H160};
-use crate::block::Block;
+use crate::block::{Block, Header};
+use super::compact::CompactBlock;
+use super::spv::MerkleBlock;
 use crate::transaction::{Transaction,SignedTransaction};
+use crate::state::AccountProof;
 use std::collections::{HashMap};
 
+/// Version of the wire protocol announced in the handshake
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Messages of the wire protocol. Bincode tags each variant with its position, so new variants
+/// are only ever appended.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Ping(String),
@@ -17,4 +25,66 @@ pub enum Message {
     Transactions(Vec<SignedTransaction>),
     NewPeer(H160),
     Ack(Vec<H160>),
-}
\ No newline at end of file
+    Version(u32),
+    /// Block locator of the requester, answered with the headers following it
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    CompactBlock(CompactBlock),
+    /// Indexes of the transactions of a compact block the requester could not find
+    GetBlockTxn(H256, Vec<u32>),
+    BlockTxn(H256, Vec<SignedTransaction>),
+    /// Address and block of a requested account proof
+    GetAccountProof(H160, H256),
+    AccountProof(H256, AccountProof),
+    /// Blocks requested by a light node, filtered down to the transactions of its addresses
+    GetMerkleBlocks(Vec<H256>, Vec<H160>),
+    MerkleBlocks(Vec<MerkleBlock>),
+}
+
+impl Message {
+    /// Name of the message type, used to label traffic metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Ping(_) => "Ping",
+            Message::Pong(_) => "Pong",
+            Message::NewBlockHashes(_) => "NewBlockHashes",
+            Message::GetBlocks(_) => "GetBlocks",
+            Message::Blocks(_) => "Blocks",
+            Message::NewState(_) => "NewState",
+            Message::NewTransactionHashes(_) => "NewTransactionHashes",
+            Message::GetTransactions(_) => "GetTransactions",
+            Message::Transactions(_) => "Transactions",
+            Message::NewPeer(_) => "NewPeer",
+            Message::Ack(_) => "Ack",
+            Message::Version(_) => "Version",
+            Message::GetHeaders(_) => "GetHeaders",
+            Message::Headers(_) => "Headers",
+            Message::CompactBlock(_) => "CompactBlock",
+            Message::GetBlockTxn(..) => "GetBlockTxn",
+            Message::BlockTxn(..) => "BlockTxn",
+            Message::GetAccountProof(..) => "GetAccountProof",
+            Message::AccountProof(..) => "AccountProof",
+            Message::GetMerkleBlocks(..) => "GetMerkleBlocks",
+            Message::MerkleBlocks(_) => "MerkleBlocks",
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(msg: &Message) -> u32 {
+        let encoded = bincode::serialize(msg).unwrap();
+        u32::from_le_bytes([encoded[0], encoded[1], encoded[2], encoded[3]])
+    }
+
+    #[test]
+    fn wire_tags_are_stable() {
+        assert_eq!(tag(&Message::Ping(String::new())), 0);
+        assert_eq!(tag(&Message::NewState((H256::default(), HashMap::new()))), 5);
+        assert_eq!(tag(&Message::Ack(Vec::new())), 10);
+        assert_eq!(tag(&Message::Version(PROTOCOL_VERSION)), 11);
+        assert_eq!(tag(&Message::BlockTxn(H256::default(), Vec::new())), 16);
+        assert_eq!(tag(&Message::MerkleBlocks(Vec::new())), 20);
+    }
+}
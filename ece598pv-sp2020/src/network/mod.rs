@@ -1,4 +1,7 @@
+pub mod compact;
 pub mod message;
 pub mod peer;
 pub mod server;
+pub mod spv;
+pub mod sync;
 pub mod worker;
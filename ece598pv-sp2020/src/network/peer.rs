@@ -1,10 +1,16 @@
 use super::message;
+use crate::crypto::hash::H256;
+use crate::metrics;
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
 use std::convert::TryInto;
 use std::io::{Read, Write};
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time;
 
 enum DecodeState {
     Length,
@@ -184,6 +190,7 @@ pub fn new(
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        status: Arc::new(Mutex::new(Status::new(direction))),
     };
     let ctx = Context {
         addr,
@@ -196,7 +203,19 @@ pub fn new(
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+/// A handle whose messages are queued on the returned receiver instead of written to a socket
+#[cfg(test)]
+pub fn test_handle(addr: std::net::SocketAddr) -> (Handle, channel::Receiver<Vec<u8>>) {
+    let (write_sender, write_receiver) = channel::channel();
+    let handle = Handle {
+        write_queue: write_sender,
+        addr,
+        status: Arc::new(Mutex::new(Status::new(Direction::Outgoing))),
+    };
+    (handle, write_receiver)
+}
+
+#[derive(Copy, Clone, Debug, Serialize)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -211,16 +230,180 @@ pub struct Context {
     pub direction: Direction,
 }
 
+/// Liveness and accounting data of a peer, shared by the server event loop and the workers
+pub struct Status {
+    direction: Direction,
+    /// Protocol version announced by the peer in its handshake
+    version: Option<u32>,
+    /// Nonce and send time of the ping that has not been answered yet
+    pending_ping: Option<(String, time::Instant)>,
+    missed_pongs: u32,
+    latency: Option<time::Duration>,
+    bytes_in: u64,
+    bytes_out: u64,
+    ban_score: u32,
+    /// Transactions the peer is known to have, oldest first in `known_order`
+    known_txs: HashSet<H256>,
+    known_order: VecDeque<H256>,
+    /// Transactions waiting for the next trickle to be announced to the peer
+    pending_announcements: Vec<H256>,
+    next_trickle: time::Instant,
+}
+
+/// Number of transaction hashes remembered per peer
+const MAX_KNOWN_TXS: usize = 10000;
+
+impl Status {
+    fn new(direction: Direction) -> Self {
+        Status {
+            direction,
+            version: None,
+            pending_ping: None,
+            missed_pongs: 0,
+            latency: None,
+            bytes_in: 0,
+            bytes_out: 0,
+            ban_score: 0,
+            known_txs: HashSet::new(),
+            known_order: VecDeque::new(),
+            pending_announcements: Vec::new(),
+            next_trickle: time::Instant::now(),
+        }
+    }
+
+    fn mark_known(&mut self, hash: &H256) {
+        if self.known_txs.insert(*hash) {
+            self.known_order.push_back(*hash);
+            if self.known_order.len() > MAX_KNOWN_TXS {
+                let oldest = self.known_order.pop_front().unwrap();
+                self.known_txs.remove(&oldest);
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: channel::Sender<Vec<u8>>,
+    status: Arc<Mutex<Status>>,
 }
 
 impl Handle {
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Send a ping with the given nonce. A ping still unanswered at this point counts as a missed
+    /// pong. Returns the number of consecutive missed pongs.
+    pub fn ping(&self, nonce: String) -> u32 {
+        let missed = {
+            let mut status = self.status.lock().unwrap();
+            if status.pending_ping.is_some() {
+                status.missed_pongs += 1;
+            }
+            status.pending_ping = Some((nonce.clone(), time::Instant::now()));
+            status.missed_pongs
+        };
+        self.write(message::Message::Ping(nonce));
+        missed
+    }
+
+    /// Record a pong from this peer. Pongs that do not answer the outstanding ping are ignored.
+    pub fn pong(&self, nonce: &str) {
+        let mut status = self.status.lock().unwrap();
+        let sent = match &status.pending_ping {
+            Some((pending, sent)) if pending == nonce => *sent,
+            _ => return,
+        };
+        status.latency = Some(sent.elapsed());
+        status.pending_ping = None;
+        status.missed_pongs = 0;
+    }
+
+    /// Round-trip time measured by the last answered ping
+    pub fn latency(&self) -> Option<time::Duration> {
+        self.status.lock().unwrap().latency
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.status.lock().unwrap().direction
+    }
+
+    pub fn version(&self) -> Option<u32> {
+        self.status.lock().unwrap().version
+    }
+
+    /// Record the protocol version the peer announced in its handshake
+    pub fn set_version(&self, version: u32) {
+        self.status.lock().unwrap().version = Some(version);
+    }
+
+    /// Total bytes received from and sent to this peer, including the length prefixes
+    pub fn traffic(&self) -> (u64, u64) {
+        let status = self.status.lock().unwrap();
+        (status.bytes_in, status.bytes_out)
+    }
+
+    /// Account for a message of `length` bytes received from this peer
+    pub fn received(&self, length: usize) {
+        self.status.lock().unwrap().bytes_in += (length + std::mem::size_of::<u32>()) as u64;
+    }
+
+    pub fn ban_score(&self) -> u32 {
+        self.status.lock().unwrap().ban_score
+    }
+
+    /// Remember that the peer has these transactions, so they are not announced to it.
+    pub fn mark_known(&self, hashes: &[H256]) {
+        let mut status = self.status.lock().unwrap();
+        for hash in hashes {
+            status.mark_known(hash);
+        }
+    }
+
+    /// Queue a transaction for the next announcement to this peer, unless the peer has it.
+    pub fn queue_announcement(&self, hash: &H256) {
+        let mut status = self.status.lock().unwrap();
+        if !status.known_txs.contains(hash) && !status.pending_announcements.contains(hash) {
+            status.pending_announcements.push(*hash);
+        }
+    }
+
+    /// Time at which the queued announcements are due
+    pub fn next_trickle(&self) -> time::Instant {
+        self.status.lock().unwrap().next_trickle
+    }
+
+    /// Announce the queued transactions in one message and schedule the next trickle.
+    pub fn trickle(&self, next: time::Instant) {
+        let batch = {
+            let mut status = self.status.lock().unwrap();
+            status.next_trickle = next;
+            let batch: Vec<H256> = status.pending_announcements.drain(..).collect();
+            for hash in &batch {
+                status.mark_known(hash);
+            }
+            batch
+        };
+        if !batch.is_empty() {
+            self.write(message::Message::NewTransactionHashes(batch));
+        }
+    }
+
+    /// Penalize the peer for misbehaving. Returns the accumulated ban score.
+    pub fn misbehaving(&self, score: u32) -> u32 {
+        let mut status = self.status.lock().unwrap();
+        status.ban_score = status.ban_score.saturating_add(score);
+        status.ban_score
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
         let buffer = bincode::serialize(&msg).unwrap();
+        let len = buffer.len() + std::mem::size_of::<u32>();
+        self.status.lock().unwrap().bytes_out += len as u64;
+        metrics::global().message_sent(msg.kind(), len);
         if self.write_queue.send(buffer).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
@@ -1,18 +1,34 @@
 use super::message;
 use super::peer::{self, ReadResult, WriteResult};
+use crate::crypto::hash::H256;
+use crate::events::{Event, EventBus};
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::sync::mpsc;
 use std::thread;
+use std::time;
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+/// Ban score at which a misbehaving peer is banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// Mean delay between two transaction announcements to the same peer
+const TRICKLE_INTERVAL: time::Duration = time::Duration::from_millis(2000);
+/// How long queued messages are given to reach the peers when shutting down
+const DRAIN_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+/// How long a peer stays banned when no duration is given
+pub const DEFAULT_BAN_DURATION: time::Duration = time::Duration::from_secs(24 * 60 * 60);
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    ping_interval: time::Duration,
+    max_missed_pongs: u32,
+    events: &EventBus,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
@@ -22,9 +38,15 @@ pub fn new(
         peers: slab::Slab::new(),
         peer_list: vec![],
         addr,
+        banned: HashMap::new(),
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        ping_interval,
+        max_missed_pongs,
+        next_ping: time::Instant::now() + ping_interval,
+        shutdown_at: None,
+        events: events.clone(),
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
@@ -34,21 +56,28 @@ pub struct Context {
     peers: slab::Slab<peer::Context>,
     peer_list: Vec<usize>,
     addr: std::net::SocketAddr,
+    /// Banned IP addresses and the time their ban expires
+    banned: HashMap<std::net::IpAddr, time::Instant>,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    ping_interval: time::Duration,
+    max_missed_pongs: u32,
+    next_ping: time::Instant,
+    /// Once shutting down, the time at which the peers are disconnected and the server stops
+    shutdown_at: Option<time::Instant>,
+    events: EventBus,
     _handle: Handle,
 }
 
 impl Context {
-    /// Start a new server context.
-    pub fn start(mut self) -> std::io::Result<()> {
-        thread::spawn(move || {
+    /// Start a new server context. The returned thread ends once the server is shut down.
+    pub fn start(mut self) -> std::io::Result<thread::JoinHandle<()>> {
+        thread::Builder::new().name("p2p-server".to_string()).spawn(move || {
             self.listen().unwrap_or_else(|e| {
                 error!("P2P server error: {}", e);
             });
-        });
-        Ok(())
+        })
     }
 
     /// Register a TCP stream in the event loop, and initialize peer context.
@@ -89,17 +118,27 @@ impl Context {
             mio::PollOpt::edge() | mio::PollOpt::oneshot(),
         )?;
 
+        // start the handshake by announcing our protocol version
+        handle.write(message::Message::Version(message::PROTOCOL_VERSION));
+
         // insert the context and return the handle
         vacant.insert(ctx);
         // record the key of this peer
         self.peer_list.push(key);
         trace!("Registering peer with event token={}", key);
+        self.events.publish(Event::PeerConnected(handle.addr(), direction));
         Ok(handle)
     }
 
     /// Connect to a peer, and register this peer
     fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
         // we need to estabilsh a stdlib tcp stream, since we need it to block
+        if self.is_banned(&addr.ip()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "peer is banned",
+            ));
+        }
         debug!("Establishing connection to peer {}", addr);
         let stream = std::net::TcpStream::connect(addr)?;
         let mio_stream = net::TcpStream::from_stream(stream)?;
@@ -113,6 +152,10 @@ impl Context {
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
+        if self.is_banned(&addr.ip()) {
+            info!("Rejected incoming connection from banned peer {}", addr);
+            return Ok(());
+        }
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
@@ -137,10 +180,137 @@ impl Context {
                     self.peers[*peer_id].handle.write(msg.clone());
                 }
             }
+            ControlSignal::RelayTransactions(hashes, origin) => {
+                trace!("Processing RelayTransactions command");
+                for peer_id in &self.peer_list {
+                    let handle = &self.peers[*peer_id].handle;
+                    if Some(handle.addr()) == origin {
+                        continue;
+                    }
+                    for hash in &hashes {
+                        handle.queue_announcement(hash);
+                    }
+                }
+            }
+            ControlSignal::GetPeers(result_chan) => {
+                trace!("Processing GetPeers command");
+                let peers = self
+                    .peer_list
+                    .iter()
+                    .map(|peer_id| {
+                        let handle = &self.peers[*peer_id].handle;
+                        let (bytes_in, bytes_out) = handle.traffic();
+                        PeerInfo {
+                            addr: handle.addr(),
+                            direction: handle.direction(),
+                            version: handle.version(),
+                            latency_ms: handle.latency().map(|l| l.as_secs_f64() * 1000.0),
+                            bytes_in,
+                            bytes_out,
+                            ban_score: handle.ban_score(),
+                        }
+                    })
+                    .collect();
+                result_chan.send(peers).unwrap();
+            }
+            ControlSignal::DisconnectPeer(addr, result_chan) => {
+                trace!("Processing DisconnectPeer command");
+                let found = self.peer_by_addr(&addr);
+                if let Some(peer_id) = found {
+                    info!("Disconnecting peer {}", addr);
+                    self.remove_peer(peer_id);
+                }
+                // nobody waits for the answer when a misbehaving peer is dropped
+                let _ = result_chan.send(found.is_some());
+            }
+            ControlSignal::Shutdown => {
+                trace!("Processing Shutdown command");
+                if self.shutdown_at.is_none() {
+                    info!("P2P server draining {} peers", self.peer_list.len());
+                    self.shutdown_at = Some(time::Instant::now() + DRAIN_TIMEOUT);
+                }
+            }
+            ControlSignal::BanPeer(ip, duration) => {
+                trace!("Processing BanPeer command");
+                info!("Banning {} for {:?}", ip, duration);
+                self.banned.insert(ip, time::Instant::now() + duration);
+                let banned_peers: Vec<usize> = self
+                    .peer_list
+                    .iter()
+                    .filter(|peer_id| self.peers[**peer_id].addr.ip() == ip)
+                    .cloned()
+                    .collect();
+                for peer_id in banned_peers {
+                    self.remove_peer(peer_id);
+                }
+            }
         }
         Ok(())
     }
 
+    fn peer_by_addr(&self, addr: &std::net::SocketAddr) -> Option<usize> {
+        self.peer_list
+            .iter()
+            .find(|peer_id| self.peers[**peer_id].addr == *addr)
+            .cloned()
+    }
+
+    /// Check whether an IP address is banned, forgetting the bans that have expired.
+    fn is_banned(&mut self, ip: &std::net::IpAddr) -> bool {
+        let now = time::Instant::now();
+        self.banned.retain(|_, until| *until > now);
+        self.banned.contains_key(ip)
+    }
+
+    /// Remove a peer from the connection set and close its socket.
+    fn remove_peer(&mut self, peer_id: usize) {
+        let peer = self.peers.remove(peer_id);
+        let _ = peer.stream.shutdown(std::net::Shutdown::Both);
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+        self.events.publish(Event::PeerDisconnected(peer.handle.addr()));
+    }
+
+    /// Send the queued transaction announcements of the peers whose trickle is due. Delays are
+    /// drawn from an exponential distribution, so that the order in which peers learn about a
+    /// transaction does not reveal where it came from.
+    fn trickle_peers(&mut self) -> time::Instant {
+        let now = time::Instant::now();
+        let mut next = now + TRICKLE_INTERVAL;
+        for peer_id in &self.peer_list {
+            let handle = &self.peers[*peer_id].handle;
+            let mut due = handle.next_trickle();
+            if due <= now {
+                let uniform: f64 = rand::random();
+                due = now + TRICKLE_INTERVAL.mul_f64(-(1.0 - uniform).ln());
+                handle.trickle(due);
+            }
+            next = next.min(due);
+        }
+        next
+    }
+
+    /// Ping every peer with a fresh random nonce, and disconnect the peers that have not answered
+    /// the last `max_missed_pongs` pings.
+    fn ping_peers(&mut self) {
+        let mut unresponsive = vec![];
+        for peer_id in &self.peer_list {
+            let handle = &self.peers[*peer_id].handle;
+            let nonce = format!("{:016x}", rand::random::<u64>());
+            if handle.ping(nonce) >= self.max_missed_pongs {
+                unresponsive.push(*peer_id);
+            }
+        }
+        for peer_id in unresponsive {
+            info!(
+                "Peer {} missed {} pongs, disconnecting",
+                self.peers[peer_id].addr, self.max_missed_pongs
+            );
+            self.remove_peer(peer_id);
+        }
+        self.next_ping = time::Instant::now() + self.ping_interval;
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
         let peer = &mut self.peers[peer_id];
@@ -159,14 +329,13 @@ impl Context {
     fn process_readable(&mut self, peer_id: usize) -> std::io::Result<()> {
         // we are using edge-triggered events, loop until block
         let peer = &mut self.peers[peer_id];
+        let mut disconnected = false;
         loop {
             match peer.reader.read() {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    disconnected = true;
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -177,6 +346,7 @@ impl Context {
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
+                    peer.handle.received(m.len());
                     self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
                     continue;
                 }
@@ -187,14 +357,15 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        disconnected = true;
                         break;
                     }
                 }
             }
         }
+        if disconnected {
+            self.remove_peer(peer_id);
+        }
         Ok(())
     }
 
@@ -224,9 +395,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.remove_peer(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -246,9 +415,7 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                 }
             }
         }
@@ -284,7 +451,26 @@ impl Context {
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            let now = time::Instant::now();
+            if let Some(shutdown_at) = self.shutdown_at {
+                if now >= shutdown_at {
+                    while let Some(&peer_id) = self.peer_list.last() {
+                        self.remove_peer(peer_id);
+                    }
+                    info!("P2P server stopped");
+                    return Ok(());
+                }
+            }
+            if now >= self.next_ping {
+                self.ping_peers();
+            }
+            let next_trickle = self.trickle_peers();
+            let mut wakeup = self.next_ping.min(next_trickle);
+            if let Some(shutdown_at) = self.shutdown_at {
+                wakeup = wakeup.min(shutdown_at);
+            }
+            let timeout = wakeup.saturating_duration_since(now);
+            self.poll.poll(&mut events, Some(timeout))?;
 
             for event in events.iter() {
                 match event.token() {
@@ -308,6 +494,9 @@ impl Context {
                             }
                         }
                     }
+                    INCOMING if self.shutdown_at.is_some() => {
+                        trace!("Ignoring incoming connection while shutting down");
+                    }
                     INCOMING => {
                         trace!("P2P server listener readable");
                         // we have a new connection
@@ -353,6 +542,9 @@ impl Context {
                             }
                             1 => {
                                 trace!("Peer {} outgoing queue readable", peer_id);
+                                if !self.peers.contains(peer_id) {
+                                    continue;
+                                }
                                 self.register_write_interest(peer_id)?;
                             }
                             _ => unreachable!(),
@@ -369,6 +561,13 @@ pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
 }
 
+/// A handle to no server, for tests that never reach the network
+#[cfg(test)]
+pub fn test_handle() -> Handle {
+    let (control_chan, _) = channel::channel();
+    Handle { control_chan }
+}
+
 impl Handle {
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
         let (sender, receiver) = cbchannel::unbounded();
@@ -382,16 +581,99 @@ impl Handle {
         receiver.recv().unwrap()
     }
 
+    /// Send a signal that needs no answer. Signals sent once the server has stopped are dropped,
+    /// as the workers may still be handling the last messages.
+    fn signal(&self, signal: ControlSignal) {
+        if self.control_chan.send(signal).is_err() {
+            debug!("P2P server stopped, dropping control signal");
+        }
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
+        self.signal(ControlSignal::BroadcastMessage(msg));
+    }
+
+    /// Announce transactions to every peer except the one they came from. Announcements are
+    /// batched per peer and skip the peers already known to have the transactions.
+    pub fn relay_transactions(&self, hashes: Vec<H256>, origin: Option<std::net::SocketAddr>) {
+        self.signal(ControlSignal::RelayTransactions(hashes, origin));
+    }
+
+    /// Get the list of connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::GetPeers(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Disconnect the peer at the given address. Returns false if no such peer is connected.
+    pub fn disconnect(&self, addr: std::net::SocketAddr) -> bool {
+        let (sender, receiver) = cbchannel::unbounded();
         self.control_chan
-            .send(ControlSignal::BroadcastMessage(msg))
+            .send(ControlSignal::DisconnectPeer(addr, sender))
             .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Disconnect the peer at the given address without waiting for the server, which may have
+    /// stopped already. Safe to call from the workers.
+    pub fn drop_peer(&self, addr: std::net::SocketAddr) {
+        let (sender, _) = cbchannel::unbounded();
+        self.signal(ControlSignal::DisconnectPeer(addr, sender));
+    }
+
+    /// Disconnect every peer at the given IP address, and refuse connections from and to it for
+    /// `duration`.
+    pub fn ban(&self, ip: std::net::IpAddr, duration: time::Duration) {
+        self.signal(ControlSignal::BanPeer(ip, duration));
+    }
+
+    /// Stop accepting connections, give queued messages `DRAIN_TIMEOUT` to go out, then
+    /// disconnect every peer and stop the server.
+    pub fn shutdown(&self) {
+        self.signal(ControlSignal::Shutdown);
     }
+
+    /// Penalize a misbehaving peer, banning it once its score reaches `BAN_THRESHOLD`. Peers on
+    /// the loopback address are only disconnected, since the nodes of a local network all share
+    /// it and banning it would cut this node off from every one of them.
+    pub fn report_misbehavior(&self, peer: &peer::Handle, score: u32) {
+        let total = peer.misbehaving(score);
+        warn!("Peer {} misbehaving, ban score {}", peer.addr(), total);
+        if total >= BAN_THRESHOLD {
+            if peer.addr().ip().is_loopback() {
+                self.drop_peer(peer.addr());
+            } else {
+                self.ban(peer.addr().ip(), DEFAULT_BAN_DURATION);
+            }
+        }
+    }
+}
+
+/// Information about a connected peer
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub direction: peer::Direction,
+    /// Protocol version announced in the handshake, if it has been received
+    pub version: Option<u32>,
+    /// Round-trip time of the last answered ping, in milliseconds
+    pub latency_ms: Option<f64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub ban_score: u32,
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    RelayTransactions(Vec<H256>, Option<std::net::SocketAddr>),
+    GetPeers(cbchannel::Sender<Vec<PeerInfo>>),
+    DisconnectPeer(std::net::SocketAddr, cbchannel::Sender<bool>),
+    BanPeer(std::net::IpAddr, time::Duration),
+    Shutdown,
 }
 
 struct ConnectRequest {
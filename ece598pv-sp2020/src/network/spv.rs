@@ -0,0 +1,133 @@
+use serde::{Serialize, Deserialize};
+use crate::block::{Block, Content, Header};
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::crypto::merkle::{self, MerkleTree};
+use crate::transaction::SignedTransaction;
+
+/// A transaction with the Merkle path proving it is part of its block
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxProof {
+    /// Position of the transaction in its block
+    pub index: usize,
+    /// Siblings of the path to the transaction's leaf, from the root down
+    pub proof: Vec<H256>,
+    pub tx: SignedTransaction,
+}
+
+/// A block filtered down to the transactions sent or received by some addresses, for light
+/// nodes that only download headers. Each transaction comes with its Merkle path to the root in
+/// the header, so it can be checked without the rest of the block.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MerkleBlock {
+    pub head: Header,
+    /// Number of transactions in the full block
+    pub tx_count: usize,
+    pub txs: Vec<TxProof>,
+}
+
+impl MerkleBlock {
+    pub fn new(block: &Block, addresses: &[H160]) -> Self {
+        let tree = MerkleTree::new(&block.content.content);
+        let txs = block
+            .content
+            .content
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| addresses.contains(&tx.sender()) || addresses.contains(&tx.transaction.address))
+            .map(|(index, tx)| TxProof {
+                index,
+                proof: tree.proof(index),
+                tx: tx.clone(),
+            })
+            .collect();
+        MerkleBlock {
+            head: block.head.clone(),
+            tx_count: block.content.content.len(),
+            txs,
+        }
+    }
+
+    pub fn hash(&self) -> H256 {
+        self.head.hash()
+    }
+
+    /// Whether every transaction is proven to be in the block at its position
+    pub fn verify(&self) -> bool {
+        self.txs
+            .iter()
+            .all(|tx| merkle::verify(&self.head.merkle_root, &tx.tx.hash(), &tx.proof, tx.index, self.tx_count))
+    }
+
+    /// The block holding only the proven transactions, as kept by light nodes. Its hash is the
+    /// hash of the full block.
+    pub fn into_block(self) -> Block {
+        Block {
+            head: self.head,
+            content: Content {
+                content: self.txs.into_iter().map(|tx| tx.tx).collect(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::transaction::{self, Transaction};
+    use ring::signature::KeyPair;
+
+    fn transfer(key: &ring::signature::Ed25519KeyPair, address: H160) -> SignedTransaction {
+        let tx = Transaction {
+            self_balance: 100,
+            address,
+            value: 10,
+            nonce: 1,
+            fee: 1,
+        };
+        SignedTransaction {
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: transaction::sign(&tx, key).as_ref().to_vec(),
+            transaction: tx,
+        }
+    }
+
+    fn block_with(txs: Vec<SignedTransaction>) -> Block {
+        Block {
+            head: Header {
+                parent_hash: H256::default(),
+                nonce: 0,
+                difficulty: H256::default(),
+                timestamp: 0,
+                merkle_root: MerkleTree::new(&txs).root(),
+                state_root: H256::default(),
+            },
+            content: Content { content: txs },
+        }
+    }
+
+    #[test]
+    fn filtered_transactions_are_proven() {
+        let watched = key_pair::address(&key_pair::random());
+        let txs: Vec<SignedTransaction> = (0..5)
+            .map(|i| {
+                let recipient = if i % 2 == 1 { watched } else { key_pair::address(&key_pair::random()) };
+                transfer(&key_pair::random(), recipient)
+            })
+            .collect();
+        let block = block_with(txs);
+        let merkle_block = MerkleBlock::new(&block, &[watched]);
+        assert_eq!(merkle_block.txs.iter().map(|tx| tx.index).collect::<Vec<_>>(), vec![1, 3]);
+        assert!(merkle_block.verify());
+
+        let mut forged = merkle_block.clone();
+        forged.txs[0].tx = block.content.content[0].clone();
+        assert!(!forged.verify());
+        let filtered = merkle_block.into_block();
+        assert_eq!(filtered.hash(), block.hash());
+        assert_eq!(filtered.content.content.len(), 2);
+
+        let single = block_with(vec![transfer(&key_pair::random(), watched)]);
+        assert!(MerkleBlock::new(&single, &[watched]).verify());
+    }
+}
@@ -0,0 +1,426 @@
+use super::message::Message;
+use super::peer;
+use crate::block::Header;
+use crate::blockchain::{Blockchain, HeaderError};
+use crate::crypto::hash::{H160, H256};
+use crate::events::{Event, EventBus};
+use crossbeam::channel::{self, select, unbounded, Receiver, Sender};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time;
+
+/// Maximum number of headers sent in one `Headers` message
+pub const MAX_HEADERS: usize = 2000;
+/// Blocks beyond the lowest missing one that may be requested at the same time
+const BLOCK_WINDOW: usize = 128;
+/// Maximum number of blocks requested from a single peer at a time
+const MAX_BLOCKS_PER_PEER: usize = 16;
+const BLOCK_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const HEADERS_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+/// A peer that lets this many requests time out in a row is no longer asked for blocks
+const MAX_STALLS: u32 = 3;
+const TICK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+struct PeerState {
+    handle: peer::Handle,
+    /// Time our outstanding `GetHeaders` was sent to this peer
+    headers_requested: Option<time::Instant>,
+    blocks_in_flight: usize,
+    stalls: u32,
+}
+
+/// Header-first block download. Headers are fetched from every peer with a block locator, and the
+/// blocks of the best header chain are then downloaded in parallel from all peers, within a window
+/// sliding along the chain. Light nodes download the blocks filtered down to the transactions of
+/// their addresses instead.
+pub struct Synchronizer {
+    peers: HashMap<std::net::SocketAddr, PeerState>,
+    /// Requested blocks, with the peer they were requested from and the time of the request
+    in_flight: HashMap<H256, (std::net::SocketAddr, time::Instant)>,
+    /// Addresses whose transactions a light node downloads, none for a full node
+    filter: Option<Vec<H160>>,
+    /// Whether a peer has answered our `GetHeaders`, before which the best header is unknown
+    headers_received: bool,
+}
+
+impl Default for Synchronizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Progress of the initial block download
+#[derive(Serialize, Debug, Clone)]
+pub struct Progress {
+    pub header_height: usize,
+    pub block_height: usize,
+    pub blocks_in_flight: usize,
+    pub peers: usize,
+    pub synced: bool,
+}
+
+impl Synchronizer {
+    pub fn new() -> Self {
+        Synchronizer {
+            peers: HashMap::new(),
+            in_flight: HashMap::new(),
+            filter: None,
+            headers_received: false,
+        }
+    }
+
+    /// Create the synchronizer of a light node, downloading `MerkleBlock`s of the transactions
+    /// sent or received by `addresses` rather than full blocks.
+    pub fn light(addresses: &[H160]) -> Self {
+        Synchronizer {
+            filter: Some(addresses.to_vec()),
+            ..Self::new()
+        }
+    }
+
+    pub fn is_light(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    /// The request for blocks, filtered for a light node
+    fn block_request(&self, hashes: Vec<H256>) -> Message {
+        match &self.filter {
+            Some(addresses) => Message::GetMerkleBlocks(hashes, addresses.clone()),
+            None => Message::GetBlocks(hashes),
+        }
+    }
+
+    /// Start syncing with a peer that has completed the handshake. A peer already syncing is
+    /// left as it is, with its requests in flight.
+    pub fn add_peer(&mut self, peer: &peer::Handle, chain: &Blockchain) {
+        if self.peers.contains_key(&peer.addr()) {
+            return;
+        }
+        self.peers.insert(
+            peer.addr(),
+            PeerState {
+                handle: peer.clone(),
+                headers_requested: None,
+                blocks_in_flight: 0,
+                stalls: 0,
+            },
+        );
+        self.request_headers(peer, chain);
+    }
+
+    /// Ask a peer for the headers following our best header. This is also how the missing
+    /// ancestors of a block with an unknown parent are found.
+    pub fn request_headers(&mut self, peer: &peer::Handle, chain: &Blockchain) {
+        peer.write(Message::GetHeaders(chain.locator()));
+        if let Some(state) = self.peers.get_mut(&peer.addr()) {
+            state.headers_requested = Some(time::Instant::now());
+        }
+    }
+
+    /// Add the headers a peer sent to the header tree, ask for more if the message was full, and
+    /// schedule the download of the new blocks.
+    pub fn on_headers(
+        &mut self,
+        peer: &peer::Handle,
+        headers: &[Header],
+        chain: &mut Blockchain,
+    ) -> Result<(), HeaderError> {
+        if let Some(state) = self.peers.get_mut(&peer.addr()) {
+            state.headers_requested = None;
+            self.headers_received = true;
+        }
+        let mut new_headers = 0;
+        for header in headers {
+            if chain.insert_header(header)? {
+                new_headers += 1;
+            }
+        }
+        if headers.len() == MAX_HEADERS {
+            self.request_headers(peer, chain);
+        }
+        if new_headers > 0 {
+            info!(
+                "Synced headers to height {}, blocks at height {}",
+                chain.best_header_height(),
+                chain.height()
+            );
+        }
+        self.schedule(chain);
+        Ok(())
+    }
+
+    /// Stop syncing with a disconnected peer, and download the blocks it was sending from the
+    /// other peers.
+    pub fn remove_peer(&mut self, addr: &std::net::SocketAddr, chain: &Blockchain) {
+        if self.peers.remove(addr).is_none() {
+            return;
+        }
+        self.in_flight.retain(|_, (from, _)| from != addr);
+        self.schedule(chain);
+    }
+
+    /// Request a single block from a peer, unless it is already being downloaded.
+    pub fn request_block(&mut self, peer: &peer::Handle, hash: &H256) {
+        if self.in_flight.contains_key(hash) {
+            return;
+        }
+        if let Some(state) = self.peers.get_mut(&peer.addr()) {
+            state.blocks_in_flight += 1;
+            self.in_flight.insert(*hash, (peer.addr(), time::Instant::now()));
+        }
+        peer.write(self.block_request(vec![*hash]));
+    }
+
+    /// Mark a block as received.
+    pub fn on_block(&mut self, hash: &H256) {
+        if let Some((addr, _)) = self.in_flight.remove(hash) {
+            if let Some(state) = self.peers.get_mut(&addr) {
+                state.blocks_in_flight = state.blocks_in_flight.saturating_sub(1);
+                state.stalls = 0;
+            }
+        }
+    }
+
+    /// Forget a block that failed validation, so that it is not downloaded again.
+    pub fn on_invalid_block(&mut self, hash: &H256, chain: &mut Blockchain) {
+        self.on_block(hash);
+        chain.discard_header(hash);
+    }
+
+    /// Request the missing blocks of the best header chain that fall in the download window from
+    /// the peers with free download slots.
+    pub fn schedule(&mut self, chain: &Blockchain) {
+        // walk back from the best header to the first block we have
+        let mut missing = Vec::new();
+        let mut hash = chain.best_header;
+        while !chain.chain.contains_key(&hash) {
+            let (header, _) = chain.headers.get(&hash).unwrap();
+            missing.push(hash);
+            hash = header.parent_hash;
+        }
+        missing.reverse();
+
+        let mut requests: HashMap<std::net::SocketAddr, Vec<H256>> = HashMap::new();
+        for hash in missing.into_iter().take(BLOCK_WINDOW) {
+            if self.in_flight.contains_key(&hash) {
+                continue;
+            }
+            let peer = self
+                .peers
+                .iter_mut()
+                .filter(|(_, state)| state.stalls < MAX_STALLS && state.blocks_in_flight < MAX_BLOCKS_PER_PEER)
+                .min_by_key(|(_, state)| state.blocks_in_flight);
+            let (addr, state) = match peer {
+                Some(p) => p,
+                None => break,
+            };
+            state.blocks_in_flight += 1;
+            self.in_flight.insert(hash, (*addr, time::Instant::now()));
+            requests.entry(*addr).or_default().push(hash);
+        }
+        for (addr, hashes) in requests {
+            self.peers[&addr].handle.write(self.block_request(hashes));
+        }
+    }
+
+    /// Expire timed out requests, stop downloading from stalling peers, and reschedule.
+    pub fn tick(&mut self, chain: &Blockchain) {
+        let now = time::Instant::now();
+        let expired: Vec<H256> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, requested))| now.duration_since(*requested) > BLOCK_TIMEOUT)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            let (addr, _) = self.in_flight.remove(&hash).unwrap();
+            if let Some(state) = self.peers.get_mut(&addr) {
+                state.blocks_in_flight = state.blocks_in_flight.saturating_sub(1);
+                state.stalls += 1;
+                if state.stalls == MAX_STALLS {
+                    warn!("Peer {} stalled the block download", addr);
+                }
+            }
+        }
+        for state in self.peers.values_mut() {
+            if let Some(requested) = state.headers_requested {
+                if now.duration_since(requested) > HEADERS_TIMEOUT {
+                    state.headers_requested = None;
+                    state.stalls += 1;
+                }
+            }
+        }
+        self.peers.retain(|_, state| state.stalls < MAX_STALLS || state.blocks_in_flight > 0);
+        self.schedule(chain);
+        if !self.is_synced(chain) {
+            info!(
+                "Syncing: headers at height {}, blocks at height {}, {} blocks in flight",
+                chain.best_header_height(),
+                chain.height(),
+                self.in_flight.len()
+            );
+        }
+    }
+
+    /// Whether the block chain has caught up with every header we know of. A node no peer has
+    /// sent headers to yet does not know how far behind it is, and is not synced.
+    pub fn is_synced(&self, chain: &Blockchain) -> bool {
+        self.headers_received
+            && self.peers.values().all(|state| state.headers_requested.is_none())
+            && chain.height() >= chain.best_header_height()
+    }
+
+    pub fn progress(&self, chain: &Blockchain) -> Progress {
+        Progress {
+            header_height: chain.best_header_height(),
+            block_height: chain.height(),
+            blocks_in_flight: self.in_flight.len(),
+            peers: self.peers.len(),
+            synced: self.is_synced(chain),
+        }
+    }
+}
+
+pub struct Context {
+    sync: Arc<Mutex<Synchronizer>>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    /// Peer events, to stop downloading from the peers that disconnect
+    events: Receiver<Event>,
+    exit_chan: Receiver<()>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    exit_chan: Sender<()>,
+}
+
+pub fn new(
+    sync: &Arc<Mutex<Synchronizer>>,
+    blockchain: &Arc<RwLock<Blockchain>>,
+    events: &EventBus,
+) -> (Context, Handle) {
+    let (exit_sender, exit_receiver) = unbounded();
+    let ctx = Context {
+        sync: sync.clone(),
+        blockchain: blockchain.clone(),
+        events: events.subscribe(),
+        exit_chan: exit_receiver,
+    };
+    (ctx, Handle { exit_chan: exit_sender })
+}
+
+impl Handle {
+    pub fn exit(&self) {
+        let _ = self.exit_chan.send(());
+    }
+}
+
+impl Context {
+    /// Start the thread that expires stalled block requests and drops disconnected peers, until
+    /// `Handle::exit` is called.
+    pub fn start(self) -> thread::JoinHandle<()> {
+        thread::Builder::new()
+            .name("sync".to_string())
+            .spawn(move || {
+                let ticker = channel::tick(TICK_INTERVAL);
+                loop {
+                    select! {
+                        recv(self.exit_chan) -> _ => break,
+                        recv(self.events) -> event => {
+                            if let Ok(Event::PeerDisconnected(addr)) = event {
+                                let chain = self.blockchain.read().unwrap();
+                                self.sync.lock().unwrap().remove_peer(&addr, &chain);
+                            }
+                        }
+                        recv(ticker) -> _ => {
+                            let chain = self.blockchain.read().unwrap();
+                            self.sync.lock().unwrap().tick(&chain);
+                        }
+                    }
+                }
+            })
+            .unwrap()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_mined_block;
+    use crate::crypto::hash::Hashable;
+
+    /// Add `length` headers after the genesis block
+    fn headers(chain: &mut Blockchain, length: usize) -> Vec<H256> {
+        let mut parent = chain.tip();
+        (0..length)
+            .map(|_| {
+                let block = generate_mined_block(&parent, &chain.diff);
+                chain.insert_header(&block.head).unwrap();
+                parent = block.hash();
+                parent
+            })
+            .collect()
+    }
+
+    fn in_flight_from(sync: &Synchronizer, addr: &std::net::SocketAddr) -> usize {
+        sync.in_flight.values().filter(|(from, _)| from == addr).count()
+    }
+
+    #[test]
+    fn repeated_peers_keep_their_requests() {
+        let mut chain = Blockchain::new();
+        let hashes = headers(&mut chain, 3);
+        let addr = "127.0.0.1:6001".parse().unwrap();
+        let (peer, _queue) = peer::test_handle(addr);
+        let mut sync = Synchronizer::new();
+        sync.add_peer(&peer, &chain);
+        sync.schedule(&chain);
+        assert_eq!(sync.peers[&addr].blocks_in_flight, 3);
+
+        sync.add_peer(&peer, &chain);
+        assert_eq!(sync.peers[&addr].blocks_in_flight, 3);
+        for hash in &hashes {
+            sync.on_block(hash);
+            sync.on_block(hash);
+        }
+        assert_eq!(sync.peers[&addr].blocks_in_flight, 0);
+        assert!(sync.in_flight.is_empty());
+    }
+
+    #[test]
+    fn disconnected_peers_hand_over_their_requests() {
+        let mut chain = Blockchain::new();
+        headers(&mut chain, 4);
+        let first = "127.0.0.1:6001".parse().unwrap();
+        let second = "127.0.0.1:6002".parse().unwrap();
+        let (first_peer, _first_queue) = peer::test_handle(first);
+        let (second_peer, _second_queue) = peer::test_handle(second);
+        let mut sync = Synchronizer::new();
+        sync.add_peer(&first_peer, &chain);
+        sync.add_peer(&second_peer, &chain);
+        sync.schedule(&chain);
+        assert_eq!(in_flight_from(&sync, &first), 2);
+        assert_eq!(in_flight_from(&sync, &second), 2);
+
+        sync.remove_peer(&first, &chain);
+        assert!(!sync.peers.contains_key(&first));
+        assert_eq!(in_flight_from(&sync, &second), 4);
+        assert_eq!(sync.peers[&second].blocks_in_flight, 4);
+    }
+
+    #[test]
+    fn nodes_are_not_synced_before_a_peer_answers() {
+        let mut chain = Blockchain::new();
+        let mut sync = Synchronizer::new();
+        assert!(!sync.is_synced(&chain));
+        let (peer, _queue) = peer::test_handle("127.0.0.1:6001".parse().unwrap());
+        sync.add_peer(&peer, &chain);
+        assert!(!sync.is_synced(&chain));
+        sync.on_headers(&peer, &[], &mut chain).unwrap();
+        assert!(sync.is_synced(&chain));
+    }
+}
@@ -1,9 +1,9 @@
-use super::message::Message;
+use super::message::{Message, PROTOCOL_VERSION};
 use super::peer;
 use crate::network::server::Handle as ServerHandle;
 use crossbeam::channel;
 use log::{debug, warn};
-use std::sync::{Arc, MutexGuard};
+use std::sync::{Arc, RwLock};
 use crate::blockchain::Blockchain;
 use crate::txgenerator::TxMempool;
 use std::sync::Mutex;
@@ -11,131 +11,504 @@ use std::thread;
 use crate::crypto::hash::{H256, Hashable, H160};
 use crate::block::Block;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::collections::{HashMap, VecDeque, HashSet};
+use std::collections::{BTreeMap, HashMap, VecDeque, HashSet};
 use crate::transaction::SignedTransaction;
 use crate::transaction;
 use std::intrinsics::transmute;
 use std::borrow::BorrowMut;
 use crate::bloomfilter::lib::BloomFilter;
+use super::sync::{self, Synchronizer};
+use super::compact::{CompactBlock, PartialBlock};
+use super::spv::MerkleBlock;
+use crate::blockchain::HeaderError;
+use crate::metrics;
+use crate::state::{self, AccountProof, AccountStates, StateCache};
+use serde::Serialize;
+use std::time;
 
+/// How long a compact block waits for its missing transactions
+const PARTIAL_BLOCK_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// Shared state is locked per message, only for what the handler needs. Locks are always taken in
+/// this order: blockchain, tx_pool, curr_state, witness_map, block_states, init_state, orphanBuf,
+/// sync, orphan_txs, partial_blocks, then the wallet's keystore and balances.
 #[derive(Clone)]
 pub struct Context {
     bloom_filter: BloomFilter,
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
+    blockchain: Arc<RwLock<Blockchain>>,
     orphanBuf: Arc<Mutex<OrphanBuffer>>,
-    tx_pool: Arc<Mutex<TxMempool>>,
-    init_state: Arc<Mutex<HashMap<H160,(u32, u32)>>>, // <address, (nonce, balance)>
+    tx_pool: Arc<RwLock<TxMempool>>,
+    init_state: Arc<RwLock<HashMap<H160,(u32, u32)>>>, // <address, (nonce, balance)>
     address: H160,
-    curr_state: Arc<Mutex<HashMap<H160,(u32, u32)>>>,
+    curr_state: Arc<RwLock<HashMap<H160,(u32, u32)>>>,
     witness_map:Arc<Mutex<HashMap<H256,HashMap<H160,(u32,u32)>>>>,
+    /// States after recent blocks, to validate blocks that do not extend the tip
+    block_states: Arc<Mutex<StateCache>>,
+    sync: Arc<Mutex<Synchronizer>>,
+    orphan_txs: Arc<Mutex<OrphanTxPool>>,
+    /// Compact blocks waiting for a `BlockTxn` response, with the time they arrived
+    partial_blocks: Arc<Mutex<HashMap<H256, (PartialBlock, time::Instant)>>>,
+    /// Whether the node is a light node, holding only headers and the blocks filtered down to
+    /// the transactions of its addresses
+    light: bool,
 }
 
+/// Maximum number of blocks held in the orphan buffer
+const MAX_ORPHANS: usize = 256;
+/// Orphans older than this are dropped, their parent is unlikely to show up anymore
+const MAX_ORPHAN_AGE: time::Duration = time::Duration::from_secs(20 * 60);
+
+/// Blocks whose parent is not in the chain yet, or whose parent's state is not known yet, indexed
+/// by hash and by parent hash
 #[derive(Clone)]
 pub struct OrphanBuffer{
-    buf: HashMap<H256, Vec<Block>>,
+    orphans: HashMap<H256, (Block, time::Instant)>,
+    by_parent: HashMap<H256, Vec<H256>>,
 }
 
 impl OrphanBuffer{
     pub fn new() -> Self{
-        let new_buf = HashMap::new();
         OrphanBuffer {
-            buf : new_buf,
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Add a block whose parent is unknown. Expired orphans are dropped first, and the oldest
+    /// orphan is evicted if the buffer is full.
     pub fn addOrphan(&mut self, block: &Block){
-        let mut toAddList = Vec::new();
-        let mut curr_buf = &mut self.buf;
-        if curr_buf.contains_key(&block.head.parent_hash){
-            toAddList = curr_buf.get(&block.head.parent_hash).unwrap().clone();
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) {
+            return;
         }
-        toAddList.push(block.clone());
-        curr_buf.insert(block.head.parent_hash,toAddList.clone());
-    }
-    pub fn findChild(&mut self, curr_chain: &mut MutexGuard<Blockchain>, curr_state: &mut HashMap<H160,(u32,u32)>, current_pool: &mut MutexGuard<TxMempool>,
-                     witness_map: &mut MutexGuard<HashMap<H256,HashMap<H160,(u32,u32)>>>){
-        let mut curr_buf = &mut self.buf;
-        for key in curr_buf.clone().keys(){
-            if curr_chain.chain.contains_key(key){
-                for block in curr_buf.clone().get(key).unwrap() {
-                    // TODO: State is determined by block's parent
-                    //Check tx
-                    let mut flag = true;
-                    for tx in block.content.content.clone() {
-                        let public_hash: H256 = ring::digest::digest(&ring::digest::SHA256, &tx.public_key).into();
-                        let owner_add: H160 = public_hash.into();
-                        if !transaction::verify(&tx) {
-                            flag = false;
-                            println!("Signature is not verified");
-                        }
+        self.expire();
+        if self.orphans.len() >= MAX_ORPHANS {
+            let oldest = self
+                .orphans
+                .iter()
+                .min_by_key(|(_, (_, arrived))| *arrived)
+                .map(|(hash, _)| *hash)
+                .unwrap();
+            self.remove(&oldest);
+        }
+        self.orphans.insert(hash, (block.clone(), time::Instant::now()));
+        self.by_parent.entry(block.head.parent_hash).or_default().push(hash);
+    }
 
-                        // 2. Check balance : check balance is enough
-                        if curr_state.get(&owner_add).unwrap().1 < tx.transaction.value {
-                            flag = false;
-                            println!("No enough balance");
-                        }
+    /// Drop the orphans that have waited longer than `MAX_ORPHAN_AGE` for their parent.
+    pub fn expire(&mut self) {
+        let expired: Vec<H256> = self
+            .orphans
+            .iter()
+            .filter(|(_, (_, arrived))| arrived.elapsed() > MAX_ORPHAN_AGE)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
 
-                        // 3. Check double spend : check tx nonce = state owner nonce + 1
-                        if curr_state.get(&owner_add).unwrap().0 != tx.transaction.nonce - 1 {
-                            flag = false;
-                            println!("Mismatch account nonce");
-                        }
-                    }
-                    if flag {
-                        curr_chain.insert(block);
-                        if witness_map.contains_key(&block.hash()){
-                            *curr_state = witness_map.get(&block.hash()).unwrap().clone();
-                        }
-                        //Update witness_map
-                        witness_map.remove(&block.hash());
-                        for tx in block.content.content.clone() {
-                            current_pool.pop_tx(&tx);
-                        }
+    fn remove(&mut self, hash: &H256) -> Option<Block> {
+        let (block, _) = self.orphans.remove(hash)?;
+        let parent = block.head.parent_hash;
+        if let Some(siblings) = self.by_parent.get_mut(&parent) {
+            siblings.retain(|h| h != hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+        Some(block)
+    }
 
-                        //View current properties
-                        let snapshot = curr_state.clone();
-                        println!("Current state");
-                        for i in snapshot.keys(){
-                            println!("Peer address: {:?}, properties (nonce, balance) {:?}", i, snapshot.get(i).unwrap());
-                        }
-                        println!("---------------------");
-                        println!("Total chain length: {:?}", curr_chain.height()+1);
-                        println!("---------------------");
-                        println!("Longest chain blocks hash");
-                        println!("Blocks : {:?}", curr_chain.all_blocks_in_longest_chain());
-                        println!("---------------------");
+    /// Remove and return the orphans whose parent is `parent`, with the time they arrived
+    fn take_children(&mut self, parent: &H256) -> Vec<(Block, time::Instant)> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        children.iter().filter_map(|hash| self.orphans.remove(hash)).collect()
+    }
+
+    /// Parents of the orphans that are neither in the chain nor in the buffer
+    pub fn missing_parents(&self, chain: &Blockchain) -> Vec<H256> {
+        self.by_parent
+            .keys()
+            .filter(|parent| !chain.chain.contains_key(parent) && !self.orphans.contains_key(parent))
+            .cloned()
+            .collect()
+    }
 
+    /// Offer `connect` every orphan whose parent is in the chain, then their children, and so on
+    /// until no orphan can be connected. Held orphans stay in the buffer until the next call.
+    /// Returns the connected orphans.
+    pub fn connect_orphans<F>(&mut self, chain: &mut Blockchain, mut connect: F) -> Vec<Block>
+    where
+        F: FnMut(&mut Blockchain, &Block) -> Connection,
+    {
+        let mut connected = Vec::new();
+        let mut held = Vec::new();
+        let mut connectable: VecDeque<H256> = self
+            .by_parent
+            .keys()
+            .filter(|parent| chain.chain.contains_key(parent))
+            .cloned()
+            .collect();
+        while let Some(parent) = connectable.pop_front() {
+            for (block, arrived) in self.take_children(&parent) {
+                match connect(chain, &block) {
+                    Connection::Connected => {
+                        // its own orphans can be connected now
+                        connectable.push_back(block.hash());
+                        connected.push(block);
                     }
-                    //let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("").as_millis();
-                    //println!("Delay{:?}",now-block.head.timestamp);
+                    Connection::Invalid => {}
+                    Connection::Held => held.push((block, arrived)),
                 }
+            }
+        }
+        for (block, arrived) in held {
+            let hash = block.hash();
+            self.by_parent.entry(block.head.parent_hash).or_default().push(hash);
+            self.orphans.insert(hash, (block, arrived));
+        }
+        connected
+    }
+
+    /// Connect a block filtered by a light node, then the orphans waiting for it. Its
+    /// transactions were proven against its header, the rest of the block is never seen.
+    pub fn connect_filtered(&mut self, curr_chain: &mut Blockchain, block: Block) {
+        let mut connectable = vec![block];
+        while let Some(block) = connectable.pop() {
+            curr_chain.insert(&block);
+            connectable.extend(self.take_children(&block.hash()).into_iter().map(|(block, _)| block));
+        }
+    }
+}
+
+/// What became of a block offered to the chain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connection {
+    Connected,
+    /// The block is invalid and was dropped
+    Invalid,
+    /// The state of the block's parent is not known yet, the block waits for it
+    Held,
+}
+
+impl Default for OrphanBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-                //All valid children of current key are inserted
-                curr_buf.remove(&key);
+/// Maximum number of transactions held in the orphan transaction pool
+const MAX_ORPHAN_TXS: usize = 1000;
+/// Maximum number of orphan transactions held for a single sender
+const MAX_ORPHAN_TXS_PER_SENDER: usize = 16;
+const MAX_ORPHAN_TX_AGE: time::Duration = time::Duration::from_secs(10 * 60);
+
+/// Transactions whose nonce is ahead of the next nonce of their sender, held until the
+/// transactions filling the gap arrive
+pub struct OrphanTxPool {
+    txs: BTreeMap<(H160, u32), (SignedTransaction, time::Instant)>,
+}
+
+impl OrphanTxPool {
+    pub fn new() -> Self {
+        OrphanTxPool {
+            txs: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Hold a transaction until its predecessor arrives. Returns false if its sender already has
+    /// `MAX_ORPHAN_TXS_PER_SENDER` orphans. The oldest orphan is evicted if the pool is full.
+    pub fn insert(&mut self, tx: &SignedTransaction) -> bool {
+        let sender = tx.sender();
+        self.expire();
+        let held = self.txs.range((sender, 0)..=(sender, u32::MAX)).count();
+        if held >= MAX_ORPHAN_TXS_PER_SENDER {
+            return false;
+        }
+        if self.txs.len() >= MAX_ORPHAN_TXS {
+            let oldest = *self
+                .txs
+                .iter()
+                .min_by_key(|(_, (_, arrived))| *arrived)
+                .map(|(key, _)| key)
+                .unwrap();
+            self.txs.remove(&oldest);
+        }
+        self.txs.insert((sender, tx.transaction.nonce), (tx.clone(), time::Instant::now()));
+        true
+    }
+
+    /// Drop the orphans that have waited longer than `MAX_ORPHAN_TX_AGE`.
+    pub fn expire(&mut self) {
+        self.txs.retain(|_, (_, arrived)| arrived.elapsed() <= MAX_ORPHAN_TX_AGE);
+    }
+
+    /// Remove and return, in nonce order, the orphans that directly follow the next nonce of their
+    /// sender given by `next_nonce`, as long as the balance of their sender given by `balance`
+    /// covers them. Orphans whose nonce is already used are dropped, the first one the balance
+    /// does not cover keeps waiting with the ones after it.
+    pub fn promote<N, B>(&mut self, next_nonce: N, balance: B) -> Vec<SignedTransaction>
+    where
+        N: Fn(&H160) -> u32,
+        B: Fn(&H160) -> u32,
+    {
+        let mut senders: Vec<H160> = self.txs.keys().map(|(sender, _)| *sender).collect();
+        senders.dedup();
+        let mut promoted = Vec::new();
+        for sender in senders {
+            let mut nonce = next_nonce(&sender);
+            let stale: Vec<(H160, u32)> = self
+                .txs
+                .range((sender, 0)..(sender, nonce))
+                .map(|(key, _)| *key)
+                .collect();
+            for key in stale {
+                self.txs.remove(&key);
             }
+            let mut available = balance(&sender);
+            while let Some((tx, _)) = self.txs.get(&(sender, nonce)) {
+                if tx.transaction.cost() > available {
+                    break;
+                }
+                available -= tx.transaction.cost();
+                promoted.push(self.txs.remove(&(sender, nonce)).unwrap().0);
+                nonce += 1;
+            }
+        }
+        promoted
+    }
+}
+
+impl Default for OrphanTxPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The nonce the next transaction of `address` must carry, counting its pending transactions
+pub fn next_nonce(pool: &TxMempool, state: &HashMap<H160, (u32, u32)>, address: &H160) -> u32 {
+    let confirmed = state.get(address).map(|(nonce, _)| *nonce).unwrap_or(0);
+    pool.pending_nonce(address).unwrap_or(0).max(confirmed) + 1
+}
+
+/// The confirmed balance of `address` that its pending transactions do not spend yet
+fn available_balance(pool: &TxMempool, state: &HashMap<H160, (u32, u32)>, address: &H160) -> u32 {
+    let (_, confirmed) = state.get(address).cloned().unwrap_or(state::DEFAULT_ACCOUNT);
+    confirmed.saturating_sub(pool.pending_spend(address))
+}
+
+/// Why a transaction was not added to the mempool
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum TxRejection {
+    AlreadyKnown,
+    InvalidSignature,
+    /// The nonce was already used by a confirmed or pending transaction of the sender
+    StaleNonce { expected: u32, nonce: u32 },
+    /// The sender has too many transactions waiting for their predecessor
+    TooManyOrphans,
+    /// The sender's balance, less its pending spends, does not cover the value and fee
+    InsufficientFunds { balance: u32, cost: u32 },
+}
+
+impl std::fmt::Display for TxRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TxRejection::AlreadyKnown => write!(f, "transaction already in the mempool"),
+            TxRejection::InvalidSignature => write!(f, "signature is not verified"),
+            TxRejection::StaleNonce { expected, nonce } => {
+                write!(f, "mismatch account nonce: expected {}, got {}", expected, nonce)
+            }
+            TxRejection::TooManyOrphans => write!(f, "too many orphan transactions from the sender"),
+            TxRejection::InsufficientFunds { balance, cost } => {
+                write!(f, "no enough balance: {} available, {} needed", balance, cost)
+            }
+        }
+    }
+}
+
+/// Where an accepted transaction went
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TxAcceptance {
+    Mempool,
+    /// Held in the orphan pool until the transactions before it arrive
+    Orphan,
+}
+
+/// Validate a transaction against the confirmed and pending state of its sender, then add it to
+/// the mempool, or to the orphan pool if its nonce is ahead. This is the single validation path
+/// for transactions from peers and from the API.
+pub fn accept_transaction(
+    tx: &SignedTransaction,
+    pool: &mut TxMempool,
+    state: &HashMap<H160, (u32, u32)>,
+    orphan_txs: &mut OrphanTxPool,
+) -> Result<TxAcceptance, TxRejection> {
+    if pool.map.contains_key(&tx.hash()) {
+        return Err(TxRejection::AlreadyKnown);
+    }
+    // 1. Check signature : transaction.verify() true/false
+    if !transaction::verify(tx) {
+        return Err(TxRejection::InvalidSignature);
+    }
+
+    // 2. Check balance : the confirmed balance must cover the pending spends and this transaction
+    let balance = available_balance(pool, state, &tx.sender());
+    if balance < tx.transaction.cost() {
+        return Err(TxRejection::InsufficientFunds { balance, cost: tx.transaction.cost() });
+    }
+
+    // 3. Check double spend : tx nonce must follow the sender's last confirmed or pending nonce,
+    // later nonces wait in the orphan pool
+    let expected = next_nonce(pool, state, &tx.sender());
+    if tx.transaction.nonce < expected {
+        return Err(TxRejection::StaleNonce { expected, nonce: tx.transaction.nonce });
+    }
+    if tx.transaction.nonce > expected {
+        return if orphan_txs.insert(tx) {
+            Ok(TxAcceptance::Orphan)
+        } else {
+            Err(TxRejection::TooManyOrphans)
+        };
+    }
+    pool.push_tx(tx);
+    Ok(TxAcceptance::Mempool)
+}
+
+/// Move the orphan transactions whose predecessors are now pending or confirmed, and that the
+/// balance of their sender covers, into the mempool. Returns the hashes of the promoted
+/// transactions.
+pub fn promote_orphan_txs(orphan_txs: &mut OrphanTxPool, pool: &mut TxMempool, state: &HashMap<H160, (u32, u32)>) -> Vec<H256> {
+    let mut promoted = Vec::new();
+    loop {
+        let txs = orphan_txs.promote(
+            |address| next_nonce(pool, state, address),
+            |address| available_balance(pool, state, address),
+        );
+        if txs.is_empty() {
+            return promoted;
+        }
+        for tx in txs {
+            pool.push_tx(&tx);
+            promoted.push(tx.hash());
         }
     }
 }
+
+/// The state after a block of the chain: cached, the state of the tip, or witnessed by an
+/// archival node
+fn known_state(
+    chain: &Blockchain,
+    curr_state: &AccountStates,
+    witness_map: &HashMap<H256, AccountStates>,
+    block_states: &StateCache,
+    hash: &H256,
+) -> Option<AccountStates> {
+    if let Some(state) = block_states.get(hash) {
+        return Some(state.clone());
+    }
+    let state_root = chain.chain.get(hash)?.0.head.state_root;
+    if *hash == chain.tip() && state::state_root(curr_state) == state_root {
+        return Some(curr_state.clone());
+    }
+    witness_map
+        .get(hash)
+        .filter(|state| state::state_root(state) == state_root)
+        .cloned()
+}
+
+/// Validate a block whose parent is in the chain against the state of its parent, then insert it
+/// and drop its transactions from the mempool. The block is held while the state of its parent
+/// is not known.
+fn connect_block(
+    chain: &mut Blockchain,
+    pool: &mut TxMempool,
+    curr_state: &mut AccountStates,
+    witness_map: &mut HashMap<H256, AccountStates>,
+    block_states: &mut StateCache,
+    block: &Block,
+) -> Connection {
+    let parent_state = match known_state(chain, curr_state, witness_map, block_states, &block.head.parent_hash) {
+        Some(state) => state,
+        None => {
+            debug!("Holding block {} until the state of its parent is known", block.hash());
+            return Connection::Held;
+        }
+    };
+    let state = match state::state_after(&parent_state, block) {
+        Ok(state) => state,
+        Err(e) => {
+            warn!("Block {} invalid: {}", block.hash(), e);
+            return Connection::Invalid;
+        }
+    };
+    chain.insert(block);
+    witness_map.remove(&block.hash());
+    if chain.tip() == block.hash() {
+        *curr_state = state.clone();
+    }
+    block_states.insert(block.hash(), state);
+    for tx in &block.content.content {
+        pool.pop_tx(tx);
+    }
+    Connection::Connected
+}
+
+/// The state after a block, known if it is the tip and `curr_state` matches its state root. Only
+/// the state of the tip is kept, archival nodes have the older ones.
+pub fn state_at<'a>(chain: &Blockchain, curr_state: &'a AccountStates, block: &H256) -> Option<&'a AccountStates> {
+    if *block != chain.tip() || state::state_root(curr_state) != chain.chain.get(block).unwrap().0.head.state_root {
+        return None;
+    }
+    Some(curr_state)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn new(
     bloom_filter: BloomFilter,
     num_worker: usize,
     msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>,
+    blockchain: &Arc<RwLock<Blockchain>>,
     orphanBuf: &Arc<Mutex<OrphanBuffer>>,
-    tx_pool: &Arc<Mutex<TxMempool>>,
-    init_state: &Arc<Mutex<HashMap<H160, (u32, u32)>>>,
+    tx_pool: &Arc<RwLock<TxMempool>>,
+    init_state: &Arc<RwLock<HashMap<H160, (u32, u32)>>>,
     address: H160,
-    curr_state: &Arc<Mutex<HashMap<H160,(u32,u32)>>>,
+    curr_state: &Arc<RwLock<HashMap<H160,(u32,u32)>>>,
     witness_map: &Arc<Mutex<HashMap<H256,HashMap<H160,(u32,u32)>>>>,
+    block_states: &Arc<Mutex<StateCache>>,
+    sync: &Arc<Mutex<Synchronizer>>,
+    orphan_txs: &Arc<Mutex<OrphanTxPool>>,
 ) -> Context {
     let blockchain = blockchain.clone();
     let mempool_buf = tx_pool.clone();
     let init_state = init_state.clone();
     let mut curr_state = curr_state.clone();
     let mut witness_map = witness_map.clone();
+    let light = sync.lock().unwrap().is_light();
     Context {
         bloom_filter: bloom_filter,
         msg_chan: msg_src,
@@ -147,46 +520,101 @@ pub fn new(
         init_state: init_state,
         address: address,
         curr_state: curr_state,
-        witness_map: witness_map
+        witness_map,
+        block_states: block_states.clone(),
+        sync: sync.clone(),
+        orphan_txs: orphan_txs.clone(),
+        partial_blocks: Arc::new(Mutex::new(HashMap::new())),
+        light,
     }
 }
 
 impl Context {
-    pub fn start(self) {
+    /// Start the worker threads. They exit once the P2P server has stopped and every received
+    /// message has been handled.
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
         let num_worker = self.num_worker;
-        for i in 0..num_worker {
-            let mut cloned = self.clone();
-            thread::spawn(move || {
-                cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
-        }
+        (0..num_worker)
+            .map(|i| {
+                let mut cloned = self.clone();
+                thread::Builder::new()
+                    .name(format!("worker-{}", i))
+                    .spawn(move || {
+                        cloned.worker_loop();
+                        debug!("Worker thread {} exited", i);
+                    })
+                    .unwrap()
+            })
+            .collect()
     }
 
     fn worker_loop(&mut self) {
+        // messages produced by this worker itself, such as blocks rebuilt from compact blocks
+        let mut local_queue: VecDeque<(Message, peer::Handle)> = VecDeque::new();
         loop {
-            let msg = self.msg_chan.recv().unwrap();
-            let (msg, peer) = msg;
+            let (msg, peer) = match local_queue.pop_front() {
+                Some(queued) => queued,
+                None => {
+                    let (bytes, peer) = match self.msg_chan.recv() {
+                        Ok(received) => received,
+                        // the server stopped
+                        Err(_) => return,
+                    };
+                    let msg: Message = match bincode::deserialize(&bytes) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            warn!("Disconnecting peer {} sending an undecodable message: {}", peer.addr(), e);
+                            self.server.drop_peer(peer.addr());
+                            continue;
+                        }
+                    };
+                    metrics::global().message_received(msg.kind(), bytes.len() + std::mem::size_of::<u32>());
+                    (msg, peer)
+                }
+            };
             let mut peer_vec = Vec::new();
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            let mut current_chain = self.blockchain.lock().unwrap();
-            let current_map = &current_chain.chain.clone();
-            //println!("len:{:?}",current_chain.height());
-            let mut current_pool = self.tx_pool.lock().unwrap();
-            let mut curr_state = self.curr_state.lock().unwrap();
-            let mut witness_map = self.witness_map.lock().unwrap();
-            let mut init_state = self.init_state.lock().unwrap();
-            let mut bloom_filter = self.bloom_filter.clone();
             match msg {
+                // light nodes do not validate transactions, and their filtered blocks must not be
+                // served as full ones
+                Message::NewState(_)
+                | Message::NewTransactionHashes(_)
+                | Message::Transactions(_)
+                | Message::GetBlocks(_)
+                | Message::GetBlockTxn(..)
+                | Message::BlockTxn(..)
+                | Message::Blocks(_)
+                | Message::GetMerkleBlocks(..)
+                    if self.light => {}
+                Message::MerkleBlocks(_) if !self.light => {}
+                Message::Version(version) => {
+                    debug!("Version: {}", version);
+                    if version != PROTOCOL_VERSION {
+                        // the variants after Version may be tagged differently, nothing more from
+                        // the peer can be decoded
+                        warn!("Disconnecting peer {} speaking protocol version {}", peer.addr(), version);
+                        self.server.drop_peer(peer.addr());
+                    } else if peer.version().is_some() {
+                        debug!("Ignoring repeated version from peer {}", peer.addr());
+                    } else {
+                        peer.set_version(version);
+                        // the handshake is complete, start syncing headers from this peer
+                        let current_chain = self.blockchain.read().unwrap();
+                        self.sync.lock().unwrap().add_peer(&peer, &current_chain);
+                    }
+                }
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
+                    peer.write(Message::Pong(nonce));
                 }
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
+                    peer.pong(&nonce);
                 }
                 Message::NewPeer(newPeer) => {
                     // Receive init message by a new coming peer
                     // println!("Got new peer");
+                    let mut init_state = self.init_state.write().unwrap();
+                    let mut bloom_filter = self.bloom_filter.clone();
                     if !init_state.contains_key(&newPeer){
                         init_state.insert(newPeer,(0,100));
                         bloom_filter.insert((H160::to_string(&newPeer.clone())+"0"+"100").as_str());
@@ -201,27 +629,47 @@ impl Context {
                 Message::NewState(Witness) => {
                     let block_hash = Witness.0;
                     let state = Witness.1;
-                    if block_hash.eq(&current_chain.tail){
+                    let current_chain = self.blockchain.read().unwrap();
+                    let mut current_pool = self.tx_pool.write().unwrap();
+                    let mut curr_state = self.curr_state.write().unwrap();
+                    let mut witness_map = self.witness_map.lock().unwrap();
+                    let known_root = current_chain.chain.get(&block_hash).map(|(block, _)| block.head.state_root);
+                    if known_root.is_some_and(|root| root != state::state_root(&state)) {
+                        warn!("State witness of block {} does not match its state root", block_hash);
+                        self.server.report_misbehavior(&peer, 20);
+                    } else if block_hash.eq(&current_chain.tail){
                         *curr_state = state;
+                        let promoted = promote_orphan_txs(&mut self.orphan_txs.lock().unwrap(), &mut current_pool, &curr_state);
+                        if !promoted.is_empty() {
+                            self.server.relay_transactions(promoted, None);
+                        }
                     }else {
                         witness_map.insert(block_hash, state);
                     }
-                    let snapshot = curr_state.clone();
-                    println!("Current state");
-                    for i in snapshot.keys(){
-                        println!("Peer address: {:?}, properties (nonce, balance) {:?}", i, snapshot.get(i).unwrap());
+                }
+                Message::GetAccountProof(address, block_hash) => {
+                    let current_chain = self.blockchain.read().unwrap();
+                    let curr_state = self.curr_state.read().unwrap();
+                    if let Some(state) = state_at(&current_chain, &curr_state, &block_hash) {
+                        peer.write(Message::AccountProof(block_hash, AccountProof::new(state, &address)));
+                    }
+                }
+                Message::AccountProof(block_hash, proof) => {
+                    let current_chain = self.blockchain.read().unwrap();
+                    match current_chain.headers.get(&block_hash) {
+                        Some((header, _)) if proof.verify(&header.state_root) => {
+                            debug!("Account {} at block {}: (nonce, balance) {:?}", proof.address.to_hex(), block_hash, proof.account);
+                        }
+                        Some(_) => {
+                            warn!("Account proof for block {} does not match its state root", block_hash);
+                            self.server.report_misbehavior(&peer, 20);
+                        }
+                        None => debug!("Account proof for unknown block {}", block_hash),
                     }
-                    println!("---------------------");
-                    println!("Total chain length: {:?}", current_chain.height()+1);
-                    println!("---------------------");
-                    println!("Longest chain blocks hash");
-                    println!("Blocks : {:?}", current_chain.all_blocks_in_longest_chain());
-                    println!("---------------------");
-                    std::mem::drop(current_chain);
-                    std::mem::drop(curr_state);
                 }
                 Message::Ack(newPeerList) => {
                     // Get new peers by request
+                    let mut init_state = self.init_state.write().unwrap();
                     for peer in newPeerList{
                         if !init_state.contains_key(&peer){
                             init_state.insert(peer,(0,100));
@@ -234,10 +682,11 @@ impl Context {
                 }
                 Message::NewTransactionHashes(NewTransactionHashes) =>{
                     //debug!("NewTransactionHashes");
-                    let mut current_tx_map = current_pool.map.clone();
+                    peer.mark_known(&NewTransactionHashes);
+                    let current_pool = self.tx_pool.read().unwrap();
                     let mut new_tx = Vec::new();
                     for tx in NewTransactionHashes{
-                        if !current_tx_map.contains_key(&tx){
+                        if !current_pool.map.contains_key(&tx){
                             new_tx.push(tx);
                         }
                     }
@@ -250,11 +699,11 @@ impl Context {
                 }
                 Message::GetTransactions(GetTransactions) =>{
                     //debug!("GetTransactions");
-                    let mut current_tx_map = current_pool.map.clone();
+                    let current_pool = self.tx_pool.read().unwrap();
                     let mut tx_vec = Vec::new();
                     for tx in GetTransactions{
-                        if current_tx_map.contains_key(&tx){
-                            tx_vec.push(current_pool.map.get(&tx).unwrap().clone());
+                        if let Some(signed) = current_pool.map.get(&tx){
+                            tx_vec.push(signed.clone());
                         }
                     }
                     // Offer exact transactions
@@ -268,47 +717,38 @@ impl Context {
                     //debug!("Transactions");
                     let mut verified_tx = Vec::new();
                     //println!("Receive new tx : {:?} ", Transactions.len());
+                    let received: Vec<H256> = Transactions.iter().map(|tx| tx.hash()).collect();
+                    peer.mark_known(&received);
+                    let mut current_pool = self.tx_pool.write().unwrap();
+                    let curr_state = self.curr_state.read().unwrap();
+                    let mut orphan_txs = self.orphan_txs.lock().unwrap();
                     for tx in Transactions{
-                        // TODO: Transaction check
-                        // 1. Check signature : transaction.verify() true/false
-                        let mut flag = true;
-                        let public_hash: H256 = ring::digest::digest(&ring::digest::SHA256, &tx.public_key).into();
-                        let owner_add: H160 = public_hash.into();
-                        let current_state = self.curr_state.lock().unwrap();
-                        if !transaction::verify(&tx) {
-                            flag = false;
-                            println!("Signature is not verified");
-                        }
-
-                        // 2. Check balance : check balance is enough
-                        // if current_state.get(&owner_add).unwrap().1 < tx.transaction.value{
-                        //     flag = false;
-                        //     println!("No enough balance");
-                        // }
-
-                        // 3. Check double spend : check tx nonce = state owner nonce + 1
-                        // if current_state.get(&owner_add).unwrap().0 != tx.transaction.nonce - 1{
-                        //     flag = false;
-                        //     println!("Mismatch account nonce");
-                        // }
-
-                        if flag{
-                            current_pool.push_tx(&tx);
-                            verified_tx.push(tx.hash());
-                        }else{
-                            println!("Transaction invalid");
+                        match accept_transaction(&tx, &mut current_pool, &curr_state, &mut orphan_txs) {
+                            Ok(TxAcceptance::Mempool) => verified_tx.push(tx.hash()),
+                            Ok(TxAcceptance::Orphan) | Err(TxRejection::AlreadyKnown) => {}
+                            Err(reason) => {
+                                println!("Transaction invalid: {}", reason);
+                                if reason == TxRejection::InvalidSignature {
+                                    self.server.report_misbehavior(&peer, 10);
+                                }
+                            }
                         }
-
                     }
+                    verified_tx.extend(promote_orphan_txs(&mut orphan_txs, &mut current_pool, &curr_state));
                     // Gossip the new transaction message
-                    if (&verified_tx).len() > 0{
-                        self.server.broadcast(Message::NewTransactionHashes(verified_tx));
+                    if !verified_tx.is_empty() {
+                        self.server.relay_transactions(verified_tx, Some(peer.addr()));
                     }
                     //println!("current transaction pool len {:?}", current_pool.map.len());
                 }
+                Message::NewBlockHashes(_) if self.light => {
+                    let current_chain = self.blockchain.read().unwrap();
+                    self.sync.lock().unwrap().request_headers(&peer, &current_chain);
+                }
                 Message::NewBlockHashes(NewBlockHashes) =>{
                     //debug!("NewBlockHashes");
                     let mut block_vec = Vec::new();
+                    let current_chain = self.blockchain.read().unwrap();
                     //println!("receiver chain height {:?}",current_chain.height());
                     for hash in NewBlockHashes.clone(){
                         if !current_chain.chain.contains_key(&hash) {
@@ -324,10 +764,10 @@ impl Context {
                     //debug!("GetBlocks");
                     //println!("Sender get request {:?}",GetBlocks.len());
                     let mut block_vec = Vec::new();
-                    for hash in GetBlocks.clone(){
-                        if current_map.contains_key(&hash){
-                            let newBlock = (*current_map).get(&hash).unwrap().0.clone();
-                            block_vec.push(newBlock.clone());
+                    let current_chain = self.blockchain.read().unwrap();
+                    for hash in GetBlocks{
+                        if let Some((block, _)) = current_chain.chain.get(&hash){
+                            block_vec.push(block.clone());
                             //println!("sent:{:?}",newBlock.hash());
                         }
                     }
@@ -336,100 +776,215 @@ impl Context {
                         peer.write(Message::Blocks(block_vec));
                     }
                 }
+                Message::GetHeaders(locator) => {
+                    let current_chain = self.blockchain.read().unwrap();
+                    let headers = current_chain.headers_after(&locator, sync::MAX_HEADERS);
+                    peer.write(Message::Headers(headers));
+                }
+                Message::Headers(headers) => {
+                    let mut current_chain = self.blockchain.write().unwrap();
+                    let mut sync = self.sync.lock().unwrap();
+                    if let Err(e) = sync.on_headers(&peer, &headers, &mut current_chain) {
+                        warn!("Invalid headers from peer {}: {}", peer.addr(), e);
+                        self.server.report_misbehavior(&peer, 20);
+                    }
+                }
+                Message::CompactBlock(compact) if self.light => {
+                    // only the header is kept, the filtered block is downloaded like the others
+                    let mut current_chain = self.blockchain.write().unwrap();
+                    let mut sync = self.sync.lock().unwrap();
+                    match sync.on_headers(&peer, &[compact.head], &mut current_chain) {
+                        Ok(()) => {}
+                        Err(HeaderError::UnknownParent) => sync.request_headers(&peer, &current_chain),
+                        Err(e) => {
+                            warn!("Invalid compact block from peer {}: {}", peer.addr(), e);
+                            self.server.report_misbehavior(&peer, 50);
+                        }
+                    }
+                }
+                Message::CompactBlock(compact) => {
+                    let hash = compact.hash();
+                    let current_chain = self.blockchain.read().unwrap();
+                    let current_pool = self.tx_pool.read().unwrap();
+                    match current_chain.validate_header(&compact.head) {
+                        Ok(()) | Err(HeaderError::UnknownParent) => {
+                            if !current_chain.chain.contains_key(&hash) {
+                                let partial = PartialBlock::new(&compact, &current_pool);
+                                let missing = partial.missing();
+                                if missing.is_empty() {
+                                    match partial.into_block() {
+                                        Some(block) => local_queue.push_back((Message::Blocks(vec![block]), peer.clone())),
+                                        None => peer.write(Message::GetBlocks(vec![hash])),
+                                    }
+                                } else {
+                                    peer.write(Message::GetBlockTxn(hash, missing));
+                                    let mut partial_blocks = self.partial_blocks.lock().unwrap();
+                                    partial_blocks.retain(|_, (_, arrived)| arrived.elapsed() < PARTIAL_BLOCK_TIMEOUT);
+                                    partial_blocks.insert(hash, (partial, time::Instant::now()));
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Invalid compact block from peer {}: {}", peer.addr(), e);
+                            self.server.report_misbehavior(&peer, 50);
+                        }
+                    }
+                }
+                Message::GetBlockTxn(hash, indexes) => {
+                    let current_chain = self.blockchain.read().unwrap();
+                    if let Some((block, _)) = current_chain.chain.get(&hash) {
+                        let txs = indexes
+                            .iter()
+                            .filter_map(|index| block.content.content.get(*index as usize))
+                            .cloned()
+                            .collect();
+                        peer.write(Message::BlockTxn(hash, txs));
+                    }
+                }
+                Message::BlockTxn(hash, txs) => {
+                    let partial = self.partial_blocks.lock().unwrap().remove(&hash);
+                    if let Some((mut partial, _)) = partial {
+                        let block = if partial.fill(txs) { partial.into_block() } else { None };
+                        match block {
+                            Some(block) => local_queue.push_back((Message::Blocks(vec![block]), peer.clone())),
+                            // fall back to the full block if the short IDs were ambiguous
+                            None => peer.write(Message::GetBlocks(vec![hash])),
+                        }
+                    }
+                }
+                Message::GetMerkleBlocks(hashes, addresses) => {
+                    let current_chain = self.blockchain.read().unwrap();
+                    let merkle_blocks: Vec<MerkleBlock> = hashes
+                        .iter()
+                        .filter_map(|hash| current_chain.chain.get(hash))
+                        .map(|(block, _)| MerkleBlock::new(block, &addresses))
+                        .collect();
+                    if !merkle_blocks.is_empty() {
+                        peer.write(Message::MerkleBlocks(merkle_blocks));
+                    }
+                }
+                Message::MerkleBlocks(merkle_blocks) => {
+                    let mut current_chain = self.blockchain.write().unwrap();
+                    let mut orphan_buffer = self.orphanBuf.lock().unwrap();
+                    let mut sync = self.sync.lock().unwrap();
+                    for merkle_block in merkle_blocks {
+                        let hash = merkle_block.hash();
+                        sync.on_block(&hash);
+                        if current_chain.chain.contains_key(&hash) || orphan_buffer.contains(&hash) {
+                            continue;
+                        }
+                        // the header was validated when it joined the header tree
+                        if !current_chain.headers.contains_key(&hash) {
+                            debug!("Merkle block {} with an unknown header", hash);
+                            continue;
+                        }
+                        if !merkle_block.verify() {
+                            warn!("Merkle block {} from peer {} does not match its Merkle root", hash, peer.addr());
+                            self.server.report_misbehavior(&peer, 50);
+                            continue;
+                        }
+                        let block = merkle_block.into_block();
+                        if current_chain.chain.contains_key(&block.head.parent_hash) {
+                            orphan_buffer.connect_filtered(&mut current_chain, block);
+                        } else {
+                            orphan_buffer.addOrphan(&block);
+                        }
+                    }
+                    metrics::global().orphan_blocks.set(orphan_buffer.len() as i64);
+                    sync.schedule(&current_chain);
+                }
                 Message::Blocks(Blocks)=>{
                     //debug!("Blocks");
                     //println!("get block!");
                     let mut verified_blocks = Vec::new();
+                    let mut current_chain = self.blockchain.write().unwrap();
+                    let mut current_pool = self.tx_pool.write().unwrap();
+                    let mut curr_state = self.curr_state.write().unwrap();
+                    let mut witness_map = self.witness_map.lock().unwrap();
+                    let mut block_states = self.block_states.lock().unwrap();
                     let mut orphan_buffer = self.orphanBuf.lock().unwrap();
+                    let mut sync = self.sync.lock().unwrap();
+                    let mut bloom_filter = self.bloom_filter.clone();
+                    // propagation delays are only meaningful for blocks relayed as they are mined
+                    let synced = sync.is_synced(&current_chain);
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
                     for block in Blocks{
-                        if !current_map.contains_key(&(block.hash())){
+                        sync.on_block(&block.hash());
+                        if !current_chain.chain.contains_key(&(block.hash())){
+                            metrics::global().blocks_received.inc();
+                            if synced {
+                                let delay = now.saturating_sub(block.head.timestamp) as f64 / 1000.0;
+                                metrics::global().block_propagation_seconds.observe(delay);
+                            }
                             let newBlock = block.clone();
-                            //PoW validity check
-                            if current_chain.diff.eq(&newBlock.head.difficulty)
-                                    && newBlock.hash().le(&newBlock.head.difficulty){
-                                verified_blocks.push(newBlock.hash());
-                                if current_map.contains_key(&newBlock.head.parent_hash) {
-
-                                    //Check transactions
-                                    let mut current_state = curr_state.clone();
+                            //PoW validity check, and the transactions must be the ones committed
+                            if current_chain.check_block(&newBlock) {
+                                if current_chain.chain.contains_key(&newBlock.head.parent_hash) {
+                                    // 4. Check the transfers against the bloom filter of known accounts
                                     let mut flag = true;
-                                    for tx in newBlock.content.content.clone() {
-                                        let public_hash: H256 = ring::digest::digest(&ring::digest::SHA256, &tx.public_key).into();
-                                        let owner_add: H160 = public_hash.into();
-                                        if !transaction::verify(&tx) {
-                                            flag = false;
-                                            println!("Signature is not verified");
-                                        }
-
-                                        // 2. Check balance : check balance is enough
-                                        if current_state.get(&owner_add).unwrap().1 < tx.transaction.value {
-                                            flag = false;
-                                            println!("No enough balance");
-                                        }
-
-                                        // 3. Check double spend : check tx nonce = state owner nonce + 1
-                                        if current_state.get(&owner_add).unwrap().0 != tx.transaction.nonce - 1 {
-                                            flag = false;
-                                            println!("Mismatch account nonce");
-                                        }
-
-                                        // 4. Replace 2 and 3
-                                        if !bloom_filter.maybe_present((H160::to_string(&tx.transaction.address.clone())+&(tx.transaction.nonce - 1).to_string()+&tx.transaction.self_balance.to_string()).as_str()) {
+                                    for tx in newBlock.content.content.iter() {
+                                        if !bloom_filter.maybe_present((H160::to_string(&tx.transaction.address.clone())+&tx.transaction.nonce.wrapping_sub(1).to_string()+&tx.transaction.self_balance.to_string()).as_str()) {
                                             flag = false;
                                             println!("Mismatch account nonce or value");
                                         }
                                     }
-                                    if flag {
-                                        current_chain.insert(&newBlock);
-                                        if witness_map.contains_key(&newBlock.hash()) {
-                                            *curr_state = witness_map.get(&newBlock.hash()).unwrap().clone();
-                                        }
-
-                                        //Update witness_map
-                                        witness_map.remove(&newBlock.hash());
-                                        
-                                        //Update Bloomfilter
-                                        for tx in newBlock.content.content.clone() {
-                                            bloom_filter.insert((H160::to_string(&tx.transaction.address.clone())+&tx.transaction.nonce.to_string()+&tx.transaction.self_balance.to_string()).as_str());
-                                            current_pool.pop_tx(&tx);
-                                        }
-                                        //View current properties
-                                        let snapshot = curr_state.clone();
-                                        println!("Current state");
-                                        for i in snapshot.keys(){
-                                            println!("Peer address: {:?}, properties (nonce, balance) {:?}", i, snapshot.get(i).unwrap());
+                                    let connection = if flag {
+                                        connect_block(&mut current_chain, &mut current_pool, &mut curr_state, &mut witness_map, &mut block_states, &newBlock)
+                                    } else {
+                                        Connection::Invalid
+                                    };
+                                    match connection {
+                                        Connection::Connected => {
+                                            verified_blocks.push(newBlock.clone());
+                                            //Update Bloomfilter
+                                            for tx in newBlock.content.content.iter() {
+                                                bloom_filter.insert((H160::to_string(&tx.transaction.address.clone())+&tx.transaction.nonce.to_string()+&tx.transaction.self_balance.to_string()).as_str());
+                                            }
                                         }
-                                        println!("---------------------");
-                                        println!("Total chain length: {:?}", current_chain.height()+1);
-                                        println!("---------------------");
-                                        println!("Longest chain blocks hash");
-                                        println!("Blocks : {:?}", current_chain.all_blocks_in_longest_chain());
-                                        println!("---------------------");
-                                    }else{
-                                        println!("Block invalid");
+                                        Connection::Invalid => sync.on_invalid_block(&newBlock.hash(), &mut current_chain),
+                                        Connection::Held => orphan_buffer.addOrphan(&newBlock),
                                     }
-                                    //let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("").as_millis();
-                                    //println!("Delay{:?}",now-block.head.timestamp);
                                 }else{
                                     // Add Orphan to buffer
-                                    orphan_buffer.addOrphan(&newBlock);
-                                    println!("Found Orphan!");
+                                    if !orphan_buffer.contains(&newBlock.hash()) {
+                                        orphan_buffer.addOrphan(&newBlock);
+                                        println!("Found Orphan!");
+                                        // ask the sender for the missing parent, and for the
+                                        // headers of any deeper gap
+                                        let parent = newBlock.head.parent_hash;
+                                        if !orphan_buffer.contains(&parent) {
+                                            sync.request_block(&peer, &parent);
+                                        }
+                                        if !current_chain.headers.contains_key(&parent) {
+                                            sync.request_headers(&peer, &current_chain);
+                                        }
+                                    }
                                 }
+                            }else{
+                                // Block does not meet the proof of work target, or does not match its Merkle root
+                                self.server.report_misbehavior(&peer, 50);
                             }
                         }
                     }
-                    let mut orphan_vec = Vec::new();
-                    orphan_buffer.findChild(&mut current_chain, &mut curr_state, &mut current_pool, &mut witness_map);
-                    let curr_orphan_buf = orphan_buffer.buf.clone();
-                    for key in curr_orphan_buf.keys(){
-                        orphan_vec.push(*key);
-                    }
-                    //println!("orphan_vector:{:?}",orphan_vec.len());
-                    if orphan_vec.len() > 0 {
-                        peer.write(Message::GetBlocks(orphan_vec));
+                    verified_blocks.extend(orphan_buffer.connect_orphans(&mut current_chain, |chain, block| {
+                        let connection = connect_block(chain, &mut current_pool, &mut curr_state, &mut witness_map, &mut block_states, block);
+                        if connection == Connection::Invalid {
+                            sync.on_invalid_block(&block.hash(), chain);
+                        }
+                        connection
+                    }));
+                    metrics::global().orphan_blocks.set(orphan_buffer.len() as i64);
+                    sync.schedule(&current_chain);
+                    // transactions confirmed by the new blocks may unlock orphan transactions
+                    let promoted = promote_orphan_txs(&mut self.orphan_txs.lock().unwrap(), &mut current_pool, &curr_state);
+                    if !promoted.is_empty() {
+                        self.server.relay_transactions(promoted, None);
                     }
-                    if verified_blocks.len() > 0{
-                        self.server.broadcast(Message::NewBlockHashes(verified_blocks));
+                    // do not relay the old blocks fetched during the initial download
+                    if sync.is_synced(&current_chain) {
+                        for block in verified_blocks {
+                            self.server.broadcast(Message::CompactBlock(CompactBlock::new(&block)));
+                        }
                     }
                 }
 
@@ -437,3 +992,202 @@ impl Context {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_mined_block;
+    use crate::crypto::key_pair;
+    use crate::transaction::Transaction;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn transfer(key: &Ed25519KeyPair, nonce: u32, value: u32) -> SignedTransaction {
+        let tx = Transaction {
+            self_balance: 0,
+            address: key_pair::address(&key_pair::random()),
+            value,
+            nonce,
+            fee: 1,
+        };
+        SignedTransaction {
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: transaction::sign(&tx, key).as_ref().to_vec(),
+            transaction: tx,
+        }
+    }
+
+    #[test]
+    fn pending_spends_count_against_the_balance() {
+        let key = key_pair::random();
+        let mut pool = TxMempool::new();
+        let mut orphan_txs = OrphanTxPool::new();
+        let state = HashMap::new();
+        let first = transfer(&key, 1, 59);
+        assert_eq!(accept_transaction(&first, &mut pool, &state, &mut orphan_txs), Ok(TxAcceptance::Mempool));
+        assert_eq!(
+            accept_transaction(&transfer(&key, 2, 40), &mut pool, &state, &mut orphan_txs),
+            Err(TxRejection::InsufficientFunds { balance: 40, cost: 41 })
+        );
+        assert_eq!(accept_transaction(&transfer(&key, 2, 39), &mut pool, &state, &mut orphan_txs), Ok(TxAcceptance::Mempool));
+
+        let spent: AccountStates = vec![(key_pair::address(&key), (0, 5))].into_iter().collect();
+        assert_eq!(
+            accept_transaction(&transfer(&key, 1, 10), &mut TxMempool::new(), &spent, &mut orphan_txs),
+            Err(TxRejection::InsufficientFunds { balance: 5, cost: 11 })
+        );
+    }
+
+    #[test]
+    fn held_orphans_wait_for_the_next_pass() {
+        let mut chain = Blockchain::new();
+        let easy: H256 = [255u8; 32].into();
+        let first = generate_mined_block(&chain.tip(), &easy);
+        let second = generate_mined_block(&first.hash(), &easy);
+        let fork = generate_mined_block(&chain.tip(), &easy);
+        let mut orphans = OrphanBuffer::new();
+        for block in [&second, &first, &fork] {
+            orphans.addOrphan(block);
+        }
+        let unknown = generate_mined_block(&H256::default(), &easy);
+        orphans.addOrphan(&generate_mined_block(&unknown.hash(), &easy));
+        assert_eq!(orphans.missing_parents(&chain), vec![unknown.hash()]);
+
+        let connect = |hold: H256| {
+            move |chain: &mut Blockchain, block: &Block| {
+                if block.hash() == hold {
+                    return Connection::Held;
+                }
+                chain.insert(block);
+                Connection::Connected
+            }
+        };
+        let connected = orphans.connect_orphans(&mut chain, connect(fork.hash()));
+        assert_eq!(connected.iter().map(|block| block.hash()).collect::<Vec<_>>(), vec![first.hash(), second.hash()]);
+        assert!(orphans.contains(&fork.hash()));
+        assert_eq!(orphans.len(), 2);
+
+        let connected = orphans.connect_orphans(&mut chain, connect(H256::default()));
+        assert_eq!(connected.len(), 1);
+        assert!(chain.chain.contains_key(&fork.hash()));
+        assert_eq!(orphans.len(), 1);
+    }
+
+    #[test]
+    fn blocks_are_checked_against_the_state_of_their_parent() {
+        let mut chain = Blockchain::new();
+        let mut pool = TxMempool::new();
+        let mut curr_state = HashMap::new();
+        let mut witness_map = HashMap::new();
+        let mut block_states = StateCache::new(state::MAX_CACHED_STATES);
+        block_states.insert(chain.tip(), HashMap::new());
+        let child = |parent: &H256, state_root: H256| {
+            let mut block = generate_mined_block(parent, &[255u8; 32].into());
+            block.content.content.clear();
+            block.head.state_root = state_root;
+            block
+        };
+        let root = state::state_root(&HashMap::new());
+        let genesis = chain.tip();
+        let first = child(&genesis, root);
+        let second = child(&first.hash(), root);
+        for block in [&first, &second] {
+            let connection = connect_block(&mut chain, &mut pool, &mut curr_state, &mut witness_map, &mut block_states, block);
+            assert_eq!(connection, Connection::Connected);
+        }
+        assert_eq!(chain.tip(), second.hash());
+
+        let forged = child(&genesis, H256::default());
+        let fork = child(&first.hash(), root);
+        assert_eq!(connect_block(&mut chain, &mut pool, &mut curr_state, &mut witness_map, &mut block_states, &forged), Connection::Invalid);
+        assert_eq!(connect_block(&mut chain, &mut pool, &mut curr_state, &mut witness_map, &mut block_states, &fork), Connection::Connected);
+
+        let mut forgotten = StateCache::new(state::MAX_CACHED_STATES);
+        let late = child(&first.hash(), root);
+        assert_eq!(connect_block(&mut chain, &mut pool, &mut curr_state, &mut witness_map, &mut forgotten, &late), Connection::Held);
+        assert!(!chain.chain.contains_key(&late.hash()));
+        witness_map.insert(first.hash(), HashMap::new());
+        assert_eq!(connect_block(&mut chain, &mut pool, &mut curr_state, &mut witness_map, &mut forgotten, &late), Connection::Connected);
+    }
+
+    #[test]
+    fn orphan_txs_are_bounded_per_sender_and_in_total() {
+        let mut orphans = OrphanTxPool::new();
+        let key = key_pair::random();
+        for nonce in 0..MAX_ORPHAN_TXS_PER_SENDER as u32 {
+            assert!(orphans.insert(&transfer(&key, nonce + 2, 1)));
+        }
+        assert!(!orphans.insert(&transfer(&key, 100, 1)));
+        assert_eq!(orphans.len(), MAX_ORPHAN_TXS_PER_SENDER);
+
+        let oldest = orphans.txs.keys().next().cloned().unwrap();
+        orphans.txs.get_mut(&oldest).unwrap().1 -= time::Duration::from_secs(1);
+        while orphans.len() < MAX_ORPHAN_TXS {
+            let key = key_pair::random();
+            for nonce in 0..MAX_ORPHAN_TXS_PER_SENDER.min(MAX_ORPHAN_TXS - orphans.len()) as u32 {
+                assert!(orphans.insert(&transfer(&key, nonce + 2, 1)));
+            }
+        }
+        assert!(orphans.insert(&transfer(&key_pair::random(), 2, 1)));
+        assert_eq!(orphans.len(), MAX_ORPHAN_TXS);
+        assert!(!orphans.txs.contains_key(&oldest));
+    }
+
+    #[test]
+    fn orphan_txs_expire() {
+        let mut orphans = OrphanTxPool::new();
+        let key = key_pair::random();
+        orphans.insert(&transfer(&key, 2, 1));
+        orphans.insert(&transfer(&key, 3, 1));
+        let arrived = orphans.txs.get_mut(&(key_pair::address(&key), 2)).unwrap();
+        arrived.1 -= MAX_ORPHAN_TX_AGE + time::Duration::from_secs(1);
+        orphans.expire();
+        assert_eq!(orphans.txs.keys().map(|(_, nonce)| *nonce).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn orphan_txs_promote_in_nonce_order_up_to_a_gap() {
+        let mut orphans = OrphanTxPool::new();
+        let key = key_pair::random();
+        let sender = key_pair::address(&key);
+        for nonce in [6, 4, 1, 3] {
+            orphans.insert(&transfer(&key, nonce, 1));
+        }
+        let other = key_pair::random();
+        orphans.insert(&transfer(&other, 2, 1));
+
+        let promoted = orphans.promote(|address| if *address == sender { 3 } else { 1 }, |_| u32::MAX);
+        let nonces: Vec<u32> = promoted.iter().map(|tx| tx.transaction.nonce).collect();
+        assert_eq!(nonces, vec![3, 4]);
+        assert!(promoted.iter().all(|tx| tx.sender() == sender));
+        // the stale orphan is dropped, the ones after a gap keep waiting
+        assert_eq!(orphans.len(), 2);
+        assert!(orphans.txs.contains_key(&(sender, 6)));
+
+        let promoted = orphans.promote(|address| if *address == sender { 5 } else { 2 }, |_| u32::MAX);
+        assert_eq!(promoted.len(), 1);
+        assert_eq!(promoted[0].sender(), key_pair::address(&other));
+        assert_eq!(orphans.len(), 1);
+    }
+
+    #[test]
+    fn promoted_orphan_txs_must_fit_the_balance() {
+        let key = key_pair::random();
+        let mut pool = TxMempool::new();
+        let mut orphan_txs = OrphanTxPool::new();
+        let state = HashMap::new();
+        for (nonce, value) in [(2, 45), (3, 45), (4, 1)] {
+            let orphan = transfer(&key, nonce, value);
+            assert_eq!(accept_transaction(&orphan, &mut pool, &state, &mut orphan_txs), Ok(TxAcceptance::Orphan));
+        }
+        let first = transfer(&key, 1, 9);
+        assert_eq!(accept_transaction(&first, &mut pool, &state, &mut orphan_txs), Ok(TxAcceptance::Mempool));
+
+        // 10 of the default balance is pending, the 90 left cover only one of the two 46 spends:
+        // the second one waits, and so do the later nonces behind it
+        let promoted = promote_orphan_txs(&mut orphan_txs, &mut pool, &state);
+        assert_eq!(promoted.len(), 1);
+        assert_eq!(pool.pending_nonce(&key_pair::address(&key)), Some(2));
+        assert_eq!(orphan_txs.len(), 2);
+        assert_eq!(pool.pending_spend(&key_pair::address(&key)), 56);
+    }
+}
@@ -0,0 +1,233 @@
+//! The account state committed in block headers, as the root of a sparse Merkle tree keyed by
+//! address. Accounts still in their initial state are left out of the tree, so nodes knowing
+//! different sets of idle accounts agree on the root.
+
+use crate::block::Block;
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::crypto::merkle::{self, SparseMerkleTree};
+use crate::transaction::{self, SignedTransaction};
+use crate::wallet::tracker::INITIAL_BALANCE;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// `(nonce, balance)` of every known account
+pub type AccountStates = HashMap<H160, (u32, u32)>;
+
+/// `(nonce, balance)` of an account that never sent nor received a transaction
+pub const DEFAULT_ACCOUNT: (u32, u32) = (0, INITIAL_BALANCE);
+
+/// Number of block states kept by full nodes, which validate a block against the state of its
+/// parent. Blocks branching off further back wait for an archival node's witness.
+pub const MAX_CACHED_STATES: usize = 128;
+
+/// Leaf of an account in the state tree, the zero hash for an account in its default state
+pub fn account_leaf(address: &H160, account: &(u32, u32)) -> H256 {
+    if *account == DEFAULT_ACCOUNT {
+        return H256::default();
+    }
+    let encoded = bincode::serialize(&(address, account)).unwrap();
+    ring::digest::digest(&ring::digest::SHA256, &encoded).into()
+}
+
+pub fn state_tree(state: &AccountStates) -> SparseMerkleTree {
+    let mut tree = SparseMerkleTree::new();
+    for (address, account) in state {
+        tree.insert(*address, account_leaf(address, account));
+    }
+    tree
+}
+
+pub fn state_root(state: &AccountStates) -> H256 {
+    state_tree(state).root()
+}
+
+/// Proof of the state of an account against a state root: of its inclusion in the state tree,
+/// or of its absence for an account in its default state
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccountProof {
+    pub address: H160,
+    /// `(nonce, balance)` of the account
+    pub account: (u32, u32),
+    /// Siblings of the path to the account's leaf, from the root down
+    pub siblings: Vec<H256>,
+}
+
+impl AccountProof {
+    pub fn new(state: &AccountStates, address: &H160) -> Self {
+        AccountProof {
+            address: *address,
+            account: state.get(address).cloned().unwrap_or(DEFAULT_ACCOUNT),
+            siblings: state_tree(state).proof(address),
+        }
+    }
+
+    /// Whether the account has this state in the state of `state_root`
+    pub fn verify(&self, state_root: &H256) -> bool {
+        merkle::verify_sparse(state_root, &self.address, &account_leaf(&self.address, &self.account), &self.siblings)
+    }
+}
+
+/// Whether the sender of a transfer can make it in `state`: its nonce follows the sender's and its
+/// balance covers the value and fee. The signature is checked separately.
+pub fn can_apply(state: &AccountStates, tx: &SignedTransaction) -> bool {
+    let (nonce, balance) = state.get(&tx.sender()).cloned().unwrap_or(DEFAULT_ACCOUNT);
+    nonce.checked_add(1) == Some(tx.transaction.nonce) && balance >= tx.transaction.cost()
+}
+
+/// The state after transfers, applied in order, such as the ones of a block to the state of its
+/// parent. The transfers are expected to be valid; an overdrawn balance stops at zero.
+pub fn apply_transactions(state: &AccountStates, txs: &[SignedTransaction]) -> AccountStates {
+    let mut state = state.clone();
+    for tx in txs {
+        apply(&mut state, tx);
+    }
+    state
+}
+
+fn apply(state: &mut AccountStates, tx: &SignedTransaction) {
+    let sender = tx.sender();
+    let (_, balance) = state.get(&sender).cloned().unwrap_or(DEFAULT_ACCOUNT);
+    state.insert(sender, (tx.transaction.nonce, balance.saturating_sub(tx.transaction.cost())));
+    let (nonce, balance) = state.get(&tx.transaction.address).cloned().unwrap_or(DEFAULT_ACCOUNT);
+    state.insert(tx.transaction.address, (nonce, balance.saturating_add(tx.transaction.value)));
+}
+
+/// The state after a block, from the state of its parent. Every transaction must be signed and
+/// applicable in order, and the result must match the block's state root.
+pub fn state_after(parent: &AccountStates, block: &Block) -> Result<AccountStates, String> {
+    let mut state = parent.clone();
+    for tx in &block.content.content {
+        if !transaction::verify(tx) {
+            return Err(format!("signature of transaction {} is not verified", tx.hash()));
+        }
+        if !can_apply(&state, tx) {
+            return Err(format!("transaction {} does not match the nonce or balance of its sender", tx.hash()));
+        }
+        apply(&mut state, tx);
+    }
+    if state_root(&state) != block.head.state_root {
+        return Err("state root mismatch".to_string());
+    }
+    Ok(state)
+}
+
+/// States after recent blocks, keyed by block hash. Once full, the state stored first is dropped.
+pub struct StateCache {
+    states: HashMap<H256, AccountStates>,
+    order: VecDeque<H256>,
+    capacity: usize,
+}
+
+impl StateCache {
+    pub fn new(capacity: usize) -> Self {
+        StateCache {
+            states: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&AccountStates> {
+        self.states.get(hash)
+    }
+
+    pub fn insert(&mut self, hash: H256, state: AccountStates) {
+        if self.states.insert(hash, state).is_some() {
+            return;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.states.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::transaction::{self, Transaction};
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn roots_and_proofs_leave_out_default_accounts() {
+        let key = key_pair::random();
+        let sender = key_pair::address(&key);
+        let recipient = key_pair::address(&key_pair::random());
+        let idle: AccountStates = vec![(sender, DEFAULT_ACCOUNT), (recipient, DEFAULT_ACCOUNT)].into_iter().collect();
+        assert_eq!(state_root(&idle), state_root(&HashMap::new()));
+
+        let tx = Transaction {
+            self_balance: INITIAL_BALANCE,
+            address: recipient,
+            value: 10,
+            nonce: 1,
+            fee: 1,
+        };
+        let tx = SignedTransaction {
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: transaction::sign(&tx, &key).as_ref().to_vec(),
+            transaction: tx,
+        };
+        assert!(can_apply(&HashMap::new(), &tx));
+        let state = apply_transactions(&HashMap::new(), std::slice::from_ref(&tx));
+        assert!(!can_apply(&state, &tx));
+        assert_eq!(state.get(&sender), Some(&(1, 89)));
+        assert_eq!(state.get(&recipient), Some(&(0, 110)));
+        assert_ne!(state_root(&state), state_root(&idle));
+
+        let proof = AccountProof::new(&state, &sender);
+        assert!(proof.verify(&state_root(&state)));
+        assert!(!AccountProof { account: (1, 90), ..proof }.verify(&state_root(&state)));
+        let idle_address = key_pair::address(&key_pair::random());
+        assert_eq!(AccountProof::new(&state, &idle_address).account, DEFAULT_ACCOUNT);
+        assert!(AccountProof::new(&state, &idle_address).verify(&state_root(&state)));
+    }
+
+    #[test]
+    fn blocks_apply_to_the_state_of_their_parent() {
+        let key = key_pair::random();
+        let recipient = key_pair::address(&key_pair::random());
+        let transfer = |nonce: u32, value: u32| {
+            let tx = Transaction {
+                self_balance: 0,
+                address: recipient,
+                value,
+                nonce,
+                fee: 1,
+            };
+            SignedTransaction {
+                public_key: key.public_key().as_ref().to_vec(),
+                signature: transaction::sign(&tx, &key).as_ref().to_vec(),
+                transaction: tx,
+            }
+        };
+        let block = |txs: Vec<SignedTransaction>, state_root: H256| {
+            let mut block = crate::block::test::generate_mined_block(&H256::default(), &[255u8; 32].into());
+            block.content.content = txs;
+            block.head.state_root = state_root;
+            block
+        };
+        let txs = vec![transfer(1, 40), transfer(2, 50)];
+        let expected = apply_transactions(&HashMap::new(), &txs);
+        assert_eq!(state_after(&HashMap::new(), &block(txs.clone(), state_root(&expected))), Ok(expected.clone()));
+        assert!(state_after(&HashMap::new(), &block(txs, H256::default())).is_err());
+        let overspent = vec![transfer(1, 40), transfer(2, 60)];
+        assert!(state_after(&HashMap::new(), &block(overspent.clone(), state_root(&apply_transactions(&HashMap::new(), &overspent)))).is_err());
+        let replayed = vec![transfer(1, 1)];
+        assert!(state_after(&expected, &block(replayed.clone(), state_root(&apply_transactions(&expected, &replayed)))).is_err());
+    }
+
+    #[test]
+    fn cache_drops_the_oldest_state() {
+        let mut cache = StateCache::new(2);
+        let hashes: Vec<H256> = (0..3u8).map(|i| [i; 32].into()).collect();
+        for hash in &hashes {
+            cache.insert(*hash, HashMap::new());
+        }
+        cache.insert(hashes[1], HashMap::new());
+        assert!(cache.get(&hashes[0]).is_none());
+        assert!(cache.get(&hashes[1]).is_some() && cache.get(&hashes[2]).is_some());
+    }
+}
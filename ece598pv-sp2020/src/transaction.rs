@@ -9,6 +9,15 @@ pub struct Transaction {
     pub address: H160,
     pub value: u32,
     pub nonce: u32,
+    /// Paid by the sender on top of `value`. Blocks have no coinbase, so fees are burned.
+    pub fee: u32,
+}
+
+impl Transaction {
+    /// What the transaction debits from the sender's balance
+    pub fn cost(&self) -> u32 {
+        self.value.saturating_add(self.fee)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -18,6 +27,14 @@ pub struct SignedTransaction {
     pub transaction: Transaction,
 }
 
+impl SignedTransaction {
+    /// Address of the account that signed the transaction
+    pub fn sender(&self) -> H160 {
+        let public_hash: H256 = ring::digest::digest(&ring::digest::SHA256, &self.public_key).into();
+        public_hash.into()
+    }
+}
+
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     key.sign(&(bincode::serialize(t).unwrap()))
@@ -1,7 +1,6 @@
 use crate::network::server::Handle as ServerHandle;
 use std::sync::Arc;
-use crate::blockchain::Blockchain;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 use log::info;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
@@ -10,19 +9,23 @@ use std::thread;
 use crate::crypto::hash::{H160, H256, Hashable};
 use std::time::{SystemTime, UNIX_EPOCH};
 use super::block::{Content, Header};
-use super::transaction::{Transaction, SignedTransaction, sign};
+use super::transaction::SignedTransaction;
 use crate::crypto::merkle::MerkleTree;
 use crate::block::Block;
 use serde::{Serialize, Deserialize};
 use super::network::message::Message;
 use std::collections::{HashMap, VecDeque, HashSet};
 use crate::crypto::key_pair;
+use crate::events::{Event, EventBus};
+use crate::wallet::Wallet;
 use ring::signature::Ed25519KeyPair;
-use ring::signature::KeyPair;
 extern crate rand;
 use rand::Rng;
 use log::Level::Debug;
 
+/// Fee paid by the generated transactions
+const GENERATED_FEE: u32 = 0;
+
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Exit,
@@ -39,19 +42,18 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     server: ServerHandle,
-    mempool_buf: Arc<Mutex<TxMempool>>,
+    mempool_buf: Arc<RwLock<TxMempool>>,
     key: Ed25519KeyPair,
-    public: Vec<u8>,
     address: H160,
-    init_state: Arc<Mutex<HashMap<H160,(u32, u32)>>>,
-    curr_state:Arc<Mutex<HashMap<H160,(u32,u32)>>>,
-    blockchain: Arc<Mutex<Blockchain>>,
+    init_state: Arc<RwLock<HashMap<H160,(u32, u32)>>>,
+    wallet: Arc<Mutex<Wallet>>,
 }
 
 #[derive(Clone)]
 pub struct TxMempool{
     pub buf: VecDeque<SignedTransaction>,
     pub map: HashMap<H256,SignedTransaction>,
+    events: EventBus,
 }
 
 impl TxMempool{
@@ -61,14 +63,23 @@ impl TxMempool{
         TxMempool {
             buf : new_buf,
             map : new_map,
+            events: EventBus::new(),
         }
     }
 
+    /// Create an empty mempool publishing its accepted and evicted transactions on `events`
+    pub fn with_events(events: &EventBus) -> Self{
+        let mut pool = Self::new();
+        pool.events = events.clone();
+        pool
+    }
+
     pub fn push_tx(&mut self, signed_transaction: &SignedTransaction){
         let mut curr_buf = &mut self.buf;
         curr_buf.push_back(signed_transaction.clone());
         let mut curr_map = &mut self.map;
         curr_map.insert(signed_transaction.hash(),signed_transaction.clone());
+        self.events.publish(Event::TxAccepted(signed_transaction.hash()));
     }
 
     pub fn pop_tx(&mut self, signed_transaction: &SignedTransaction){
@@ -83,15 +94,35 @@ impl TxMempool{
         curr_buf.remove(index);
         //println!("Poped tx : {:?}", signed_transaction.hash());
         let mut curr_map = &mut self.map;
-        curr_map.remove(&signed_transaction.hash());
+        if curr_map.remove(&signed_transaction.hash()).is_some() {
+            self.events.publish(Event::TxEvicted(signed_transaction.hash()));
+        }
+    }
+
+    /// Highest nonce among the pending transactions sent by `address`
+    pub fn pending_nonce(&self, address: &H160) -> Option<u32> {
+        self.buf
+            .iter()
+            .filter(|tx| tx.sender() == *address)
+            .map(|tx| tx.transaction.nonce)
+            .max()
+    }
+
+    /// Value and fees of the pending transactions sent by `address`
+    pub fn pending_spend(&self, address: &H160) -> u32 {
+        self.buf
+            .iter()
+            .filter(|tx| tx.sender() == *address)
+            .fold(0, |spend: u32, tx| spend.saturating_add(tx.transaction.cost()))
     }
 
     pub fn pop_multi_tx(&mut self, topN: &u32){
         let mut curr_buf = &mut self.buf;
         let mut curr_map = &mut self.map;
         for _x in 0..*topN {
-            let pop_tx = curr_buf.pop_front();
-            curr_map.remove(&pop_tx.unwrap().hash());
+            let pop_tx = curr_buf.pop_front().unwrap();
+            curr_map.remove(&pop_tx.hash());
+            self.events.publish(Event::TxEvicted(pop_tx.hash()));
         }
     }
 }
@@ -104,30 +135,23 @@ pub struct Handle {
 
 pub fn new(
     server: &ServerHandle,
-    tx_pool: &Arc<Mutex<TxMempool>>,
+    tx_pool: &Arc<RwLock<TxMempool>>,
     key: Ed25519KeyPair,
-    init_state: &Arc<Mutex<HashMap<H160,(u32, u32)>>>,
-    curr_state: &Arc<Mutex<HashMap<H160,(u32, u32)>>>,
-    blockchain: &Arc<Mutex<Blockchain>>,
+    init_state: &Arc<RwLock<HashMap<H160,(u32, u32)>>>,
+    wallet: &Arc<Mutex<Wallet>>,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let mempool_buf = tx_pool.clone();
-    let trusted_public = key.public_key().as_ref().to_vec();
-    let public_hash: H256 = ring::digest::digest(&ring::digest::SHA256, &trusted_public).into();
-    let address: H160 = public_hash.into();
-    let mut curr_state = curr_state.clone();
-    let blockchain = blockchain.clone();
+    let address = key_pair::address(&key);
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         server: server.clone(),
         mempool_buf: mempool_buf,
         key: key,
-        public: trusted_public,
         address: address,
         init_state: init_state.clone(),
-        curr_state: curr_state,
-        blockchain: blockchain,
+        wallet: wallet.clone(),
     };
 
     let handle = Handle {
@@ -151,14 +175,16 @@ impl Handle {
 }
 
 impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
+    /// Start the generator thread, which runs until `Handle::exit` is called.
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let thread = thread::Builder::new()
             .name("generator".to_string())
             .spawn(move || {
                 self.generate_loop();
             })
             .unwrap();
         info!("Generator initialized into paused mode");
+        thread
     }
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
@@ -174,8 +200,6 @@ impl Context {
         }
     }
     fn generate_loop(&mut self) {
-        let mut count  = 1;
-
         // main mining loop
         loop {
             //check and react to control signals
@@ -202,58 +226,31 @@ impl Context {
 
             // TODO :: FIGURE OUT THE RECIPIENT
 
-            let init_state = self.init_state.lock().unwrap();
-            let mut peer_vec = Vec::new();
-            for key in init_state.keys() {
-                peer_vec.push(key);
-            }
-
-            let mut rand_num =  0;
-            //println!("Current peer number: {:?}", peer_vec.len());
-            while peer_vec[rand_num].eq( &self.address){
+            let init_state = self.init_state.read().unwrap();
+            let peer_vec: Vec<H160> = init_state.keys().filter(|key| **key != self.address).cloned().collect();
+            std::mem::drop(init_state);
+            // one transaction at a time, the next one once it is confirmed
+            let pool = self.mempool_buf.read().unwrap();
+            let wallet = self.wallet.lock().unwrap();
+            let transfer = if !peer_vec.is_empty() && pool.pending_nonce(&self.address).is_none() {
                 let mut rng = rand::thread_rng();
-                rand_num =  rng.gen_range(0, peer_vec.len());
-                //println!("Rand number {:?}, Process number : {:?}", rand_num, peer_vec.len());
-            }
-
+                let peer_add = peer_vec[rng.gen_range(0, peer_vec.len())];
+                wallet.build_transfer(&self.key, &pool, peer_add, 1, GENERATED_FEE).ok()
+            } else {
+                None
+            };
+            std::mem::drop(wallet);
+            std::mem::drop(pool);
 
-            let peer_add = peer_vec[rand_num].clone();
-            let current_chain = self.blockchain.lock().unwrap();
-            let current_state = self.curr_state.lock().unwrap();
-            let current_nonce = current_state.get(&self.address).unwrap().0;
-            let current_balance = current_state.get(&self.address).unwrap().1;
-            if init_state.len() >= 2 && count == current_nonce + 1{
-                println!("New tx: Sender is: {:?}, Receiver is : {:?}", self.address, peer_add);
-                println!("---------------------");
-                let mut txpool = self.mempool_buf.lock().unwrap();
-                let trans = Transaction {
-                    self_balance: current_balance,
-                    address: peer_add, // should be recipient address
-                    value: 1,
-                    nonce: count,
-                };
-                // current_self_balance = current_self_balance - 1;
-                count = count + 1;
-                let signature = sign(&trans, &self.key);
-                let trusted_sign = signature.as_ref().to_vec();
-                let signed_trans = SignedTransaction {
-                    public_key: self.public.clone(),
-                    signature: trusted_sign.clone(),
-                    transaction: trans.clone(),
-                };
-                txpool.push_tx(&signed_trans);
-                let mut tx_vec = Vec::new();
-                tx_vec.push(signed_trans);
-                //self.server.broadcast(Message::Transactions(tx_vec));
+            if let Some(signed_trans) = transfer {
+                self.mempool_buf.write().unwrap().push_tx(&signed_trans);
+                // relay through the same trickled announcements as transactions from peers
+                self.server.relay_transactions(vec![signed_trans.hash()], None);
                 //println!("NEW TX");
-                std::mem::drop(txpool);
             } else {
                 //println!("current peers number {:?}", init_state.len());
                 //println!("current nonce number {:?}", init_state.get(&self.address).unwrap().0);
             }
-            std::mem::drop(init_state);
-            std::mem::drop(current_chain);
-            std::mem::drop(current_state);
 
             let interval = time::Duration::from_micros(1000000);
             thread::sleep(interval);
@@ -0,0 +1,41 @@
+//! Deterministic keys: a seed phrase is stretched into a seed, from which keys are derived along
+//! SLIP-0010 paths, so that backing up the phrase backs up every derived address.
+
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::num::NonZeroU32;
+
+/// Path of the account whose addresses are derived by default, at `<ACCOUNT_PATH>/<index>'`
+pub const ACCOUNT_PATH: &str = "m/44'/1'/0'";
+/// Number of consecutive unused addresses after which scanning stops
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+/// Random bytes of a generated seed phrase
+const PHRASE_ENTROPY: usize = 16;
+const SEED_ITERATIONS: u32 = 2048;
+
+/// Generate a seed phrase of 8 groups of 4 hex digits.
+pub fn generate_phrase() -> String {
+    let mut entropy = [0; PHRASE_ENTROPY];
+    SystemRandom::new().fill(&mut entropy).unwrap();
+    entropy.chunks(2).map(hex::encode).collect::<Vec<_>>().join(" ")
+}
+
+/// The 64-byte seed of a phrase, stretched like BIP-39 does. Whitespace between words does not
+/// matter.
+pub fn seed_from_phrase(phrase: &str) -> Vec<u8> {
+    let phrase = phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut seed = vec![0; 64];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA512,
+        NonZeroU32::new(SEED_ITERATIONS).unwrap(),
+        b"mnemonic",
+        phrase.as_bytes(),
+        &mut seed,
+    );
+    seed
+}
+
+/// Path of the address at `index` of the default account
+pub fn address_path(index: u32) -> String {
+    format!("{}/{}'", ACCOUNT_PATH, index)
+}
@@ -0,0 +1,419 @@
+use crate::crypto::hash::H160;
+use crate::crypto::key_pair;
+use crate::wallet::hd;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::Ed25519KeyPair;
+use ring::{aead, pbkdf2};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+const KEYSTORE_VERSION: u32 = 1;
+/// PBKDF2-HMAC-SHA256 iterations deriving the encryption key from the passphrase
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug)]
+pub enum WalletError {
+    Io(std::io::Error),
+    /// The keystore file is not a keystore of a supported version
+    Format(String),
+    WrongPassphrase,
+    InvalidKey,
+    UnknownAddress(H160),
+    /// The keystore has no seed to derive keys from
+    NoSeed,
+    /// The keystore already has a seed, which would orphan the keys derived from it
+    SeedExists,
+    InvalidPath(String),
+    /// Derived keys have no PKCS#8 document, they are backed up by the seed phrase
+    DerivedKey(H160),
+}
+
+impl std::fmt::Display for WalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WalletError::Io(e) => write!(f, "keystore I/O error: {}", e),
+            WalletError::Format(e) => write!(f, "invalid keystore: {}", e),
+            WalletError::WrongPassphrase => write!(f, "wrong passphrase"),
+            WalletError::InvalidKey => write!(f, "invalid Ed25519 PKCS#8 key"),
+            WalletError::UnknownAddress(address) => write!(f, "no key for address {}", address.to_hex()),
+            WalletError::NoSeed => write!(f, "the wallet has no seed"),
+            WalletError::SeedExists => write!(f, "the wallet already has a seed"),
+            WalletError::InvalidPath(e) => write!(f, "{}", e),
+            WalletError::DerivedKey(address) => {
+                write!(f, "key {} is derived from the seed, back up the seed phrase instead", address.to_hex())
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for WalletError {
+    fn from(e: std::io::Error) -> Self {
+        WalletError::Io(e)
+    }
+}
+
+/// A key as stored on disk, its PKCS#8 document encrypted with AES-256-GCM. The address is kept
+/// in clear so that it can be listed, and is authenticated as additional data.
+#[derive(Serialize, Deserialize)]
+struct EncryptedKey {
+    address: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// An address of the address book, derived from the seed
+#[derive(Serialize, Deserialize)]
+struct DerivedAddress {
+    path: String,
+    address: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    version: u32,
+    salt: String,
+    iterations: u32,
+    keys: Vec<EncryptedKey>,
+    /// The HD seed, encrypted like the keys with `SEED_AAD` as additional data
+    #[serde(default)]
+    seed: Option<EncryptedKey>,
+    #[serde(default)]
+    derived: Vec<DerivedAddress>,
+}
+
+/// Additional data authenticating the encrypted seed, in place of an address
+const SEED_AAD: &str = "seed";
+
+/// Ed25519 keys stored in a file, encrypted with a key derived from a passphrase
+pub struct Keystore {
+    path: PathBuf,
+    salt: Vec<u8>,
+    iterations: u32,
+    encryption_key: aead::LessSafeKey,
+    /// Addresses and PKCS#8 documents of the imported and random keys, in the order they were added
+    keys: Vec<(H160, Vec<u8>)>,
+    seed: Option<Vec<u8>>,
+    /// Derivation paths and addresses of the keys derived from the seed, in the order they were
+    /// derived
+    derived: Vec<(String, H160)>,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<aead::LessSafeKey, WalletError> {
+    let iterations = NonZeroU32::new(iterations).ok_or_else(|| WalletError::Format("zero iterations".to_string()))?;
+    let mut key = [0; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, &key).unwrap();
+    Ok(aead::LessSafeKey::new(key))
+}
+
+fn decode_hex(field: &str, value: &str) -> Result<Vec<u8>, WalletError> {
+    hex::decode(value).map_err(|e| WalletError::Format(format!("{}: {}", field, e)))
+}
+
+fn seal(encryption_key: &aead::LessSafeKey, aad: &str, plaintext: &[u8]) -> EncryptedKey {
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).unwrap();
+    let mut in_out = plaintext.to_vec();
+    encryption_key
+        .seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(aad.as_bytes()),
+            &mut in_out,
+        )
+        .unwrap();
+    EncryptedKey {
+        address: aad.to_string(),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(in_out),
+    }
+}
+
+fn unseal(encryption_key: &aead::LessSafeKey, encrypted: &EncryptedKey) -> Result<Vec<u8>, WalletError> {
+    let mut nonce = [0; NONCE_LEN];
+    let nonce_bytes = decode_hex("nonce", &encrypted.nonce)?;
+    if nonce_bytes.len() != NONCE_LEN {
+        return Err(WalletError::Format("nonce: invalid length".to_string()));
+    }
+    nonce.copy_from_slice(&nonce_bytes);
+    let mut in_out = decode_hex("ciphertext", &encrypted.ciphertext)?;
+    let plaintext = encryption_key
+        .open_in_place(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(encrypted.address.as_bytes()),
+            &mut in_out,
+        )
+        .map_err(|_| WalletError::WrongPassphrase)?;
+    Ok(plaintext.to_vec())
+}
+
+fn parse_address(address: &str) -> Result<H160, WalletError> {
+    address.parse().map_err(|e| WalletError::Format(format!("address: {}", e)))
+}
+
+impl Keystore {
+    /// Create an empty keystore at `path`. Nothing is written until a key is added.
+    pub fn create(path: &Path, passphrase: &str) -> Result<Self, WalletError> {
+        let mut salt = vec![0; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).unwrap();
+        Ok(Keystore {
+            path: path.to_path_buf(),
+            encryption_key: derive_key(passphrase, &salt, PBKDF2_ITERATIONS)?,
+            salt,
+            iterations: PBKDF2_ITERATIONS,
+            keys: Vec::new(),
+            seed: None,
+            derived: Vec::new(),
+        })
+    }
+
+    /// Open the keystore at `path` and decrypt its keys.
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self, WalletError> {
+        let file: KeystoreFile = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| WalletError::Format(e.to_string()))?;
+        if file.version != KEYSTORE_VERSION {
+            return Err(WalletError::Format(format!("unsupported version {}", file.version)));
+        }
+        let salt = decode_hex("salt", &file.salt)?;
+        let encryption_key = derive_key(passphrase, &salt, file.iterations)?;
+        let mut keys = Vec::new();
+        for encrypted in &file.keys {
+            let address = parse_address(&encrypted.address)?;
+            keys.push((address, unseal(&encryption_key, encrypted)?));
+        }
+        let seed = match &file.seed {
+            Some(encrypted) if encrypted.address == SEED_AAD => Some(unseal(&encryption_key, encrypted)?),
+            Some(_) => return Err(WalletError::Format("seed: invalid additional data".to_string())),
+            None => None,
+        };
+        let mut derived = Vec::new();
+        for entry in file.derived {
+            key_pair::parse_path(&entry.path).map_err(WalletError::Format)?;
+            derived.push((entry.path, parse_address(&entry.address)?));
+        }
+        Ok(Keystore {
+            path: path.to_path_buf(),
+            salt,
+            iterations: file.iterations,
+            encryption_key,
+            keys,
+            seed,
+            derived,
+        })
+    }
+
+    /// Open the keystore at `path`, or create it if the file does not exist.
+    pub fn open_or_create(path: &Path, passphrase: &str) -> Result<Self, WalletError> {
+        if path.exists() {
+            Self::open(path, passphrase)
+        } else {
+            Self::create(path, passphrase)
+        }
+    }
+
+    /// Write the keystore, replacing the file atomically.
+    fn save(&self) -> Result<(), WalletError> {
+        let file = KeystoreFile {
+            version: KEYSTORE_VERSION,
+            salt: hex::encode(&self.salt),
+            iterations: self.iterations,
+            keys: self
+                .keys
+                .iter()
+                .map(|(address, pkcs8)| seal(&self.encryption_key, &address.to_hex(), pkcs8))
+                .collect(),
+            seed: self.seed.as_ref().map(|seed| seal(&self.encryption_key, SEED_AAD, seed)),
+            derived: self
+                .derived
+                .iter()
+                .map(|(path, address)| DerivedAddress {
+                    path: path.clone(),
+                    address: address.to_hex(),
+                })
+                .collect(),
+        };
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&file).unwrap())?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// Addresses of the stored keys, in the order they were added, then of the derived keys
+    pub fn addresses(&self) -> Vec<H160> {
+        self.keys
+            .iter()
+            .map(|(address, _)| *address)
+            .chain(self.derived.iter().map(|(_, address)| *address))
+            .collect()
+    }
+
+    /// Derivation paths and addresses of the keys derived from the seed
+    pub fn address_book(&self) -> &[(String, H160)] {
+        &self.derived
+    }
+
+    pub fn has_seed(&self) -> bool {
+        self.seed.is_some()
+    }
+
+    /// Set the seed the keys are derived from, from a new or restored seed phrase.
+    pub fn set_seed_phrase(&mut self, phrase: &str) -> Result<(), WalletError> {
+        if self.seed.is_some() {
+            return Err(WalletError::SeedExists);
+        }
+        self.seed = Some(hd::seed_from_phrase(phrase));
+        self.save()
+    }
+
+    fn derive_key(&self, path: &str) -> Result<Ed25519KeyPair, WalletError> {
+        let seed = self.seed.as_ref().ok_or(WalletError::NoSeed)?;
+        let path = key_pair::parse_path(path).map_err(WalletError::InvalidPath)?;
+        Ok(key_pair::derive(seed, &path).key_pair())
+    }
+
+    /// Derive the key at `path` and add it to the address book.
+    pub fn derive(&mut self, path: &str) -> Result<H160, WalletError> {
+        let address = key_pair::address(&self.derive_key(path)?);
+        if !self.derived.iter().any(|(_, known)| *known == address) {
+            self.derived.push((path.to_string(), address));
+            self.save()?;
+        }
+        Ok(address)
+    }
+
+    /// Derive the first address of the default account that is not in the address book yet.
+    pub fn derive_next(&mut self) -> Result<H160, WalletError> {
+        let index = (0..)
+            .find(|index| !self.derived.iter().any(|(path, _)| *path == hd::address_path(*index)))
+            .unwrap();
+        self.derive(&hd::address_path(index))
+    }
+
+    /// Derive the addresses of the default account in order, until `gap_limit` consecutive ones
+    /// are unused, and add the used ones to the address book. Returns the addresses added.
+    pub fn scan(&mut self, gap_limit: u32, is_used: impl Fn(&H160) -> bool) -> Result<Vec<H160>, WalletError> {
+        let mut found = Vec::new();
+        let mut unused = 0;
+        let mut index = 0;
+        while unused < gap_limit {
+            let path = hd::address_path(index);
+            let address = key_pair::address(&self.derive_key(&path)?);
+            if is_used(&address) {
+                unused = 0;
+                if !self.derived.iter().any(|(_, known)| *known == address) {
+                    self.derived.push((path, address));
+                    found.push(address);
+                }
+            } else {
+                unused += 1;
+            }
+            index += 1;
+        }
+        if !found.is_empty() {
+            self.save()?;
+        }
+        Ok(found)
+    }
+
+    fn add(&mut self, pkcs8: Vec<u8>) -> Result<H160, WalletError> {
+        let key = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| WalletError::InvalidKey)?;
+        let address = key_pair::address(&key);
+        if !self.keys.iter().any(|(known, _)| *known == address) {
+            self.keys.push((address, pkcs8));
+            self.save()?;
+        }
+        Ok(address)
+    }
+
+    /// Generate a new key and store it.
+    pub fn new_key(&mut self) -> Result<H160, WalletError> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        self.add(pkcs8.as_ref().to_vec())
+    }
+
+    /// Store a key given as the hex of its PKCS#8 document.
+    pub fn import(&mut self, pkcs8_hex: &str) -> Result<H160, WalletError> {
+        let pkcs8 = hex::decode(pkcs8_hex.trim()).map_err(|_| WalletError::InvalidKey)?;
+        self.add(pkcs8)
+    }
+
+    /// The hex of the PKCS#8 document of a stored key
+    pub fn export(&self, address: &H160) -> Result<String, WalletError> {
+        self.pkcs8(address).map(hex::encode)
+    }
+
+    fn pkcs8(&self, address: &H160) -> Result<&[u8], WalletError> {
+        match self.keys.iter().find(|(known, _)| known == address) {
+            Some((_, pkcs8)) => Ok(pkcs8),
+            None if self.derived.iter().any(|(_, known)| known == address) => Err(WalletError::DerivedKey(*address)),
+            None => Err(WalletError::UnknownAddress(*address)),
+        }
+    }
+
+    pub fn key_pair(&self, address: &H160) -> Result<Ed25519KeyPair, WalletError> {
+        if let Some((path, _)) = self.derived.iter().find(|(_, known)| known == address) {
+            return self.derive_key(path);
+        }
+        Ed25519KeyPair::from_pkcs8(self.pkcs8(address)?).map_err(|_| WalletError::InvalidKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keystore_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("keystore-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn keys_survive_reopening() {
+        let path = keystore_path("reopen");
+        let mut keystore = Keystore::create(&path, "passphrase").unwrap();
+        let created = keystore.new_key().unwrap();
+        let exported = keystore.export(&created).unwrap();
+
+        let mut other = Keystore::create(&keystore_path("import"), "other").unwrap();
+        assert_eq!(other.import(&exported).unwrap(), created);
+
+        let reopened = Keystore::open(&path, "passphrase").unwrap();
+        assert_eq!(reopened.addresses(), vec![created]);
+        assert_eq!(key_pair::address(&reopened.key_pair(&created).unwrap()), created);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(keystore_path("import")).unwrap();
+    }
+
+    #[test]
+    fn scan_restores_used_addresses() {
+        let path = keystore_path("scan");
+        let mut keystore = Keystore::create(&path, "passphrase").unwrap();
+        keystore.set_seed_phrase("0001 0203 0405 0607").unwrap();
+        let first = keystore.derive_next().unwrap();
+        keystore.derive(&hd::address_path(3)).unwrap();
+        let fourth = keystore.derive(&hd::address_path(3)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut restored = Keystore::create(&path, "passphrase").unwrap();
+        restored.set_seed_phrase("0001  0203 0405 0607").unwrap();
+        // address 3 is beyond a gap limit of 2 after address 0
+        assert_eq!(restored.scan(2, |address| *address == first || *address == fourth).unwrap(), vec![first]);
+        assert_eq!(restored.scan(3, |address| *address == first || *address == fourth).unwrap(), vec![fourth]);
+        let reopened = Keystore::open(&path, "passphrase").unwrap();
+        assert_eq!(reopened.addresses(), vec![first, fourth]);
+        assert_eq!(key_pair::address(&reopened.key_pair(&fourth).unwrap()), fourth);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let path = keystore_path("passphrase");
+        Keystore::create(&path, "passphrase").unwrap().new_key().unwrap();
+        match Keystore::open(&path, "wrong") {
+            Err(WalletError::WrongPassphrase) => {}
+            _ => panic!("opened with a wrong passphrase"),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,6 @@
+pub mod hd;
+pub mod keystore;
+pub mod tracker;
+
+pub use keystore::{Keystore, WalletError};
+pub use tracker::{TransferError, Wallet};
@@ -0,0 +1,338 @@
+//! Balances and history of the owned addresses, followed from the blocks connected to and
+//! disconnected from the longest chain, and transfers built from them.
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::crypto::key_pair;
+use crate::events::Event;
+use crate::network::worker;
+use crate::transaction::{self, SignedTransaction, Transaction};
+use crate::txgenerator::TxMempool;
+use crossbeam::channel::Receiver;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+/// Balance of every account before its first transaction
+pub const INITIAL_BALANCE: u32 = 100;
+
+/// Why a transfer could not be built
+#[derive(Debug, PartialEq)]
+pub enum TransferError {
+    UnknownAddress(H160),
+    /// The pending balance does not cover the value and the fee
+    InsufficientFunds { available: u32, needed: u32 },
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransferError::UnknownAddress(address) => write!(f, "address {} is not in the wallet", address.to_hex()),
+            TransferError::InsufficientFunds { available, needed } => {
+                write!(f, "insufficient funds: {} available, {} needed", available, needed)
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BalanceView {
+    pub address: String,
+    pub confirmed: u32,
+    /// The confirmed balance with the pending transactions of the mempool applied
+    pub pending: u32,
+    /// The nonce of the next transaction sent from this address
+    pub next_nonce: u32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct HistoryEntry {
+    pub hash: String,
+    pub from: String,
+    pub to: String,
+    pub value: u32,
+    pub fee: u32,
+    pub nonce: u32,
+    /// Hash of the confirming block, or `None` while pending
+    pub block: Option<String>,
+    pub height: Option<usize>,
+}
+
+impl HistoryEntry {
+    fn new(tx: &SignedTransaction, block: Option<(H256, usize)>) -> Self {
+        HistoryEntry {
+            hash: tx.hash().to_string(),
+            from: tx.sender().to_hex(),
+            to: tx.transaction.address.to_hex(),
+            value: tx.transaction.value,
+            fee: tx.transaction.fee,
+            nonce: tx.transaction.nonce,
+            block: block.map(|(hash, _)| hash.to_string()),
+            height: block.map(|(_, height)| height),
+        }
+    }
+}
+
+/// Locked after the chain, mempool and keystore locks, see `network::worker::Context`
+pub struct Wallet {
+    /// Confirmed nonce and balance of each owned address
+    accounts: HashMap<H160, (u32, u32)>,
+    /// Confirmed transactions sent or received by the owned addresses, with their block, in the
+    /// order they were connected
+    history: Vec<(SignedTransaction, H256, usize)>,
+    /// Blocks applied to the balances, so that an event for a block already replayed by `watch`
+    /// is not applied twice
+    connected: HashSet<H256>,
+}
+
+impl Wallet {
+    pub fn new(addresses: &[H160]) -> Self {
+        Wallet {
+            accounts: addresses.iter().map(|address| (*address, (0, INITIAL_BALANCE))).collect(),
+            history: Vec::new(),
+            connected: HashSet::new(),
+        }
+    }
+
+    pub fn addresses(&self) -> Vec<H160> {
+        self.accounts.keys().cloned().collect()
+    }
+
+    /// Start following `address`, replaying the longest chain to find its balance and history.
+    pub fn watch(&mut self, address: H160, chain: &Blockchain) {
+        if self.accounts.contains_key(&address) {
+            return;
+        }
+        self.accounts.insert(address, (0, INITIAL_BALANCE));
+        self.history.clear();
+        self.connected.clear();
+        for account in self.accounts.values_mut() {
+            *account = (0, INITIAL_BALANCE);
+        }
+        for (height, hash) in chain.all_blocks_in_longest_chain().iter().enumerate() {
+            self.connect_block(&chain.chain.get(hash).unwrap().0, height);
+        }
+    }
+
+    fn owns(&self, tx: &SignedTransaction) -> bool {
+        self.accounts.contains_key(&tx.sender()) || self.accounts.contains_key(&tx.transaction.address)
+    }
+
+    pub fn connect_block(&mut self, block: &Block, height: usize) {
+        if !self.connected.insert(block.hash()) {
+            return;
+        }
+        for tx in &block.content.content {
+            if !self.owns(tx) {
+                continue;
+            }
+            if let Some((nonce, balance)) = self.accounts.get_mut(&tx.sender()) {
+                *nonce = tx.transaction.nonce;
+                *balance = balance.saturating_sub(tx.transaction.cost());
+            }
+            if let Some((_, balance)) = self.accounts.get_mut(&tx.transaction.address) {
+                *balance = balance.saturating_add(tx.transaction.value);
+            }
+            self.history.push((tx.clone(), block.hash(), height));
+        }
+    }
+
+    /// Undo `connect_block`, for a block leaving the longest chain in a reorganization.
+    pub fn disconnect_block(&mut self, block: &Block) {
+        let hash = block.hash();
+        if !self.connected.remove(&hash) {
+            return;
+        }
+        for tx in block.content.content.iter().rev() {
+            if !self.owns(tx) {
+                continue;
+            }
+            if let Some((nonce, balance)) = self.accounts.get_mut(&tx.sender()) {
+                // light nodes connect blocks they could not validate, any nonce or cost may show up
+                *nonce = tx.transaction.nonce.saturating_sub(1);
+                *balance = balance.saturating_add(tx.transaction.cost());
+            }
+            if let Some((_, balance)) = self.accounts.get_mut(&tx.transaction.address) {
+                *balance = balance.saturating_sub(tx.transaction.value);
+            }
+        }
+        self.history.retain(|(_, block, _)| *block != hash);
+    }
+
+    /// Keep the wallet up to date with the blocks connected to and disconnected from the longest
+    /// chain.
+    pub fn track(wallet: &Arc<Mutex<Wallet>>, blockchain: &Arc<RwLock<Blockchain>>, events: Receiver<Event>) {
+        let wallet = wallet.clone();
+        let blockchain = blockchain.clone();
+        thread::Builder::new()
+            .name("wallet".to_string())
+            .spawn(move || {
+                for event in events.iter() {
+                    let (hash, height, connected) = match event {
+                        Event::BlockConnected { hash, height, .. } => (hash, height, true),
+                        Event::BlockDisconnected { hash, height } => (hash, height, false),
+                        _ => continue,
+                    };
+                    let chain = blockchain.read().unwrap();
+                    let block = &chain.chain.get(&hash).unwrap().0;
+                    let mut wallet = wallet.lock().unwrap();
+                    if connected {
+                        wallet.connect_block(block, height);
+                    } else {
+                        wallet.disconnect_block(block);
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    /// The nonce of the next transaction sent from `address`, after its pending ones
+    pub fn next_nonce(&self, address: &H160, pool: &TxMempool) -> u32 {
+        worker::next_nonce(pool, &self.accounts, address)
+    }
+
+    pub fn balance(&self, address: &H160, pool: &TxMempool) -> Result<BalanceView, TransferError> {
+        let (_, confirmed) = *self.accounts.get(address).ok_or(TransferError::UnknownAddress(*address))?;
+        let mut pending = confirmed;
+        for tx in pool.buf.iter() {
+            if tx.sender() == *address {
+                pending = pending.saturating_sub(tx.transaction.cost());
+            }
+            if tx.transaction.address == *address {
+                pending += tx.transaction.value;
+            }
+        }
+        Ok(BalanceView {
+            address: address.to_hex(),
+            confirmed,
+            pending,
+            next_nonce: self.next_nonce(address, pool),
+        })
+    }
+
+    /// Transactions of `address`, or of every owned address: the pending ones of the mempool,
+    /// then the confirmed ones from the most recent
+    pub fn history(&self, address: Option<&H160>, pool: &TxMempool) -> Vec<HistoryEntry> {
+        let concerns = |tx: &SignedTransaction| match address {
+            Some(address) => tx.sender() == *address || tx.transaction.address == *address,
+            None => self.owns(tx),
+        };
+        let pending = pool.buf.iter().filter(|tx| concerns(tx)).map(|tx| HistoryEntry::new(tx, None));
+        let confirmed = self
+            .history
+            .iter()
+            .rev()
+            .filter(|(tx, _, _)| concerns(tx))
+            .map(|(tx, block, height)| HistoryEntry::new(tx, Some((*block, *height))));
+        pending.chain(confirmed).collect()
+    }
+
+    /// Build and sign a transfer of `value` from the address of `key`, paying `fee`. The nonce
+    /// follows the pending transactions of the sender.
+    pub fn build_transfer(
+        &self,
+        key: &Ed25519KeyPair,
+        pool: &TxMempool,
+        to: H160,
+        value: u32,
+        fee: u32,
+    ) -> Result<SignedTransaction, TransferError> {
+        let from = key_pair::address(key);
+        let balance = self.balance(&from, pool)?;
+        let needed = value.saturating_add(fee);
+        if balance.pending < needed {
+            return Err(TransferError::InsufficientFunds {
+                available: balance.pending,
+                needed,
+            });
+        }
+        let tx = Transaction {
+            self_balance: balance.confirmed,
+            address: to,
+            value,
+            nonce: balance.next_nonce,
+            fee,
+        };
+        Ok(SignedTransaction {
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: transaction::sign(&tx, key).as_ref().to_vec(),
+            transaction: tx,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Content, Header};
+    use crate::crypto::merkle::MerkleTree;
+
+    fn block_with(txs: Vec<SignedTransaction>) -> Block {
+        Block {
+            head: Header {
+                parent_hash: H256::default(),
+                nonce: 0,
+                difficulty: H256::default(),
+                timestamp: 0,
+                merkle_root: MerkleTree::new(&txs).root(),
+                state_root: H256::default(),
+            },
+            content: Content { content: txs },
+        }
+    }
+
+    #[test]
+    fn balances_follow_connected_blocks() {
+        let key = key_pair::random();
+        let own = key_pair::address(&key);
+        let other = key_pair::address(&key_pair::random());
+        let mut wallet = Wallet::new(&[own]);
+        let pool = TxMempool::new();
+
+        let tx = wallet.build_transfer(&key, &pool, other, 30, 2).unwrap();
+        assert_eq!(tx.transaction.nonce, 1);
+        let block = block_with(vec![tx]);
+        wallet.connect_block(&block, 1);
+        wallet.connect_block(&block, 1);
+        let balance = wallet.balance(&own, &pool).unwrap();
+        assert_eq!((balance.confirmed, balance.next_nonce), (68, 2));
+        assert_eq!(wallet.history(None, &pool).len(), 1);
+
+        assert_eq!(
+            wallet.build_transfer(&key, &pool, other, 68, 1).unwrap_err(),
+            TransferError::InsufficientFunds { available: 68, needed: 69 }
+        );
+
+        wallet.disconnect_block(&block);
+        let balance = wallet.balance(&own, &pool).unwrap();
+        assert_eq!((balance.confirmed, balance.next_nonce), (INITIAL_BALANCE, 1));
+        assert!(wallet.history(None, &pool).is_empty());
+    }
+
+    #[test]
+    fn unvalidated_blocks_do_not_overflow() {
+        let key = key_pair::random();
+        let own = key_pair::address(&key);
+        let mut wallet = Wallet::new(&[own]);
+        let pool = TxMempool::new();
+        let tx = Transaction {
+            self_balance: 0,
+            address: own,
+            value: u32::MAX,
+            nonce: 0,
+            fee: 0,
+        };
+        let block = block_with(vec![SignedTransaction {
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: Vec::new(),
+            transaction: tx,
+        }]);
+        wallet.connect_block(&block, 1);
+        assert_eq!(wallet.balance(&own, &pool).unwrap().confirmed, u32::MAX);
+        wallet.disconnect_block(&block);
+        assert_eq!(wallet.balance(&own, &pool).unwrap().next_nonce, 1);
+    }
+}